@@ -0,0 +1,37 @@
+#![warn(clippy::all)]
+
+//! Serves stand-ins for Supabase auth and the tutoring proxy:
+//!
+//! ```sh
+//! cargo run --example stub_server --features stub [config.json]
+//! SUPABASE_URL=http://127.0.0.1:54321 cargo run
+//! ```
+//!
+//! Then set the tutor's provider to `proxy` with the endpoint
+//! `http://127.0.0.1:54321/` and sign in with any email and the code
+//! `123456`. `STUB_ADDR` changes the address.
+
+#[cfg(not(target_arch = "wasm32"))]
+fn main() -> Result<(), String> {
+    use mergesort_egui::stub::{StubConfig, StubServer};
+
+    env_logger::init();
+    let addr = std::env::var("STUB_ADDR").unwrap_or_else(|_| "127.0.0.1:54321".to_string());
+    let config = match std::env::args().nth(1) {
+        Some(path) => {
+            let text = std::fs::read_to_string(&path)
+                .map_err(|e| format!("Failed to read {}: {}", path, e))?;
+            serde_json::from_str(&text).map_err(|e| format!("Failed to parse {}: {}", path, e))?
+        }
+        None => StubConfig::default(),
+    };
+
+    let server = StubServer::start(&addr, config)?;
+    println!("Stub server listening on {}", server.url());
+    loop {
+        std::thread::park();
+    }
+}
+
+#[cfg(target_arch = "wasm32")]
+fn main() {}
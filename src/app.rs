@@ -1,64 +1,190 @@
 #![warn(clippy::all)]
 
+use chrono::{DateTime, Utc};
 use eframe::egui;
 use egui_commonmark::{CommonMarkCache, CommonMarkViewer};
 use email_address::*;
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 use std::sync::mpsc::{self, Receiver, Sender};
 
-use crate::{initialize_auth_state, save_auth_state};
+use crate::context::{ContextStrategy, ContextTrim};
+use crate::error::ApiError;
+use crate::lesson::{load_lesson_catalog, set_active_lesson, LessonPack, LESSON_CATALOG};
+use crate::markers::{self, strip_markers};
+use crate::metadata::TurnMetadata;
+use crate::milestones::{MilestoneStatus, MilestoneTracker};
+use crate::providers::Provider;
+use crate::settings::{Settings, SettingsOverrides};
+use crate::{
+    initialize_auth_state, save_auth_state, MilestoneCall, RequestHandle, TutorReply, Usage,
+};
 #[cfg(target_arch = "wasm32")]
-use crate::{make_anthropic_request, request_otp_web, verify_otp_web, AUTH_STATE, PENDING_STATE};
+use crate::{make_tutor_request, refresh_session_web, request_otp_web, verify_otp_web, AUTH_STATE};
 #[cfg(target_arch = "wasm32")]
 use wasm_bindgen_futures::spawn_local;
 
 #[cfg(not(target_arch = "wasm32"))]
 use crate::{
-    make_anthropic_request, request_otp_native, verify_otp_native, AUTH_STATE, PENDING_STATE,
+    make_tutor_request, refresh_session_native, request_otp_native, verify_otp_native, AUTH_STATE,
 };
 
-#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
-enum MilestoneStatus {
-    NotStarted,
-    InProgress,
-    Completed,
-}
+/// How long to wait before retrying a session refresh that failed to connect.
+const REFRESH_RETRY_SECS: i64 = 30;
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-struct Milestone {
-    id: String,
-    description: String,
-    status: MilestoneStatus,
-}
+/// The lesson whose conversation was saved before lessons had their own sessions.
+const LEGACY_LESSON_ID: &str = "mergesort";
+
+/// Accent used for completed milestones in the side panel and the chat.
+const MILESTONE_COLOR: egui::Color32 = egui::Color32::from_rgb(34, 197, 94);
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub(crate) struct MilestoneMatch {
-    milestone_id: String,
-    description: String,
+    pub(crate) milestone_id: String,
+    pub(crate) description: String,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub(crate) struct ChatMessage {
     pub(crate) content: String,
     pub(crate) from_user: bool,
-    pub(crate) cacheable: bool,
     #[serde(skip)]
     pub(crate) analyzed_for_milestones: bool,
     #[serde(default)]
     pub(crate) found_milestones: Vec<MilestoneMatch>,
+    /// Set on a user message whose request was stopped before the tutor replied.
+    #[serde(default)]
+    pub(crate) unsent: bool,
+    /// When the student submitted the message, or the tutor's reply arrived.
+    #[serde(default)]
+    pub(crate) sent_at: Option<DateTime<Utc>>,
+    /// Pacing details sent to the tutor with a student message.
+    #[serde(default)]
+    pub(crate) metadata: Option<TurnMetadata>,
 }
 
 #[derive(Debug)]
 struct ScrollState {
     stick_to_bottom: bool,
+    /// Message to bring into view on the next frame.
+    scroll_to_message: Option<usize>,
+    /// Message outlined as the evidence for a milestone picked in the side panel.
+    highlighted_message: Option<usize>,
 }
 
 impl ScrollState {
     fn new() -> Self {
         Self {
             stick_to_bottom: true,
+            scroll_to_message: None,
+            highlighted_message: None,
         }
     }
+
+    fn focus_message(&mut self, idx: usize) {
+        self.stick_to_bottom = false;
+        self.scroll_to_message = Some(idx);
+        self.highlighted_message = Some(idx);
+    }
+
+    fn follow_latest(&mut self) {
+        self.stick_to_bottom = true;
+        self.scroll_to_message = None;
+        self.highlighted_message = None;
+    }
+}
+
+/// Everything a student has done in one lesson, kept separately per lesson so
+/// switching lessons never loses progress.
+#[derive(Default, serde::Deserialize, serde::Serialize)]
+#[serde(default)]
+struct LessonSession {
+    milestones: MilestoneTracker,
+    chat_history: Vec<ChatMessage>,
+    #[serde(skip)]
+    message_caches: Vec<CommonMarkCache>,
+    current_input: String,
+    /// The latest request that had older history trimmed, by the index of
+    /// the student message it sent.
+    #[serde(skip)]
+    context_notice: Option<(usize, ContextTrim)>,
+    /// When the student started typing the message in the input box.
+    #[serde(skip)]
+    typing_started_at: Option<DateTime<Utc>>,
+}
+
+impl LessonSession {
+    fn new(pack: &LessonPack) -> Self {
+        let initial_messages = vec![
+            ChatMessage {
+                content: "I am ready, please begin.".to_string(),
+                from_user: true,
+                analyzed_for_milestones: true,
+                found_milestones: Vec::new(),
+                unsent: false,
+                sent_at: None,
+                metadata: None,
+            },
+            ChatMessage {
+                content: pack.manifest.opening_message.clone(),
+                from_user: false,
+                analyzed_for_milestones: true,
+                found_milestones: Vec::new(),
+                unsent: false,
+                sent_at: Some(Utc::now()),
+                metadata: None,
+            },
+        ];
+
+        let mut session = Self {
+            milestones: MilestoneTracker::default(),
+            chat_history: initial_messages,
+            message_caches: Vec::new(),
+            current_input: String::new(),
+            context_notice: None,
+            typing_started_at: None,
+        };
+        session.sync_milestones(pack);
+        session.sync_caches();
+        session
+    }
+
+    /// Rebuilds the milestone list from the lesson pack, keeping the status of
+    /// milestones that still exist so edits to the instructions don't lose progress.
+    fn sync_milestones(&mut self, pack: &LessonPack) {
+        self.milestones =
+            MilestoneTracker::from_definitions(&pack.milestones, &self.milestones, Utc::now());
+    }
+
+    /// Makes sure every message has a markdown cache, e.g. after loading from storage.
+    fn sync_caches(&mut self) {
+        self.message_caches
+            .resize_with(self.chat_history.len(), CommonMarkCache::default);
+    }
+
+    /// Index of the tutor message whose marker completed `milestone_id`.
+    fn message_for_milestone(&self, milestone_id: &str) -> Option<usize> {
+        self.chat_history.iter().position(|message| {
+            message
+                .found_milestones
+                .iter()
+                .any(|m| m.milestone_id == milestone_id)
+        })
+    }
+
+    fn completed_count(&self) -> usize {
+        self.milestones.completed_count()
+    }
+}
+
+/// The single conversation saved at the top level of the app state before
+/// lessons had their own sessions.
+#[derive(Default, serde::Deserialize)]
+#[serde(default)]
+struct LegacyState {
+    milestones: MilestoneTracker,
+    chat_history: Vec<ChatMessage>,
+    current_input: String,
 }
 
 // At the top of app.rs, modify the LearningApp struct declaration:
@@ -67,18 +193,30 @@ impl ScrollState {
 #[serde(default)]
 pub struct LearningApp {
     label: String,
-    milestones: Vec<Milestone>,
     reset_modal_open: bool,
-    chat_history: Vec<ChatMessage>,
+    /// Per-lesson state, keyed by lesson id.
+    sessions: BTreeMap<String, LessonSession>,
+    /// The lesson being studied, or `None` while the catalog is shown.
+    active_lesson: Option<String>,
+    settings: Settings,
+    /// The saved settings and what the startup overrides made of them. Until
+    /// the student changes anything, the saved ones are what's stored, so
+    /// settings that came from a link don't outlive it.
     #[serde(skip)]
-    message_caches: Vec<CommonMarkCache>, // CommonMarkCache must also be Clone
-    current_input: String,
+    overridden_settings: Option<(Settings, Settings)>,
+    #[serde(skip)]
+    settings_open: bool,
+    /// Tokens used by replies since the app started.
+    #[serde(skip)]
+    usage: Usage,
     #[serde(skip)]
-    error_modal: Option<String>,
+    error_modal: Option<ApiError>,
     #[serde(skip)]
-    pending_message: Option<String>,
+    pending_message: Option<ChatMessage>,
     #[serde(skip)]
-    is_loading: bool,
+    pending_request: Option<PendingRequest>,
+    #[serde(skip)]
+    next_request_id: u64,
     #[serde(skip)]
     scroll_state: ScrollState,
     #[serde(skip)]
@@ -92,9 +230,18 @@ pub struct LearningApp {
     #[serde(skip)]
     auth_error: Option<String>,
     #[serde(skip)]
+    refreshing_session: bool,
+    /// When to try again after a refresh failed for a transient reason.
+    #[serde(skip)]
+    refresh_retry_at: Option<DateTime<Utc>>,
+    #[serde(skip)]
     auth_tx: Sender<AuthMessage>,
     #[serde(skip)]
     auth_rx: Receiver<AuthMessage>,
+    #[serde(skip)]
+    tutor_tx: Sender<TutorMessage>,
+    #[serde(skip)]
+    tutor_rx: Receiver<TutorMessage>,
 }
 
 #[derive(Debug, PartialEq)]
@@ -106,117 +253,185 @@ enum AuthStep {
 
 #[allow(dead_code)]
 enum AuthMessage {
-    OTPRequested(Result<(), String>),
-    OTPVerified(Result<(), String>),
+    OTPRequested(Result<(), ApiError>),
+    OTPVerified(Result<(), ApiError>),
+    SessionRefreshed(Result<(), ApiError>),
+}
+
+/// Progress reported by a tutor request, tagged with the request's id.
+enum TutorMessage {
+    Delta {
+        request_id: u64,
+        text: String,
+    },
+    /// The request failed and is being retried.
+    Retrying {
+        request_id: u64,
+        retry: u32,
+        of: u32,
+    },
+    Reply {
+        request_id: u64,
+        result: Result<TutorReply, ApiError>,
+    },
+}
+
+/// The tutor request in flight and where its reply belongs.
+struct PendingRequest {
+    id: u64,
+    /// Lesson whose session sent the request.
+    lesson_id: String,
+    /// Index of the user message being answered.
+    user_message: usize,
+    /// Index of the assistant message a streamed reply is being written into.
+    reply_message: Option<usize>,
+    /// The retry under way and how many are allowed, once a try has failed.
+    retry: Option<(u32, u32)>,
+    handle: Option<RequestHandle>,
 }
 
 impl Default for LearningApp {
     fn default() -> Self {
         let (auth_tx, auth_rx) = mpsc::channel();
-
-        let initial_messages = vec![
-            ChatMessage {
-                content: "I am ready, please begin.".to_string(),
-                from_user: true,
-                cacheable: false,
-                analyzed_for_milestones: true,
-                found_milestones: Vec::new(),
-            },
-            ChatMessage {
-                content: [
-                        "Hello! I'm excited to guide you through learning about one of computer science's most elegant sorting algorithms. Let's start with something simple.\n\n",
-                        "Imagine you have these numbers: `[7, 4, 2, 1]`\n\n",
-                        "How would you sort these numbers from smallest to largest? Don't worry about being efficient - just tell me your first instinct for how you'd do it."
-                ].concat(),
-                from_user: false,
-                cacheable: false,
-                analyzed_for_milestones: true,
-                found_milestones: Vec::new(),
-            },
-        ];
+        let (tutor_tx, tutor_rx) = mpsc::channel();
 
         Self {
             label: "Hello World!".to_owned(),
-            milestones: vec![
-                Milestone {
-                    id: "inefficiency_discovery".to_string(),
-                    description: "Understanding sorting inefficiency".to_string(),
-                    status: MilestoneStatus::InProgress,
-                },
-                Milestone {
-                    id: "splitting_insight".to_string(),
-                    description: "Discovering divide-and-conquer benefit".to_string(),
-                    status: MilestoneStatus::NotStarted,
-                },
-                Milestone {
-                    id: "merging_development".to_string(),
-                    description: "Understanding systematic merging".to_string(),
-                    status: MilestoneStatus::NotStarted,
-                },
-                Milestone {
-                    id: "recursive_pattern".to_string(),
-                    description: "Grasping recursive nature".to_string(),
-                    status: MilestoneStatus::NotStarted,
-                },
-                Milestone {
-                    id: "efficiency_analysis".to_string(),
-                    description: "Comprehending O(n log n) complexity".to_string(),
-                    status: MilestoneStatus::NotStarted,
-                },
-            ],
             reset_modal_open: false,
-            chat_history: initial_messages,
-            message_caches: vec![CommonMarkCache::default(), CommonMarkCache::default()],
-            current_input: String::new(),
+            sessions: BTreeMap::new(),
+            active_lesson: None,
+            settings: Settings::default(),
+            overridden_settings: None,
+            settings_open: false,
+            usage: Usage::default(),
             error_modal: None,
             pending_message: None,
-            is_loading: false,
+            pending_request: None,
+            next_request_id: 0,
             scroll_state: ScrollState::new(),
             auth_modal_open: false,
             auth_email: String::new(),
             auth_code: String::new(),
             auth_step: AuthStep::EnterEmail,
             auth_error: None,
+            refreshing_session: false,
+            refresh_retry_at: None,
             auth_tx,
             auth_rx,
+            tutor_tx,
+            tutor_rx,
         }
     }
 }
 
 impl LearningApp {
     pub fn new(cc: &eframe::CreationContext<'_>) -> Self {
-        if let Some(storage) = cc.storage {
+        load_lesson_catalog(&cc.egui_ctx);
+
+        let mut app = if let Some(storage) = cc.storage {
             // Initialize auth state from storage
             initialize_auth_state(storage);
 
             let mut app: Self = eframe::get_value(storage, eframe::APP_KEY).unwrap_or_default();
+            app.migrate_legacy_session(storage);
             // Initialize caches for any existing messages
-            app.message_caches = Vec::with_capacity(app.chat_history.len());
-            for _ in 0..app.chat_history.len() {
-                app.message_caches.push(CommonMarkCache::default());
+            let catalog = LESSON_CATALOG.lock().unwrap();
+            for (id, session) in app.sessions.iter_mut() {
+                session.sync_caches();
+                if let Some(pack) = catalog.get(id) {
+                    session.sync_milestones(pack);
+                }
             }
+            drop(catalog);
             app
         } else {
-            Default::default()
+            Self::default()
+        };
+
+        let saved = app.settings.clone();
+        match SettingsOverrides::load().and_then(|o| app.settings.apply(o)) {
+            Ok(()) if app.settings != saved => {
+                app.overridden_settings = Some((saved, app.settings.clone()));
+            }
+            Ok(()) => {}
+            Err(e) => log::error!("{}; keeping the saved tutor settings", e),
         }
+
+        set_active_lesson(app.active_lesson.as_deref());
+        app
     }
 
-    fn reset_to_default(&mut self) {
-        // Save the current auth state
-        let auth_state = AUTH_STATE.lock().unwrap();
-        let saved_email = auth_state.email.clone();
-        let saved_token = auth_state.access_token.clone();
-        let saved_signed_in = auth_state.signed_in;
-        drop(auth_state);
+    /// Moves a conversation saved before lessons had their own sessions into
+    /// the merge sort session, which is the lesson it belonged to.
+    fn migrate_legacy_session(&mut self, storage: &dyn eframe::Storage) {
+        if self.sessions.contains_key(LEGACY_LESSON_ID) {
+            return;
+        }
+        let Some(legacy) = eframe::get_value::<LegacyState>(storage, eframe::APP_KEY) else {
+            return;
+        };
+        if legacy.chat_history.is_empty() {
+            return;
+        }
 
-        // Reset the app
-        *self = Default::default();
+        log::info!(
+            "Moving the saved conversation into the '{}' lesson",
+            LEGACY_LESSON_ID
+        );
+        self.sessions.insert(
+            LEGACY_LESSON_ID.to_string(),
+            LessonSession {
+                milestones: legacy.milestones,
+                chat_history: legacy.chat_history,
+                current_input: legacy.current_input,
+                ..LessonSession::default()
+            },
+        );
+    }
 
-        // Restore auth state
-        let mut auth_state = AUTH_STATE.lock().unwrap();
-        auth_state.email = saved_email;
-        auth_state.access_token = saved_token;
-        auth_state.signed_in = saved_signed_in;
+    fn session(&self) -> Option<&LessonSession> {
+        self.active_lesson
+            .as_ref()
+            .and_then(|id| self.sessions.get(id))
+    }
+
+    fn session_mut(&mut self) -> Option<&mut LessonSession> {
+        self.active_lesson
+            .as_ref()
+            .and_then(|id| self.sessions.get_mut(id))
+    }
+
+    fn open_lesson(&mut self, pack: &LessonPack) {
+        let id = pack.manifest.id.clone();
+        self.sessions
+            .entry(id.clone())
+            .or_insert_with(|| LessonSession::new(pack));
+        set_active_lesson(Some(&id));
+        self.active_lesson = Some(id);
+        self.scroll_state.follow_latest();
+    }
+
+    fn close_lesson(&mut self) {
+        set_active_lesson(None);
+        self.active_lesson = None;
+    }
+
+    fn reset_to_default(&mut self) {
+        // Only the open lesson is reset; other lessons keep their progress
+        let Some(id) = self.active_lesson.clone() else {
+            return;
+        };
+        if self
+            .pending_request
+            .as_ref()
+            .is_some_and(|request| request.lesson_id == id)
+        {
+            self.stop_request();
+        }
+        let catalog = LESSON_CATALOG.lock().unwrap();
+        if let Some(pack) = catalog.get(&id) {
+            self.sessions.insert(id, LessonSession::new(pack));
+        }
     }
 
     fn render_side_panel(&mut self, ui: &mut egui::Ui) {
@@ -238,61 +453,166 @@ impl LearningApp {
             }
             drop(auth_state);
 
-            ui.label(
-                egui::RichText::new("Milestone Progress")
-                    .size(18.0)
-                    .heading(),
-            );
-            ui.add_space(8.0);
-            // Render milestones
-            self.milestones
-                .iter()
-                .enumerate()
-                .for_each(|(index, milestone)| {
-                    ui.horizontal(|ui| {
-                        ui.add_space(8.0);
-                        ui.with_layout(egui::Layout::left_to_right(egui::Align::Center), |ui| {
-                            // Show number + description or ???
-                            let display_text = match milestone.status {
-                                MilestoneStatus::Completed => {
-                                    format!("{}. {} ✔", index + 1, milestone.description)
-                                }
-                                MilestoneStatus::InProgress => {
-                                    format!("{}.   ? ? ?  (In Progress... ⏳)", index + 1)
-                                }
-                                MilestoneStatus::NotStarted => {
-                                    format!("{}.   ? ? ?", index + 1)
-                                }
-                            };
+            let available_width = ui.available_width();
+
+            if let Some(session) = self.session() {
+                let cue_after = chrono::Duration::minutes(
+                    LESSON_CATALOG
+                        .lock()
+                        .unwrap()
+                        .active_pack()
+                        .map_or(5, |pack| pack.manifest.checkpoint_cue_minutes)
+                        .into(),
+                );
+                let now = Utc::now();
+                let mut lingering = false;
+                let mut focus_milestone = None;
 
-                            ui.label(egui::RichText::new(display_text).size(15.0));
+                ui.label(
+                    egui::RichText::new("Milestone Progress")
+                        .size(18.0)
+                        .heading(),
+                );
+                ui.add_space(8.0);
+                // Render milestones
+                session
+                    .milestones
+                    .iter()
+                    .enumerate()
+                    .for_each(|(index, milestone)| {
+                        let elapsed = milestone.elapsed(now);
+                        let over_cue = milestone.status == MilestoneStatus::InProgress
+                            && elapsed.is_some_and(|e| e > cue_after);
+                        lingering |= over_cue;
+
+                        ui.horizontal(|ui| {
+                            ui.add_space(8.0);
+                            ui.with_layout(
+                                egui::Layout::left_to_right(egui::Align::Center),
+                                |ui| {
+                                    // Show number + description or ???
+                                    let display_text = match milestone.status {
+                                        MilestoneStatus::Completed if milestone.out_of_order => {
+                                            format!("{}. {} ✔ ⚠", index + 1, milestone.description)
+                                        }
+                                        MilestoneStatus::Completed => {
+                                            format!("{}. {} ✔", index + 1, milestone.description)
+                                        }
+                                        MilestoneStatus::InProgress => {
+                                            format!("{}.   ? ? ?  (In Progress... ⏳)", index + 1)
+                                        }
+                                        MilestoneStatus::NotStarted => {
+                                            format!("{}.   ? ? ?", index + 1)
+                                        }
+                                    };
+
+                                    let label = egui::RichText::new(display_text).size(15.0);
+                                    if milestone.status == MilestoneStatus::Completed {
+                                        if ui
+                                            .add(
+                                                egui::Label::new(label).sense(egui::Sense::click()),
+                                            )
+                                            .on_hover_text(match &milestone.evidence {
+                                                Some(evidence) => format!(
+                                                    "{}\n\nShow the message that earned this checkpoint",
+                                                    evidence
+                                                ),
+                                                None => "Show the message that earned this checkpoint"
+                                                    .to_string(),
+                                            })
+                                            .clicked()
+                                        {
+                                            focus_milestone = Some(milestone.id.clone());
+                                        }
+                                    } else {
+                                        ui.label(label);
+                                    }
+
+                                    if let Some(elapsed) = elapsed {
+                                        let timer = egui::RichText::new(format!(
+                                            "⏱ {}",
+                                            format_duration(elapsed)
+                                        ))
+                                        .size(13.0);
+                                        ui.label(if over_cue {
+                                            timer.color(egui::Color32::from_rgb(234, 179, 8))
+                                        } else {
+                                            timer.weak()
+                                        });
+                                    }
+                                },
+                            );
                         });
+                        ui.add_space(8.0);
                     });
+
+                if lingering {
+                    ui.label(
+                        egui::RichText::new(
+                            "💡 You've been on this checkpoint for a while. \
+                             It's fine to ask the tutor for a hint or another example!",
+                        )
+                        .color(egui::Color32::from_rgb(234, 179, 8)),
+                    );
                     ui.add_space(8.0);
-                });
+                }
 
-            ui.add_space(8.0);
-            ui.separator();
-            ui.add_space(8.0);
+                // Keep the timers ticking
+                if session
+                    .milestones
+                    .iter()
+                    .any(|m| m.status == MilestoneStatus::InProgress)
+                {
+                    ui.ctx()
+                        .request_repaint_after(std::time::Duration::from_secs(1));
+                }
 
-            let available_width = ui.available_width();
-            if ui
-                .add_sized(
-                    egui::vec2(available_width, 30.0),
-                    egui::Button::new("🔄 Reset Assignment"),
-                )
-                .clicked()
-            {
-                self.reset_modal_open = true;
-            }
+                render_milestone_graph(ui, &session.milestones);
 
-            ui.add_space(8.0);
-            ui.separator();
-            ui.add_space(8.0);
+                let focus_idx = focus_milestone.and_then(|id| session.message_for_milestone(&id));
+                if let Some(idx) = focus_idx {
+                    self.scroll_state.focus_message(idx);
+                }
+
+                ui.add_space(8.0);
+                ui.separator();
+                ui.add_space(8.0);
+
+                if ui
+                    .add_enabled(
+                        self.pending_request.is_none(),
+                        egui::Button::new("📚 All Lessons")
+                            .min_size(egui::vec2(available_width, 30.0)),
+                    )
+                    .clicked()
+                {
+                    self.close_lesson();
+                }
+
+                ui.add_space(8.0);
+
+                if ui
+                    .add_sized(
+                        egui::vec2(available_width, 30.0),
+                        egui::Button::new("🔄 Reset Assignment"),
+                    )
+                    .clicked()
+                {
+                    self.reset_modal_open = true;
+                }
+
+                ui.add_space(8.0);
+                ui.separator();
+                ui.add_space(8.0);
+            }
 
             ui.with_layout(
                 egui::Layout::top_down_justified(egui::Align::Center),
                 |ui| {
+                    if ui.button("⚙ Tutor Settings").clicked() {
+                        self.settings_open = !self.settings_open;
+                    }
+                    ui.add_space(8.0);
                     egui::widgets::global_theme_preference_buttons(ui);
                     ui.add_space(8.0);
                     ui.separator();
@@ -302,6 +622,63 @@ impl LearningApp {
             );
         });
     }
+    fn render_catalog_panel(&mut self, ui: &mut egui::Ui) {
+        let catalog = LESSON_CATALOG.lock().unwrap();
+        let mut selected = None;
+
+        egui::ScrollArea::vertical()
+            .auto_shrink([false; 2])
+            .show(ui, |ui| {
+                ui.add_space(8.0);
+                ui.label(egui::RichText::new("Choose a Lesson").size(20.0).heading());
+                ui.add_space(12.0);
+
+                for pack in &catalog.packs {
+                    let manifest = &pack.manifest;
+                    let session = self.sessions.get(&manifest.id);
+
+                    egui::Frame::none()
+                        .fill(ui.visuals().extreme_bg_color)
+                        .rounding(egui::Rounding::same(10.0))
+                        .inner_margin(egui::Margin::symmetric(12.0, 12.0))
+                        .show(ui, |ui| {
+                            ui.set_width(ui.available_width());
+                            ui.label(egui::RichText::new(&manifest.title).size(17.0).strong());
+                            if !manifest.description.is_empty() {
+                                ui.add_space(4.0);
+                                ui.label(&manifest.description);
+                            }
+                            ui.add_space(8.0);
+                            ui.horizontal(|ui| {
+                                let (progress, label) = match session {
+                                    Some(session) => (
+                                        format!(
+                                            "{} / {} milestones",
+                                            session.completed_count(),
+                                            session.milestones.len()
+                                        ),
+                                        "Continue",
+                                    ),
+                                    None => {
+                                        (format!("{} milestones", pack.milestones.len()), "Start")
+                                    }
+                                };
+                                ui.label(egui::RichText::new(progress).weak());
+                                if ui.button(label).clicked() {
+                                    selected = Some(pack.clone());
+                                }
+                            });
+                        });
+                    ui.add_space(10.0);
+                }
+            });
+
+        drop(catalog);
+        if let Some(pack) = selected {
+            self.open_lesson(&pack);
+        }
+    }
+
     fn render_chat_panel(&mut self, ui: &mut egui::Ui) {
         let available_height = ui.available_height();
         let (user_msg_bg, user_msg_stroke) = if ui.visuals().dark_mode {
@@ -310,6 +687,17 @@ impl LearningApp {
             (egui::Color32::from_rgb(254, 243, 199), egui::Color32::BLACK)
         };
 
+        let Some(session) = self
+            .active_lesson
+            .as_ref()
+            .and_then(|id| self.sessions.get_mut(id))
+        else {
+            return;
+        };
+        let mut outgoing = None;
+        let mut resend = None;
+        let mut stop = false;
+
         ui.vertical(|ui| {
             let scroll_area = egui::ScrollArea::vertical()
                 .auto_shrink([false; 2])
@@ -320,7 +708,7 @@ impl LearningApp {
                 let old_override_text_color = ui.style().visuals.override_text_color;
                 ui.style_mut().visuals.override_text_color = Some(user_msg_stroke);
 
-                for (idx, message) in self.chat_history.iter().enumerate() {
+                for (idx, message) in session.chat_history.iter().enumerate() {
                     if message.from_user {
                         ui.with_layout(egui::Layout::right_to_left(egui::Align::Min), |ui| {
                             egui::Frame::none()
@@ -330,24 +718,93 @@ impl LearningApp {
                                 .show(ui, |ui| {
                                     CommonMarkViewer::new().show(
                                         ui,
-                                        &mut self.message_caches[idx],
+                                        &mut session.message_caches[idx],
                                         &message.content,
                                     )
                                 });
                         });
+                        if message.unsent {
+                            ui.with_layout(egui::Layout::right_to_left(egui::Align::Min), |ui| {
+                                if ui
+                                    .add_enabled(
+                                        self.pending_request.is_none(),
+                                        egui::Button::new("Resend"),
+                                    )
+                                    .clicked()
+                                {
+                                    resend = Some(idx);
+                                }
+                                ui.label(egui::RichText::new("⚠ Not sent").weak());
+                            });
+                        }
+                        if let Some((_, trim)) = session
+                            .context_notice
+                            .as_ref()
+                            .filter(|(message, _)| *message == idx)
+                        {
+                            ui.with_layout(egui::Layout::right_to_left(egui::Align::Min), |ui| {
+                                ui.label(egui::RichText::new(format!("ℹ {}", trim)).weak())
+                                    .on_hover_text(
+                                        "Messages that earned milestones are always kept.",
+                                    );
+                            });
+                        }
+                        if let Some((retry, of)) = self
+                            .pending_request
+                            .as_ref()
+                            .filter(|request| request.user_message == idx)
+                            .and_then(|request| request.retry)
+                        {
+                            ui.with_layout(egui::Layout::right_to_left(egui::Align::Min), |ui| {
+                                ui.label(
+                                    egui::RichText::new(format!("Retrying ({}/{})…", retry, of))
+                                        .weak(),
+                                );
+                            });
+                        }
                     } else {
+                        // A reply that only marked a milestone has no text
+                        // of its own to show
+                        let text = strip_markers(&message.content);
+                        let awaited = self
+                            .pending_request
+                            .as_ref()
+                            .is_some_and(|request| request.reply_message == Some(idx));
+                        if text.trim().is_empty() && message.found_milestones.is_empty() && !awaited
+                        {
+                            continue;
+                        }
+                        let highlighted = self.scroll_state.highlighted_message == Some(idx);
                         ui.with_layout(egui::Layout::left_to_right(egui::Align::Min), |ui| {
-                            egui::Frame::none()
+                            let frame = egui::Frame::none()
                                 .fill(ui.visuals().extreme_bg_color)
                                 .rounding(egui::Rounding::same(10.0))
                                 .inner_margin(egui::Margin::symmetric(10.0, 10.0))
+                                .stroke(if highlighted {
+                                    egui::Stroke::new(2.0, MILESTONE_COLOR)
+                                } else {
+                                    egui::Stroke::NONE
+                                })
                                 .show(ui, |ui| {
-                                    CommonMarkViewer::new().show(
-                                        ui,
-                                        &mut self.message_caches[idx],
-                                        &message.content,
-                                    )
+                                    ui.vertical(|ui| {
+                                        // Markers stay in the transcript but are never shown
+                                        if !text.trim().is_empty() {
+                                            CommonMarkViewer::new().show(
+                                                ui,
+                                                &mut session.message_caches[idx],
+                                                &text,
+                                            );
+                                        }
+                                        for found in &message.found_milestones {
+                                            render_milestone_badge(ui, &found.description);
+                                        }
+                                    });
                                 });
+
+                            if self.scroll_state.scroll_to_message == Some(idx) {
+                                frame.response.scroll_to_me(Some(egui::Align::Center));
+                                self.scroll_state.scroll_to_message = None;
+                            }
                         });
                     }
                     ui.add_space(10.0);
@@ -369,27 +826,46 @@ impl LearningApp {
                         egui::ScrollArea::vertical()
                             .max_height(150.0) // Limit maximum height
                             .show(ui, |ui| {
-                                ui.add(
-                                    egui::TextEdit::multiline(&mut self.current_input)
+                                let input = ui.add(
+                                    egui::TextEdit::multiline(&mut session.current_input)
                                         .hint_text("Type your message here...")
                                         .desired_width(available_width * 0.87)
                                         .frame(true)
                                         .desired_rows(4), // Start with 3 rows
                                 );
+                                if input.changed() {
+                                    if session.current_input.is_empty() {
+                                        session.typing_started_at = None;
+                                    } else {
+                                        session.typing_started_at.get_or_insert_with(Utc::now);
+                                    }
+                                }
                             });
 
                         let button_width = available_width * 0.12;
                         let button_height = ui.spacing().interact_size.y * 4.0;
 
                         let auth_state = AUTH_STATE.lock().unwrap();
-                        let is_signed_in = auth_state.signed_in;
+                        // Scripted replies are for offline demos, where
+                        // signing in may not be possible
+                        let is_signed_in = auth_state.signed_in
+                            || matches!(self.settings.provider, Provider::Scripted);
                         drop(auth_state);
 
-                        if self.is_loading {
-                            ui.add_sized(
-                                egui::vec2(button_width, button_height),
-                                egui::Spinner::new(),
-                            );
+                        if self.pending_request.is_some() {
+                            ui.vertical(|ui| {
+                                ui.add_sized(
+                                    egui::vec2(button_width, button_height * 0.6),
+                                    egui::Spinner::new(),
+                                );
+                                stop = ui
+                                    .add_sized(
+                                        egui::vec2(button_width, button_height * 0.4),
+                                        egui::Button::new("⏹ Stop"),
+                                    )
+                                    .on_hover_text("Stop waiting for the tutor's reply")
+                                    .clicked();
+                            });
                         } else {
                             let button = egui::Button::new("Send")
                                 .min_size(egui::vec2(button_width, button_height));
@@ -399,28 +875,50 @@ impl LearningApp {
                                     self.auth_modal_open = true;
                                 }
                             } else if ui.add(button).clicked()
-                                && !self.current_input.is_empty()
-                                && !self.is_loading
+                                && !session.current_input.is_empty()
+                                && self.pending_request.is_none()
                             {
-                                let message = self.current_input.clone();
-                                self.current_input.clear();
-                                self.send_message(message);
+                                outgoing = Some((
+                                    std::mem::take(&mut session.current_input),
+                                    session.typing_started_at.take(),
+                                ));
                             }
                         }
                     });
                 });
         });
+
+        if stop {
+            self.stop_request();
+        }
+        if let Some(idx) = resend {
+            self.resend_message(idx);
+        }
+        if let Some((message, started_typing_at)) = outgoing {
+            self.send_message(message, started_typing_at);
+        }
     }
     fn render_reset_modal(&mut self, ctx: &egui::Context) {
         if self.reset_modal_open {
+            let title = self.active_lesson.as_deref().map(|id| {
+                LESSON_CATALOG
+                    .lock()
+                    .unwrap()
+                    .get(id)
+                    .map_or_else(|| id.to_string(), |pack| pack.manifest.title.clone())
+            });
             egui::Window::new("Reset Assignment")
                 .collapsible(false)
                 .resizable(false)
                 .show(ctx, |ui| {
-                    ui.label("⚠ This will reset all progress and cannot be undone!");
+                    ui.label(format!(
+                        "⚠ This will reset your progress in \"{}\" and cannot be undone! \
+                         Other lessons keep their progress.",
+                        title.unwrap_or_default()
+                    ));
                     ui.add_space(16.0);
                     ui.horizontal(|ui| {
-                        if ui.button("Yes, Reset Everything").clicked() {
+                        if ui.button("Yes, Reset This Lesson").clicked() {
                             self.reset_to_default();
                             self.reset_modal_open = false;
                         }
@@ -432,18 +930,139 @@ impl LearningApp {
         }
     }
 
-    fn handle_api_error(&mut self, error: String) {
+    /// Instructor-facing view of the tutor request settings.
+    fn render_settings_modal(&mut self, ctx: &egui::Context) {
+        let mut open = self.settings_open;
+        egui::Window::new("Tutor Settings")
+            .open(&mut open)
+            .collapsible(false)
+            .resizable(false)
+            .show(ctx, |ui| {
+                let settings = &mut self.settings;
+                egui::Grid::new("settings_grid")
+                    .num_columns(2)
+                    .spacing([12.0, 8.0])
+                    .show(ui, |ui| {
+                        ui.label("Provider");
+                        ui.label(settings.provider.kind());
+                        ui.end_row();
+
+                        if let Some(endpoint) = settings.provider.endpoint_mut() {
+                            ui.label("Endpoint");
+                            ui.text_edit_singleline(endpoint);
+                            ui.end_row();
+                        }
+                        if matches!(settings.provider, Provider::Proxy { .. })
+                            && !settings.provider.uses_sign_in()
+                        {
+                            ui.label("");
+                            ui.label(
+                                egui::RichText::new(
+                                    "Not a trusted course proxy, so your sign-in isn't sent to it.",
+                                )
+                                .weak(),
+                            );
+                            ui.end_row();
+                        }
+                        if let Some(model) = settings.provider.model_mut() {
+                            ui.label("Model");
+                            ui.text_edit_singleline(model);
+                            ui.end_row();
+                        }
+
+                        ui.label("Max tokens");
+                        ui.add(egui::DragValue::new(&mut settings.max_tokens).range(1..=8192));
+                        ui.end_row();
+
+                        ui.label("Temperature");
+                        ui.add(egui::Slider::new(&mut settings.temperature, 0.0..=2.0));
+                        ui.end_row();
+
+                        ui.label("Context budget");
+                        ui.add(
+                            egui::DragValue::new(&mut settings.context_budget)
+                                .range(1_000..=1_000_000)
+                                .speed(100)
+                                .suffix(" tokens"),
+                        );
+                        ui.end_row();
+
+                        ui.label("Over budget");
+                        ui.horizontal(|ui| {
+                            ui.radio_value(
+                                &mut settings.context_strategy,
+                                ContextStrategy::Summarise,
+                                "Summarise old turns",
+                            );
+                            ui.radio_value(
+                                &mut settings.context_strategy,
+                                ContextStrategy::Drop,
+                                "Drop old turns",
+                            );
+                        });
+                        ui.end_row();
+                    });
+
+                ui.add_space(8.0);
+                ui.label(
+                    egui::RichText::new(
+                        "Changes apply to the next message. Settings from the environment, \
+                         config file or URL replace these at startup.",
+                    )
+                    .weak(),
+                );
+                ui.add_space(8.0);
+                ui.separator();
+                let usage = self.usage;
+                let prompt = usage.input_tokens
+                    + usage.cache_read_input_tokens
+                    + usage.cache_creation_input_tokens;
+                ui.label(format!(
+                    "This session: {} prompt tokens, {} from cache ({}%), {} written to cache, {} output tokens",
+                    prompt,
+                    usage.cache_read_input_tokens,
+                    (usage.cache_read_input_tokens * 100).checked_div(prompt).unwrap_or(0),
+                    usage.cache_creation_input_tokens,
+                    usage.output_tokens
+                ));
+
+                ui.add_space(8.0);
+                if ui.button("Restore Defaults").clicked() {
+                    *settings = Settings::default();
+                }
+            });
+        self.settings_open = open;
+    }
+
+    fn handle_api_error(&mut self, error: ApiError) {
+        log::error!("Tutor request failed: {}", error);
         self.error_modal = Some(error);
-        if let Some(last_message) = self.chat_history.last() {
+        if let Some(last_message) = self.session().and_then(|s| s.chat_history.last()) {
             if last_message.from_user {
-                self.pending_message = Some(last_message.content.clone());
+                self.pending_message = Some(last_message.clone());
             }
         }
     }
 
+    /// Signs the student out and opens the sign-in window, explaining why.
+    fn return_to_sign_in(&mut self, error: &ApiError) {
+        log::info!("Returning to sign-in: {}", error);
+        let mut auth_state = AUTH_STATE.lock().unwrap();
+        auth_state.sign_out();
+        if let Some(email) = &auth_state.email {
+            self.auth_email = email.clone();
+        }
+        drop(auth_state);
+
+        self.auth_step = AuthStep::EnterEmail;
+        self.auth_error = Some(tutor_error_message(error));
+        self.auth_modal_open = true;
+    }
+
     fn retry_last_message(&mut self) {
         if let Some(message) = self.pending_message.take() {
-            self.send_message(message);
+            let started_typing_at = message.metadata.and_then(|m| m.started_typing_at);
+            self.send_message(message.content, started_typing_at);
         }
         self.error_modal = None;
     }
@@ -458,11 +1077,16 @@ impl LearningApp {
                 .collapsible(false)
                 .resizable(false)
                 .show(ctx, |ui| {
-                    ui.label("An error occurred:");
-                    ui.label(error);
+                    ui.label(tutor_error_message(&error));
                     ui.add_space(8.0);
-                    if has_pending && ui.button("Retry").clicked() {
-                        self.retry_last_message();
+                    match error {
+                        // Sending again won't help until the settings change
+                        ApiError::Config(_) => {}
+                        _ => {
+                            if has_pending && ui.button("Retry").clicked() {
+                                self.retry_last_message();
+                            }
+                        }
                     }
                     if ui.button("Close").clicked() {
                         self.error_modal = None;
@@ -472,73 +1096,288 @@ impl LearningApp {
         }
     }
 
-    fn send_message(&mut self, message: String) {
-        self.chat_history.push(ChatMessage {
-            content: message.clone(),
+    fn send_message(&mut self, message: String, started_typing_at: Option<DateTime<Utc>>) {
+        let Some(lesson_id) = self.active_lesson.clone() else {
+            return;
+        };
+        let Some(session) = self.sessions.get_mut(&lesson_id) else {
+            return;
+        };
+        let now = Utc::now();
+        let last_tutor_message_at = session
+            .chat_history
+            .iter()
+            .rev()
+            .find(|message| !message.from_user)
+            .and_then(|message| message.sent_at);
+        let message = ChatMessage {
+            content: message,
             from_user: true,
-            cacheable: false,
             analyzed_for_milestones: false,
             found_milestones: Vec::new(),
-        });
-        self.message_caches.push(CommonMarkCache::default());
+            unsent: false,
+            sent_at: Some(now),
+            metadata: Some(TurnMetadata::capture(
+                &session.milestones,
+                last_tutor_message_at,
+                started_typing_at,
+                now,
+            )),
+        };
+        session.chat_history.push(message.clone());
+        session.message_caches.push(CommonMarkCache::default());
+        let user_message = session.chat_history.len() - 1;
+
+        // Pass the chat history before adding the new message, leaving out
+        // anything that was never sent
+        let history: Vec<ChatMessage> = session.chat_history[..user_message]
+            .iter()
+            .filter(|message| !message.unsent)
+            .cloned()
+            .collect();
 
         // Set stick_to_bottom before and after adding the message
-        self.scroll_state.stick_to_bottom = true;
-        self.is_loading = true;
+        self.scroll_state.follow_latest();
+
+        self.next_request_id += 1;
+        let request_id = self.next_request_id;
+        let delta_tx = self.tutor_tx.clone();
+        let retry_tx = self.tutor_tx.clone();
+        let reply_tx = self.tutor_tx.clone();
+        let started = make_tutor_request(
+            &self.settings,
+            message,
+            history,
+            move |text| {
+                let _ = delta_tx.send(TutorMessage::Delta { request_id, text });
+            },
+            move |retry, of| {
+                let _ = retry_tx.send(TutorMessage::Retrying {
+                    request_id,
+                    retry,
+                    of,
+                });
+            },
+            move |result| {
+                let _ = reply_tx.send(TutorMessage::Reply { request_id, result });
+            },
+        );
+        let handle = started.map(|started| {
+            if let Some(trim) = started.trim {
+                session.context_notice = Some((user_message, trim));
+            }
+            started.handle
+        });
 
-        // Pass the chat history before adding the new message
-        let history = self.chat_history[..self.chat_history.len() - 1].to_vec();
+        self.pending_request = Some(PendingRequest {
+            id: request_id,
+            lesson_id,
+            user_message,
+            reply_message: None,
+            retry: None,
+            handle,
+        });
+    }
 
-        make_anthropic_request(message, history, |result| {
-            let mut state = PENDING_STATE.lock().unwrap();
-            match result {
-                Ok(response) => state.response = Some(response),
-                Err(error) => state.error = Some(error),
-            }
+    /// Aborts the in-flight request. Anything it still delivers is ignored and
+    /// the student's message is kept but marked as not sent.
+    fn stop_request(&mut self) {
+        let Some(request) = self.pending_request.take() else {
+            return;
+        };
+        if let Some(handle) = &request.handle {
+            handle.abort();
+        }
+
+        let Some(session) = self.sessions.get_mut(&request.lesson_id) else {
+            return;
+        };
+        if let Some(idx) = request.reply_message {
+            session.chat_history.truncate(idx);
+            session.message_caches.truncate(idx);
+        }
+        if let Some(message) = session.chat_history.get_mut(request.user_message) {
+            message.unsent = true;
+        }
+    }
+
+    /// Sends an unsent message again, moving it to the end of the chat.
+    fn resend_message(&mut self, idx: usize) {
+        let Some(session) = self.session_mut() else {
+            return;
+        };
+        if idx >= session.chat_history.len() {
+            return;
+        }
+        let message = session.chat_history.remove(idx);
+        session.message_caches.remove(idx);
+        let started_typing_at = message.metadata.and_then(|m| m.started_typing_at);
+        self.send_message(message.content, started_typing_at);
+    }
+
+    /// Writes streamed text into the reply of request `request_id`, starting
+    /// the assistant message when the first text arrives. Returns the index of
+    /// that message, or `None` if the request was stopped or superseded.
+    fn push_streamed_text(&mut self, request_id: u64, text: &str) -> Option<usize> {
+        let request = self
+            .pending_request
+            .as_mut()
+            .filter(|request| request.id == request_id)?;
+        let session = self.sessions.get_mut(&request.lesson_id)?;
+
+        let idx = *request.reply_message.get_or_insert_with(|| {
+            session.chat_history.push(ChatMessage {
+                content: String::new(),
+                from_user: false,
+                analyzed_for_milestones: false,
+                found_milestones: Vec::new(),
+                unsent: false,
+                sent_at: None,
+                metadata: None,
+            });
+            session.message_caches.push(CommonMarkCache::default());
+            session.chat_history.len() - 1
         });
+        session.chat_history[idx].content.push_str(text);
+        request.retry = None;
+        self.scroll_state.follow_latest();
+        Some(idx)
+    }
+
+    /// Completes request `request_id` with its final result. Results of
+    /// requests that were stopped or superseded are dropped.
+    fn finish_request(&mut self, request_id: u64, result: Result<TutorReply, ApiError>) {
+        match result {
+            Ok(reply) => {
+                // The complete reply replaces whatever was streamed
+                let Some(idx) = self.push_streamed_text(request_id, "") else {
+                    return;
+                };
+                let Some(request) = self.pending_request.take() else {
+                    return;
+                };
+                if let Some(session) = self.sessions.get_mut(&request.lesson_id) {
+                    session.chat_history[idx].content = reply.text;
+                    session.chat_history[idx].sent_at = Some(Utc::now());
+                }
+                if let Some(usage) = reply.usage {
+                    log::info!(
+                        "Reply used {} input tokens ({} read from cache, {} written to cache) and {} output tokens",
+                        usage.input_tokens,
+                        usage.cache_read_input_tokens,
+                        usage.cache_creation_input_tokens,
+                        usage.output_tokens
+                    );
+                    self.usage += usage;
+                }
+                self.record_milestones(&request.lesson_id, idx, reply.milestone_calls);
+            }
+            Err(error) => {
+                let Some(request) = self
+                    .pending_request
+                    .take_if(|request| request.id == request_id)
+                else {
+                    return;
+                };
+                if let (Some(idx), Some(session)) = (
+                    request.reply_message,
+                    self.sessions.get_mut(&request.lesson_id),
+                ) {
+                    session.chat_history.truncate(idx);
+                    session.message_caches.truncate(idx);
+                }
+                if error.needs_sign_in() {
+                    // Keep the message to send again once signed in
+                    if let Some(message) = self
+                        .sessions
+                        .get_mut(&request.lesson_id)
+                        .and_then(|session| session.chat_history.get_mut(request.user_message))
+                    {
+                        message.unsent = true;
+                    }
+                    self.return_to_sign_in(&error);
+                    return;
+                }
+                self.handle_api_error(error);
+            }
+        }
     }
 
-    fn scan_message_for_milestones(&mut self, message_idx: usize) {
-        let message = &self.chat_history[message_idx];
+    /// Applies the milestones the tutor marked with tool calls, falling back to
+    /// `MILESTONE[id]` markers in the text when it made none.
+    fn record_milestones(
+        &mut self,
+        lesson_id: &str,
+        message_idx: usize,
+        calls: Vec<MilestoneCall>,
+    ) {
+        let catalog = LESSON_CATALOG.lock().unwrap();
+        let (Some(pack), Some(session)) =
+            (catalog.get(lesson_id), self.sessions.get_mut(lesson_id))
+        else {
+            return;
+        };
+        let message = &session.chat_history[message_idx];
 
         if message.analyzed_for_milestones || message.from_user {
             return;
         }
 
-        let milestone_ids = [
-            "inefficiency_discovery",
-            "splitting_insight",
-            "merging_development",
-            "recursive_pattern",
-            "efficiency_analysis",
-        ];
+        let claims: Vec<(String, Option<String>)> = if calls.is_empty() {
+            let known_ids: Vec<&str> = pack.milestones.iter().map(|m| m.id.as_str()).collect();
+            let scan = markers::scan(&message.content, &known_ids);
+            for diagnostic in &scan.diagnostics {
+                log::warn!("Ignoring milestone marker: {}", diagnostic);
+            }
+            scan.markers
+                .into_iter()
+                .map(|marker| (marker.milestone_id, None))
+                .collect()
+        } else {
+            calls
+                .into_iter()
+                .map(|call| (call.milestone_id, Some(call.justification)))
+                .collect()
+        };
 
         let mut found_milestones = Vec::new();
-
-        for line in message.content.lines() {
-            if let Some(start) = line.find("MILESTONE[") {
-                if let Some(end) = line[start..].find("]") {
-                    let milestone_id = &line[(start + "MILESTONE[".len())..start + end];
-                    let milestone_id = milestone_id.trim();
-
-                    if milestone_ids.contains(&milestone_id) {
-                        if let Some(milestone) = self
-                            .milestones
-                            .iter_mut()
-                            .find(|c| c.id.as_str() == milestone_id)
-                        {
-                            milestone.status = MilestoneStatus::Completed;
-                            found_milestones.push(MilestoneMatch {
-                                milestone_id: milestone_id.to_string(),
-                                description: milestone.description.clone(),
-                            });
-                        }
+        for (milestone_id, evidence) in claims {
+            let Some(definition) = pack.milestone(&milestone_id) else {
+                log::warn!("Tutor marked unknown milestone '{}'", milestone_id);
+                continue;
+            };
+            match session.milestones.complete(
+                &definition.id,
+                pack.manifest.out_of_order_milestones,
+                Utc::now(),
+            ) {
+                Ok(events) => {
+                    for event in &events {
+                        log::info!(
+                            "Milestone '{}': {:?} -> {:?}{}",
+                            event.milestone_id,
+                            event.from,
+                            event.to,
+                            if event.out_of_order {
+                                " (out of order)"
+                            } else {
+                                ""
+                            }
+                        );
                     }
+                    if let Some(milestone) = session.milestones.get_mut(&definition.id) {
+                        milestone.evidence = evidence;
+                    }
+                    found_milestones.push(MilestoneMatch {
+                        milestone_id: definition.id.clone(),
+                        description: definition.description.clone(),
+                    });
                 }
+                Err(e) => log::warn!("Ignoring milestone marker: {}", e),
             }
         }
 
-        let message = &mut self.chat_history[message_idx];
+        let message = &mut session.chat_history[message_idx];
         message.found_milestones = found_milestones;
         message.analyzed_for_milestones = true;
     }
@@ -656,6 +1495,57 @@ impl LearningApp {
         }
     }
 
+    /// Refreshes the sign-in session shortly before its access token expires,
+    /// and signs out sessions that have expired and can't be refreshed.
+    fn refresh_session_if_due(&mut self, ctx: &egui::Context) {
+        if self.refreshing_session {
+            return;
+        }
+        let now = Utc::now();
+        let mut auth_state = AUTH_STATE.lock().unwrap();
+        let due = match auth_state.refresh_due() {
+            Some(due) => due.max(self.refresh_retry_at.unwrap_or(due)),
+            None => {
+                if auth_state.signed_in && auth_state.token_expired(now) {
+                    log::info!("Sign-in session expired");
+                    auth_state.sign_out();
+                }
+                return;
+            }
+        };
+        drop(auth_state);
+
+        if due > now {
+            if let Ok(wait) = (due - now).to_std() {
+                ctx.request_repaint_after(wait);
+            }
+            return;
+        }
+
+        self.refreshing_session = true;
+        self.refresh_retry_at = None;
+        let tx = self.auth_tx.clone();
+        let ctx = ctx.clone();
+
+        #[cfg(target_arch = "wasm32")]
+        {
+            spawn_local(async move {
+                let result = refresh_session_web().await;
+                let _ = tx.send(AuthMessage::SessionRefreshed(result));
+                ctx.request_repaint();
+            });
+        }
+
+        #[cfg(not(target_arch = "wasm32"))]
+        {
+            tokio::spawn(async move {
+                let result = refresh_session_native().await;
+                let _ = tx.send(AuthMessage::SessionRefreshed(result));
+                ctx.request_repaint();
+            });
+        }
+    }
+
     fn verify_otp(&mut self) {
         if self.auth_email.is_empty() {
             self.auth_error = Some("Please enter an email address".to_string());
@@ -694,10 +1584,193 @@ impl LearningApp {
     }
 }
 
+/// What to tell the student when a tutor request fails.
+fn tutor_error_message(error: &ApiError) -> String {
+    match error {
+        ApiError::Network(_) => {
+            "Couldn't reach the tutor. Check your internet connection and try again.".to_string()
+        }
+        ApiError::Interrupted(_) => {
+            "The connection dropped while the tutor was replying. Please try again.".to_string()
+        }
+        ApiError::Status { status: 429, .. } => {
+            "The tutor is busy right now. Please wait a minute and try again.".to_string()
+        }
+        ApiError::Status { status: 401, .. } | ApiError::Status { status: 403, .. } => {
+            "The tutor didn't accept your sign-in. Please sign in again.".to_string()
+        }
+        ApiError::Status { status, .. } if *status >= 500 => format!(
+            "The tutor service is having trouble (error {}). Please try again shortly.",
+            status
+        ),
+        ApiError::Status { status, .. } => {
+            format!("The tutor service turned the message down (error {}).", status)
+        }
+        ApiError::Decode(_) => "The tutor's reply couldn't be read. Please try again.".to_string(),
+        ApiError::Provider(message) => {
+            format!("The tutor ran into a problem while replying: {}", message)
+        }
+        ApiError::Config(message) => format!(
+            "The tutor isn't set up correctly: {}. Ask your instructor to check the tutor settings.",
+            message
+        ),
+        ApiError::Auth { message, .. } => format!("Sign-in problem: {}", message),
+        ApiError::NotAuthenticated => "Please sign in to talk to the tutor.".to_string(),
+        ApiError::SessionExpired => {
+            "Your sign-in has expired. Please sign in again to keep talking to the tutor."
+                .to_string()
+        }
+    }
+}
+
+/// What to tell the student when requesting or verifying a sign-in code fails.
+fn auth_error_message(error: &ApiError) -> String {
+    const RATE_LIMITED: &str = "Too many attempts. Please wait a few minutes before trying again.";
+
+    match error {
+        ApiError::Auth { code, message } => match code.as_str() {
+            "otp_expired" => "Code has expired or is invalid. Please try again.".to_string(),
+            "invalid_token" => "Invalid code. Please check and try again.".to_string(),
+            "over_email_send_rate_limit" | "over_request_rate_limit" => RATE_LIMITED.to_string(),
+            _ if message.is_empty() => format!("Sign-in failed ({})", code),
+            _ => format!("Sign-in failed: {}", message),
+        },
+        ApiError::Status { status: 429, .. } => RATE_LIMITED.to_string(),
+        ApiError::Network(_) => {
+            "Couldn't reach the sign-in service. Check your internet connection.".to_string()
+        }
+        _ => format!("Error: {}", error),
+    }
+}
+
+/// Celebratory chip shown in place of the marker in a tutor message.
+fn render_milestone_badge(ui: &mut egui::Ui, description: &str) {
+    ui.add_space(6.0);
+    egui::Frame::none()
+        .fill(MILESTONE_COLOR)
+        .rounding(egui::Rounding::same(8.0))
+        .inner_margin(egui::Margin::symmetric(8.0, 4.0))
+        .show(ui, |ui| {
+            ui.label(
+                egui::RichText::new(format!("🎉 Checkpoint reached: {}", description))
+                    .color(egui::Color32::BLACK)
+                    .size(13.0),
+            );
+        });
+}
+
+/// Formats a duration as `m:ss`, or `h:mm:ss` past an hour.
+fn format_duration(duration: chrono::Duration) -> String {
+    let seconds = duration.num_seconds().max(0);
+    let (hours, minutes, seconds) = (seconds / 3600, seconds / 60 % 60, seconds % 60);
+    if hours > 0 {
+        format!("{}:{:02}:{:02}", hours, minutes, seconds)
+    } else {
+        format!("{}:{:02}", minutes, seconds)
+    }
+}
+
+/// Draws the milestones as layers of numbered nodes, with an edge from each
+/// prerequisite to the milestones that depend on it.
+fn render_milestone_graph(ui: &mut egui::Ui, tracker: &MilestoneTracker) {
+    let depths = tracker.depths();
+    let Some(max_depth) = depths.iter().max() else {
+        return;
+    };
+
+    let node_radius = 12.0;
+    let layer_height = 40.0;
+    let width = ui.available_width();
+    let (rect, response) = ui.allocate_exact_size(
+        egui::vec2(width, (max_depth + 1) as f32 * layer_height),
+        egui::Sense::hover(),
+    );
+
+    // Spread each layer's nodes evenly across the panel
+    let positions: Vec<egui::Pos2> = depths
+        .iter()
+        .enumerate()
+        .map(|(index, depth)| {
+            let layer_size = depths.iter().filter(|d| *d == depth).count();
+            let slot = depths[..index].iter().filter(|d| *d == depth).count();
+            egui::pos2(
+                rect.left() + width * (slot as f32 + 1.0) / (layer_size as f32 + 1.0),
+                rect.top() + layer_height * (*depth as f32 + 0.5),
+            )
+        })
+        .collect();
+
+    let painter = ui.painter_at(rect);
+    let visuals = ui.visuals();
+    let milestones: Vec<_> = tracker.iter().collect();
+
+    for (index, milestone) in milestones.iter().enumerate() {
+        for required in &milestone.requires {
+            if let Some(from) = milestones.iter().position(|m| &m.id == required) {
+                painter.line_segment(
+                    [positions[from], positions[index]],
+                    visuals.widgets.noninteractive.fg_stroke,
+                );
+            }
+        }
+    }
+
+    let mut hovered = None;
+    for (index, milestone) in milestones.iter().enumerate() {
+        let (fill, text_color) = if milestone.status == MilestoneStatus::Completed {
+            (MILESTONE_COLOR, egui::Color32::BLACK)
+        } else if tracker.is_reachable(&milestone.id) {
+            (egui::Color32::from_rgb(234, 179, 8), egui::Color32::BLACK)
+        } else {
+            (
+                visuals.widgets.inactive.bg_fill,
+                visuals.strong_text_color(),
+            )
+        };
+
+        painter.circle(
+            positions[index],
+            node_radius,
+            fill,
+            visuals.widgets.noninteractive.fg_stroke,
+        );
+        painter.text(
+            positions[index],
+            egui::Align2::CENTER_CENTER,
+            (index + 1).to_string(),
+            egui::FontId::proportional(13.0),
+            text_color,
+        );
+
+        if response
+            .hover_pos()
+            .is_some_and(|pos| pos.distance(positions[index]) <= node_radius)
+        {
+            hovered = Some(match milestone.status {
+                MilestoneStatus::Completed => milestone.description.clone(),
+                _ if tracker.is_reachable(&milestone.id) => "? ? ?  (Available now)".to_string(),
+                _ => "? ? ?  (Locked)".to_string(),
+            });
+        }
+    }
+
+    if let Some(text) = hovered {
+        response.on_hover_text(text);
+    }
+}
+
 impl eframe::App for LearningApp {
     fn save(&mut self, storage: &mut dyn eframe::Storage) {
-        // Save both app state and auth state
-        eframe::set_value(storage, eframe::APP_KEY, self);
+        // Save both app state and auth state, leaving out settings that only
+        // came from overrides
+        match &self.overridden_settings {
+            Some((saved, overridden)) if *overridden == self.settings => {
+                let settings = std::mem::replace(&mut self.settings, saved.clone());
+                eframe::set_value(storage, eframe::APP_KEY, self);
+                self.settings = settings;
+            }
+            _ => eframe::set_value(storage, eframe::APP_KEY, self),
+        }
         save_auth_state(storage);
     }
 
@@ -705,21 +1778,16 @@ impl eframe::App for LearningApp {
         // Check for auth messages on both platforms
         while let Ok(msg) = self.auth_rx.try_recv() {
             match msg {
-                AuthMessage::OTPRequested(result) => {
-                    match result {
-                        Ok(_) => {
-                            self.auth_step = AuthStep::EnterCode;
-                            self.auth_error = None;
-                        }
-                        Err(e) => {
-                            self.auth_error = Some(match e.as_str() {
-                                s if s.contains("over_email_send_rate_limit") => 
-                                    "Too many attempts. Please wait a few minutes before trying again.".to_string(),
-                                _ => format!("Failed to send code: {}", e)
-                            });
-                        }
+                AuthMessage::OTPRequested(result) => match result {
+                    Ok(_) => {
+                        self.auth_step = AuthStep::EnterCode;
+                        self.auth_error = None;
                     }
-                }
+                    Err(e) => {
+                        log::error!("OTP request error: {}", e);
+                        self.auth_error = Some(auth_error_message(&e));
+                    }
+                },
                 AuthMessage::OTPVerified(result) => match result {
                     Ok(_) => {
                         self.auth_modal_open = false;
@@ -728,56 +1796,64 @@ impl eframe::App for LearningApp {
                     Err(e) => {
                         // Log the full error for debugging
                         log::error!("OTP verification error: {}", e);
-                        self.auth_error = Some(match e.as_str() {
-                            s if s.contains("otp_expired") => {
-                                "Code has expired or is invalid. Please try again.".to_string()
-                            }
-                            s if s.contains("invalid_token") => {
-                                "Invalid code. Please check and try again.".to_string()
-                            }
-                            s if s.contains("rate_limit") => {
-                                "Too many attempts. Please wait a few minutes before trying again."
-                                    .to_string()
-                            }
-                            _ => format!("Error: {}", e),
-                        });
+                        self.auth_error = Some(auth_error_message(&e));
                     }
                 },
+                AuthMessage::SessionRefreshed(result) => {
+                    self.refreshing_session = false;
+                    match result {
+                        Ok(_) => log::info!("Refreshed the sign-in session"),
+                        Err(e) if e.is_transient() => {
+                            log::warn!("Session refresh failed, will retry: {}", e);
+                            self.refresh_retry_at =
+                                Some(Utc::now() + chrono::Duration::seconds(REFRESH_RETRY_SECS));
+                        }
+                        Err(e) => {
+                            log::warn!("Session refresh was refused: {}", e);
+                            self.return_to_sign_in(&ApiError::SessionExpired);
+                        }
+                    }
+                }
             }
         }
+        self.refresh_session_if_due(ctx);
 
-        // Check for pending messages
-        let mut state = PENDING_STATE.lock().unwrap();
-        if let Some(response) = state.response.take() {
-            self.scroll_state.stick_to_bottom = true; // Set before adding AI response
-            self.chat_history.push(ChatMessage {
-                content: response,
-                from_user: false,
-                cacheable: false,
-                analyzed_for_milestones: false,
-                found_milestones: Vec::new(),
-            });
-            self.message_caches.push(CommonMarkCache::default());
-
-            let last_idx = self.chat_history.len() - 1;
-            self.scan_message_for_milestones(last_idx);
-
-            self.is_loading = false;
-            ctx.request_repaint();
-        }
-        if let Some(error) = state.error.take() {
-            self.handle_api_error(error);
-            self.is_loading = false; // Reset loading state on error
+        // Check for tutor replies
+        while let Ok(msg) = self.tutor_rx.try_recv() {
+            match msg {
+                TutorMessage::Delta { request_id, text } => {
+                    self.push_streamed_text(request_id, &text);
+                }
+                TutorMessage::Retrying {
+                    request_id,
+                    retry,
+                    of,
+                } => {
+                    if let Some(request) = self
+                        .pending_request
+                        .as_mut()
+                        .filter(|request| request.id == request_id)
+                    {
+                        request.retry = Some((retry, of));
+                    }
+                }
+                TutorMessage::Reply { request_id, result } => {
+                    self.finish_request(request_id, result);
+                    ctx.request_repaint();
+                }
+            }
         }
 
+        let title = LESSON_CATALOG
+            .lock()
+            .unwrap()
+            .active_pack()
+            .map(|pack| pack.manifest.title.clone())
+            .unwrap_or_else(|| "Lessons".to_string());
         egui::TopBottomPanel::top("top_panel").show(ctx, |ui| {
             ui.add_space(8.0);
             ui.vertical_centered(|ui| {
-                ui.label(
-                    egui::RichText::new("Week 12 - Recursion and MergeSort")
-                        .size(24.0)
-                        .heading(),
-                );
+                ui.label(egui::RichText::new(title).size(24.0).heading());
             });
         });
 
@@ -786,11 +1862,97 @@ impl eframe::App for LearningApp {
         });
 
         egui::CentralPanel::default().show(ctx, |ui| {
-            self.render_chat_panel(ui);
+            if self.session().is_some() {
+                self.render_chat_panel(ui);
+            } else {
+                self.render_catalog_panel(ui);
+            }
         });
 
         self.render_reset_modal(ctx);
+        self.render_settings_modal(ctx);
         self.render_error_modal(ctx);
         self.render_auth_modal(ctx);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    #[derive(Default)]
+    struct MemoryStorage(HashMap<String, String>);
+
+    impl eframe::Storage for MemoryStorage {
+        fn get_string(&self, key: &str) -> Option<String> {
+            self.0.get(key).cloned()
+        }
+
+        fn set_string(&mut self, key: &str, value: String) {
+            self.0.insert(key.to_string(), value);
+        }
+
+        fn flush(&mut self) {}
+    }
+
+    #[test]
+    fn conversation_saved_before_lessons_moves_into_mergesort() {
+        let mut storage = MemoryStorage::default();
+        eframe::Storage::set_string(
+            &mut storage,
+            eframe::APP_KEY,
+            r#"(
+                label: "Hello World!",
+                milestones: [
+                    (id: "inefficiency_discovery", description: "Understanding Sorting Inefficiency", status: Completed),
+                    (id: "splitting_insight", description: "Divide and Conquer Insight", status: InProgress),
+                ],
+                reset_modal_open: false,
+                chat_history: [
+                    (content: "I am ready, please begin.", from_user: true, cacheable: false, found_milestones: []),
+                    (content: "How would you sort these?", from_user: false, cacheable: true, found_milestones: []),
+                ],
+                current_input: "Compare every pair",
+            )"#
+            .to_string(),
+        );
+
+        let mut app: LearningApp = eframe::get_value(&storage, eframe::APP_KEY).unwrap_or_default();
+        app.migrate_legacy_session(&storage);
+
+        let session = &app.sessions[LEGACY_LESSON_ID];
+        assert_eq!(session.chat_history.len(), 2);
+        assert_eq!(session.chat_history[1].content, "How would you sort these?");
+        assert_eq!(session.current_input, "Compare every pair");
+        assert!(session.milestones.is_completed("inefficiency_discovery"));
+
+        // Once saved in the new shape, nothing is migrated again
+        eframe::set_value(&mut storage, eframe::APP_KEY, &app);
+        let mut reloaded: LearningApp = eframe::get_value(&storage, eframe::APP_KEY).unwrap();
+        reloaded.migrate_legacy_session(&storage);
+        assert_eq!(reloaded.sessions[LEGACY_LESSON_ID].chat_history.len(), 2);
+    }
+    #[test]
+    fn settings_from_a_link_are_not_saved() {
+        let saved = Settings::default();
+        let mut linked = saved.clone();
+        linked.max_tokens = 512;
+        let mut app = LearningApp {
+            settings: linked.clone(),
+            overridden_settings: Some((saved.clone(), linked)),
+            ..LearningApp::default()
+        };
+        let mut storage = MemoryStorage::default();
+        eframe::App::save(&mut app, &mut storage);
+        let reloaded: LearningApp = eframe::get_value(&storage, eframe::APP_KEY).unwrap();
+        assert_eq!(reloaded.settings, saved);
+        assert_eq!(app.settings.max_tokens, 512);
+
+        // What the student changes afterwards is theirs to keep
+        app.settings.temperature = 0.5;
+        eframe::App::save(&mut app, &mut storage);
+        let reloaded: LearningApp = eframe::get_value(&storage, eframe::APP_KEY).unwrap();
+        assert_eq!(reloaded.settings, app.settings);
+    }
+}
@@ -0,0 +1,382 @@
+#![warn(clippy::all)]
+
+use serde::{Deserialize, Serialize};
+use std::ops::Range;
+
+use crate::metadata;
+use crate::providers::{ChatRequest, ChatTurn};
+
+/// Roughly how many characters make a token, across the models we support.
+const CHARS_PER_TOKEN: usize = 4;
+/// Role markers and separators each message adds, in tokens.
+const MESSAGE_OVERHEAD: usize = 4;
+/// The newest turns, always sent so the tutor can follow the conversation.
+const KEEP_RECENT: usize = 4;
+/// Exchanges trimmed together. Trimming a few at a time, at fixed places in
+/// the conversation, keeps the trimmed history and summary the same from one
+/// request to the next, so the history after it can still be read from the
+/// cache.
+const TRIM_CHUNK: usize = 4;
+/// How much of each turn a summary keeps, in characters.
+const SUMMARY_EXCERPT: usize = 160;
+const SUMMARY_HEADING: &str = "[Summary of earlier conversation, shortened to save space]";
+
+/// What to do with older turns once a request would exceed the context budget.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum ContextStrategy {
+    /// Replace them with a short excerpt of each.
+    #[default]
+    Summarise,
+    /// Leave them out.
+    Drop,
+}
+
+impl ContextStrategy {
+    pub(crate) fn parse(value: &str) -> Option<Self> {
+        match value {
+            "summarise" | "summarize" => Some(Self::Summarise),
+            "drop" => Some(Self::Drop),
+            _ => None,
+        }
+    }
+}
+
+/// Older turns that were summarised or dropped to fit a request in budget.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct ContextTrim {
+    pub(crate) strategy: ContextStrategy,
+    pub(crate) messages: usize,
+}
+
+impl std::fmt::Display for ContextTrim {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let action = match self.strategy {
+            ContextStrategy::Summarise => "summarised",
+            ContextStrategy::Drop => "left out",
+        };
+        write!(
+            f,
+            "{} earlier message{} {} {} to fit the tutor's memory",
+            self.messages,
+            if self.messages == 1 { "" } else { "s" },
+            if self.messages == 1 { "was" } else { "were" },
+            action
+        )
+    }
+}
+
+/// A rough token count: close enough to budget with, not to bill with.
+pub(crate) fn estimate_tokens(text: &str) -> usize {
+    text.chars().count().div_ceil(CHARS_PER_TOKEN)
+}
+
+fn turn_tokens(turn: &ChatTurn) -> usize {
+    estimate_tokens(&turn.content) + MESSAGE_OVERHEAD
+}
+
+/// Estimated size of the whole request: system prompt, tools and messages.
+pub(crate) fn request_tokens(chat: &ChatRequest) -> usize {
+    let tools = serde_json::to_string(&chat.tools).unwrap_or_default();
+    estimate_tokens(&chat.system)
+        + estimate_tokens(&tools)
+        + chat.messages.iter().map(turn_tokens).sum::<usize>()
+}
+
+/// Trims the oldest exchanges, [`TRIM_CHUNK`] at a time, until the request
+/// fits in `budget` tokens. A chunk is only trimmed once all of it is older
+/// than the most recent turns. Exchanges with a pinned turn and the most
+/// recent turns are always kept, so
+/// a request that can't be brought under budget is sent anyway, as small as it
+/// can be made. Whole exchanges are trimmed and a summary is folded into the
+/// student turn after it, so turns still alternate between student and tutor.
+pub(crate) fn fit_to_budget(
+    chat: &mut ChatRequest,
+    budget: usize,
+    strategy: ContextStrategy,
+) -> Option<ContextTrim> {
+    let mut total = request_tokens(chat);
+    if total <= budget {
+        return None;
+    }
+
+    let recent = chat.messages.len().saturating_sub(KEEP_RECENT);
+    let mut removed = vec![false; chat.messages.len()];
+    let mut summary = Vec::new();
+    let mut summary_tokens = 0;
+
+    let exchanges = exchanges(&chat.messages);
+    let chunks = exchanges
+        .chunks(TRIM_CHUNK)
+        .take_while(|chunk| chunk.len() == TRIM_CHUNK && chunk[TRIM_CHUNK - 1].end <= recent);
+    for chunk in chunks {
+        if total <= budget {
+            break;
+        }
+
+        for exchange in chunk {
+            if chat.messages[exchange.clone()]
+                .iter()
+                .any(|turn| turn.pinned)
+            {
+                continue;
+            }
+
+            for idx in exchange.clone() {
+                let turn = &chat.messages[idx];
+                removed[idx] = true;
+                total -= turn_tokens(turn);
+
+                if strategy == ContextStrategy::Summarise {
+                    let line = summary_line(turn);
+                    let mut added = estimate_tokens(&line) + 1;
+                    if summary.is_empty() {
+                        added += estimate_tokens(SUMMARY_HEADING);
+                    }
+                    total += added;
+                    summary_tokens += added;
+                    summary.push(line);
+                }
+            }
+        }
+    }
+
+    let trimmed = removed.iter().filter(|r| **r).count();
+    if trimmed == 0 {
+        return None;
+    }
+    if total > budget {
+        log::warn!(
+            "Request is still about {} tokens after trimming, over the budget of {}",
+            total,
+            budget
+        );
+    }
+    log::info!(
+        "Trimmed {} messages to fit the context budget ({} summary tokens)",
+        trimmed,
+        summary_tokens
+    );
+
+    let first_removed = removed.iter().position(|r| *r).unwrap_or(0);
+    let mut kept: Vec<ChatTurn> = std::mem::take(&mut chat.messages)
+        .into_iter()
+        .zip(removed)
+        .filter_map(|(turn, removed)| (!removed).then_some(turn))
+        .collect();
+    // Trimming stops short of the recent turns at the start of an exchange, so
+    // a student turn follows the first trimmed one
+    if let Some(turn) = kept.get_mut(first_removed).filter(|_| !summary.is_empty()) {
+        turn.content = format!(
+            "{}\n{}\n\n{}",
+            SUMMARY_HEADING,
+            summary.join("\n"),
+            turn.content
+        );
+    }
+    chat.messages = kept;
+
+    Some(ContextTrim {
+        strategy,
+        messages: trimmed,
+    })
+}
+
+/// Splits turns into exchanges: a student turn and the tutor turns after it.
+/// Tutor turns before the first student turn are an exchange of their own.
+fn exchanges(turns: &[ChatTurn]) -> Vec<Range<usize>> {
+    let mut starts: Vec<usize> = (0..turns.len())
+        .filter(|&idx| idx == 0 || turns[idx].from_user)
+        .collect();
+    starts.push(turns.len());
+    starts.windows(2).map(|pair| pair[0]..pair[1]).collect()
+}
+
+/// Marks what the provider should cache: the system prompt, which holds the
+/// whole lesson, and the last turn before the student's new message. That
+/// breakpoint moves on by one exchange per request, so each request reads the
+/// history up to the previous one from the cache, unless [`fit_to_budget`]
+/// has just trimmed another chunk.
+pub(crate) fn mark_cache_breakpoints(chat: &mut ChatRequest) {
+    chat.cache_system = true;
+    let breakpoint = chat.messages.len().checked_sub(2);
+    for (idx, turn) in chat.messages.iter_mut().enumerate() {
+        turn.cacheable = Some(idx) == breakpoint;
+    }
+}
+
+fn summary_line(turn: &ChatTurn) -> String {
+    let speaker = if turn.from_user { "Student" } else { "Tutor" };
+    let text = metadata::strip_block(&turn.content)
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ");
+    match text.char_indices().nth(SUMMARY_EXCERPT) {
+        Some((end, _)) => format!("- {}: {}…", speaker, &text[..end]),
+        None => format!("- {}: {}", speaker, text),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn turn(from_user: bool, content: &str, pinned: bool) -> ChatTurn {
+        ChatTurn {
+            from_user,
+            content: content.to_string(),
+            cacheable: false,
+            pinned,
+        }
+    }
+
+    fn chat(messages: Vec<ChatTurn>) -> ChatRequest {
+        ChatRequest {
+            system: "You are a patient tutor.".to_string(),
+            messages,
+            tools: Vec::new(),
+            max_tokens: 512,
+            temperature: 0.0,
+            cache_system: false,
+            email: "student@uni.edu".to_string(),
+            access_token: None,
+        }
+    }
+
+    /// `turns` turns of about 100 tokens, the second exchange pinned for a
+    /// milestone.
+    fn long_session(turns: usize) -> ChatRequest {
+        let long = "compare ".repeat(50);
+        chat(
+            (0..turns)
+                .map(|i| turn(i % 2 == 0, &format!("{} {}", i, long), i == 2 || i == 3))
+                .collect(),
+        )
+    }
+
+    fn contents(chat: &ChatRequest) -> Vec<String> {
+        chat.messages
+            .iter()
+            .map(|m| m.content.split_whitespace().next().unwrap().to_string())
+            .collect()
+    }
+
+    /// Turns alternate between student and tutor, starting with the student.
+    fn assert_alternates(chat: &ChatRequest) {
+        for (idx, turn) in chat.messages.iter().enumerate() {
+            assert_eq!(
+                turn.from_user,
+                idx % 2 == 0,
+                "turn {} of {:?}",
+                idx,
+                contents(chat)
+            );
+        }
+    }
+
+    #[test]
+    fn requests_within_budget_are_untouched() {
+        let mut request = long_session(14);
+        assert_eq!(
+            fit_to_budget(&mut request, 10_000, ContextStrategy::Drop),
+            None
+        );
+        assert_eq!(request.messages.len(), 14);
+    }
+
+    #[test]
+    fn dropping_keeps_pinned_and_recent_turns() {
+        let mut request = long_session(14);
+        let trim = fit_to_budget(&mut request, 900, ContextStrategy::Drop);
+
+        assert_eq!(
+            trim,
+            Some(ContextTrim {
+                strategy: ContextStrategy::Drop,
+                messages: 6,
+            })
+        );
+        assert_eq!(
+            contents(&request),
+            vec!["2", "3", "8", "9", "10", "11", "12", "13"]
+        );
+        assert_alternates(&request);
+        assert!(request_tokens(&request) <= 900);
+    }
+
+    #[test]
+    fn summarising_replaces_old_turns_with_excerpts() {
+        let mut request = long_session(14);
+        let trim = fit_to_budget(&mut request, 1200, ContextStrategy::Summarise).unwrap();
+
+        assert_eq!(trim.messages, 6);
+        assert_eq!(
+            contents(&request),
+            vec!["[Summary", "3", "8", "9", "10", "11", "12", "13"]
+        );
+        assert_alternates(&request);
+        let summary = &request.messages[0].content;
+        assert!(summary.contains("- Student: 0 compare"), "{}", summary);
+        assert!(summary.contains("- Tutor: 7 compare"), "{}", summary);
+        assert!(summary.contains("\n\n2 compare"), "{}", summary);
+        let (excerpts, _) = summary.split_once("\n\n").unwrap();
+        assert!(excerpts.lines().all(|line| line.chars().count() < 200));
+        assert!(request_tokens(&request) <= 1200);
+    }
+
+    #[test]
+    fn recent_turns_are_sent_even_over_budget() {
+        let mut request = long_session(14);
+        fit_to_budget(&mut request, 10, ContextStrategy::Drop);
+        assert_eq!(
+            contents(&request),
+            vec!["2", "3", "8", "9", "10", "11", "12", "13"]
+        );
+        assert_alternates(&request);
+    }
+
+    #[test]
+    fn cache_breakpoint_follows_the_conversation() {
+        let mut request = chat(vec![
+            turn(true, "I am ready, please begin.", false),
+            turn(false, "How would you sort [7, 2, 4, 1]?", false),
+            turn(true, "Compare every pair?", false),
+        ]);
+        mark_cache_breakpoints(&mut request);
+
+        assert!(request.cache_system);
+        let cacheable: Vec<bool> = request.messages.iter().map(|m| m.cacheable).collect();
+        assert_eq!(cacheable, vec![false, true, false]);
+    }
+
+    #[test]
+    fn requests_over_budget_in_a_row_share_their_cached_history() {
+        let mut first = long_session(14);
+        let mut second = long_session(16);
+        for request in [&mut first, &mut second] {
+            fit_to_budget(request, 1200, ContextStrategy::Summarise).unwrap();
+            mark_cache_breakpoints(request);
+        }
+
+        // Everything up to the first request's breakpoint is sent unchanged
+        let breakpoint = first.messages.iter().position(|m| m.cacheable).unwrap();
+        assert_eq!(breakpoint, first.messages.len() - 2);
+        for (idx, turn) in first.messages[..=breakpoint].iter().enumerate() {
+            assert_eq!(turn.content, second.messages[idx].content, "turn {}", idx);
+            assert_eq!(turn.from_user, second.messages[idx].from_user);
+        }
+        assert_alternates(&second);
+    }
+
+    #[test]
+    fn trim_notice_reads_naturally() {
+        let trim = ContextTrim {
+            strategy: ContextStrategy::Summarise,
+            messages: 1,
+        };
+        assert_eq!(
+            trim.to_string(),
+            "1 earlier message was summarised to fit the tutor's memory"
+        );
+    }
+}
@@ -0,0 +1,133 @@
+#![warn(clippy::all)]
+
+use serde::Deserialize;
+use std::time::Duration;
+
+/// Why a request to the tutor or to Supabase failed.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum ApiError {
+    /// The request never got a response.
+    Network(String),
+    /// The connection dropped after part of a reply had been shown.
+    Interrupted(String),
+    /// The server answered with an error status.
+    Status {
+        status: u16,
+        retry_after: Option<Duration>,
+    },
+    /// Supabase rejected a sign-in request, e.g. with `otp_expired`.
+    Auth { code: String, message: String },
+    /// A response couldn't be decoded.
+    Decode(String),
+    /// The model provider reported an error partway through a reply.
+    Provider(String),
+    /// The request couldn't be built from the current settings.
+    Config(String),
+    /// The student isn't signed in.
+    NotAuthenticated,
+    /// The student's access token has expired or was rejected.
+    SessionExpired,
+}
+
+impl std::fmt::Display for ApiError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Network(e) => write!(f, "Network error: {}", e),
+            Self::Interrupted(e) => write!(f, "Connection lost mid-reply: {}", e),
+            Self::Status { status, .. } => write!(f, "Server error: {}", status),
+            Self::Auth { code, message } => write!(f, "Auth error {}: {}", code, message),
+            Self::Decode(e) => write!(f, "Failed to parse response: {}", e),
+            Self::Provider(e) => write!(f, "Provider error: {}", e),
+            Self::Config(e) => write!(f, "Configuration error: {}", e),
+            Self::NotAuthenticated => write!(f, "Not authenticated"),
+            Self::SessionExpired => write!(f, "Session expired"),
+        }
+    }
+}
+
+impl ApiError {
+    /// Whether sending the same request again may succeed without anyone
+    /// changing anything.
+    pub(crate) fn is_transient(&self) -> bool {
+        match self {
+            Self::Network(_) => true,
+            Self::Status { status, .. } => *status == 429 || (500..600).contains(status),
+            _ => false,
+        }
+    }
+
+    /// Whether the student has to sign in (again) before trying again.
+    pub(crate) fn needs_sign_in(&self) -> bool {
+        matches!(self, Self::NotAuthenticated | Self::SessionExpired)
+    }
+
+    pub(crate) fn retry_after(&self) -> Option<Duration> {
+        match self {
+            Self::Status { retry_after, .. } => *retry_after,
+            _ => None,
+        }
+    }
+
+    /// Reads a Supabase auth error body, which names the problem with an
+    /// `error_code` (or, from older servers, `error`). Bodies without a code
+    /// are reported by their status.
+    pub(crate) fn from_auth_response(status: u16, body: &str) -> Self {
+        #[derive(Deserialize)]
+        struct AuthErrorBody {
+            #[serde(alias = "error")]
+            error_code: Option<String>,
+            #[serde(alias = "error_description")]
+            msg: Option<String>,
+        }
+
+        match serde_json::from_str::<AuthErrorBody>(body) {
+            Ok(AuthErrorBody {
+                error_code: Some(code),
+                msg,
+            }) => Self::Auth {
+                code,
+                message: msg.unwrap_or_default(),
+            },
+            _ => Self::Status {
+                status,
+                retry_after: None,
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn auth_errors_keep_their_code() {
+        assert_eq!(
+            ApiError::from_auth_response(
+                403,
+                r#"{"code":403,"error_code":"otp_expired","msg":"Token has expired or is invalid"}"#
+            ),
+            ApiError::Auth {
+                code: "otp_expired".to_string(),
+                message: "Token has expired or is invalid".to_string(),
+            }
+        );
+        assert_eq!(
+            ApiError::from_auth_response(
+                400,
+                r#"{"error":"invalid_grant","error_description":"Invalid Refresh Token"}"#
+            ),
+            ApiError::Auth {
+                code: "invalid_grant".to_string(),
+                message: "Invalid Refresh Token".to_string(),
+            }
+        );
+        assert_eq!(
+            ApiError::from_auth_response(502, "<html>Bad Gateway</html>"),
+            ApiError::Status {
+                status: 502,
+                retry_after: None,
+            }
+        );
+    }
+}
@@ -0,0 +1,639 @@
+#![warn(clippy::all)]
+
+use lazy_static::lazy_static;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::sync::Mutex;
+
+use crate::milestones::OutOfOrderPolicy;
+use crate::scripted::Transcript;
+
+/// Placeholder in the instructions file that is replaced with the lesson markdown.
+const LESSON_PLACEHOLDER: &str = "{{LESSON_CONTENT}}";
+
+/// Packs that ship inside the binary, used when no catalog can be loaded at runtime.
+const BUILTIN_PACKS: &[(&str, &str, &str, Option<&str>)] = &[
+    (
+        include_str!("../lessons/binary-search/lesson.json"),
+        include_str!("../lessons/binary-search/instructions.md"),
+        include_str!("../lessons/binary-search/lesson.md"),
+        None,
+    ),
+    (
+        include_str!("../lessons/recursion-basics/lesson.json"),
+        include_str!("../lessons/recursion-basics/instructions.md"),
+        include_str!("../lessons/recursion-basics/lesson.md"),
+        None,
+    ),
+    (
+        include_str!("../lessons/mergesort/lesson.json"),
+        include_str!("../lessons/mergesort/instructions.md"),
+        include_str!("../lessons/mergesort/lesson.md"),
+        Some(include_str!("../lessons/mergesort/transcript.json")),
+    ),
+    (
+        include_str!("../lessons/quicksort/lesson.json"),
+        include_str!("../lessons/quicksort/instructions.md"),
+        include_str!("../lessons/quicksort/lesson.md"),
+        None,
+    ),
+];
+
+/// Where the lesson catalog is looked up when nothing else is configured.
+const DEFAULT_LESSONS_DIR: &str = "lessons";
+
+/// Heading of the instructions section that defines the lesson's milestones.
+const MILESTONES_HEADING: &str = "### Core Milestones";
+
+/// Id used in the instructions to show the marker format, not a real milestone.
+const EXAMPLE_MILESTONE_ID: &str = "milestone_id";
+
+lazy_static! {
+    // `1. **Understanding Sorting Inefficiency** (`inefficiency_discovery`)`
+    static ref MILESTONE_ENTRY: Regex =
+        Regex::new(r"^(\d+)\.\s+\*\*(.+?)\*\*\s*(?:\(`([A-Za-z0-9_]+)`\))?\s*$").unwrap();
+    static ref MILESTONE_MARKER: Regex = Regex::new(r"MILESTONE\[([^\]]*)\]").unwrap();
+    // `Requires: `splitting_insight`, `merging_development`` or `Requires: none`
+    static ref MILESTONE_REQUIRES: Regex = Regex::new(r"^\s*Requires:\s*(.*?)\s*$").unwrap();
+    static ref BACKTICKED_ID: Regex = Regex::new(r"`([A-Za-z0-9_]+)`").unwrap();
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct MilestoneDefinition {
+    pub id: String,
+    pub description: String,
+    /// Milestones that must be completed first. Defaults to the previous
+    /// milestone when the instructions don't give a `Requires:` line.
+    pub requires: Vec<String>,
+}
+
+/// The `lesson.json` file at the root of every lesson pack.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LessonManifest {
+    pub id: String,
+    pub title: String,
+    #[serde(default)]
+    pub description: String,
+    /// Path of the tutor instructions, relative to the manifest.
+    #[serde(default = "default_instructions_file")]
+    pub instructions: String,
+    /// Path of the lesson content, relative to the manifest.
+    #[serde(default = "default_lesson_file")]
+    pub lesson: String,
+    /// First tutor message shown when a student opens the lesson.
+    pub opening_message: String,
+    /// How to treat milestones the tutor marks before their prerequisites.
+    #[serde(default)]
+    pub out_of_order_milestones: OutOfOrderPolicy,
+    /// Minutes on one checkpoint before the side panel nudges the student.
+    #[serde(default = "default_checkpoint_cue_minutes")]
+    pub checkpoint_cue_minutes: u32,
+    /// Path of the scripted tutor's replies, relative to the manifest.
+    #[serde(default)]
+    pub transcript: Option<String>,
+}
+
+fn default_instructions_file() -> String {
+    "instructions.md".to_string()
+}
+
+fn default_lesson_file() -> String {
+    "lesson.md".to_string()
+}
+
+fn default_checkpoint_cue_minutes() -> u32 {
+    5
+}
+
+/// A manifest together with the instructions and lesson markdown it points to.
+#[derive(Debug, Clone)]
+pub struct LessonPack {
+    pub manifest: LessonManifest,
+    pub instructions: String,
+    pub lesson: String,
+    /// Milestones in lesson order, parsed from the instructions' Core Milestones section.
+    pub milestones: Vec<MilestoneDefinition>,
+    /// What the scripted provider answers with in this lesson, if anything.
+    pub(crate) transcript: Option<Transcript>,
+}
+
+impl LessonPack {
+    pub fn from_parts(
+        manifest: LessonManifest,
+        instructions: String,
+        lesson: String,
+        transcript: Option<String>,
+    ) -> Result<Self, String> {
+        if !instructions.contains(LESSON_PLACEHOLDER) {
+            return Err(format!(
+                "Lesson pack '{}': instructions are missing the {} placeholder",
+                manifest.id, LESSON_PLACEHOLDER
+            ));
+        }
+
+        let milestones = parse_milestones(&instructions)
+            .map_err(|e| format!("Lesson pack '{}': {}", manifest.id, e))?;
+        let transcript = transcript
+            .map(|text| {
+                let transcript = Transcript::parse(&text)?;
+                let known: Vec<&str> = milestones.iter().map(|m| m.id.as_str()).collect();
+                transcript.check_markers(&known)?;
+                Ok(transcript)
+            })
+            .transpose()
+            .map_err(|e: String| format!("Lesson pack '{}': {}", manifest.id, e))?;
+
+        Ok(Self {
+            manifest,
+            instructions,
+            lesson,
+            milestones,
+            transcript,
+        })
+    }
+
+    pub fn milestone(&self, id: &str) -> Option<&MilestoneDefinition> {
+        self.milestones.iter().find(|m| m.id == id)
+    }
+
+    pub fn system_message(&self) -> String {
+        self.instructions.replace(LESSON_PLACEHOLDER, &self.lesson)
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn load_from_dir(dir: &std::path::Path) -> Result<Self, String> {
+        let manifest: LessonManifest = serde_json::from_str(&read_file(&dir.join("lesson.json"))?)
+            .map_err(|e| format!("Invalid lesson manifest in {}: {}", dir.display(), e))?;
+        let instructions = read_file(&dir.join(&manifest.instructions))?;
+        let lesson = read_file(&dir.join(&manifest.lesson))?;
+        let transcript = manifest
+            .transcript
+            .as_ref()
+            .map(|path| read_file(&dir.join(path)))
+            .transpose()?;
+
+        Self::from_parts(manifest, instructions, lesson, transcript)
+    }
+
+    #[cfg(target_arch = "wasm32")]
+    pub async fn load_from_url(base_url: &str) -> Result<Self, String> {
+        let base_url = base_url.trim_end_matches('/');
+
+        let manifest: LessonManifest =
+            serde_json::from_str(&fetch_text(&format!("{}/lesson.json", base_url)).await?)
+                .map_err(|e| format!("Invalid lesson manifest at {}: {}", base_url, e))?;
+        let instructions = fetch_text(&format!("{}/{}", base_url, manifest.instructions)).await?;
+        let lesson = fetch_text(&format!("{}/{}", base_url, manifest.lesson)).await?;
+        let transcript = match &manifest.transcript {
+            Some(path) => Some(fetch_text(&format!("{}/{}", base_url, path)).await?),
+            None => None,
+        };
+
+        Self::from_parts(manifest, instructions, lesson, transcript)
+    }
+}
+
+/// Reads the numbered list under "Core Milestones", e.g.
+/// ``1. **Understanding Sorting Inefficiency** (`inefficiency_discovery`)``, with an
+/// optional ``Requires: `other_id` `` line after each entry, and checks that every
+/// `MILESTONE[...]` example elsewhere in the instructions names one of them.
+fn parse_milestones(instructions: &str) -> Result<Vec<MilestoneDefinition>, String> {
+    let mut milestones: Vec<MilestoneDefinition> = Vec::new();
+    let mut in_section = false;
+    let mut in_code = false;
+
+    for (line_idx, line) in instructions.lines().enumerate() {
+        let trimmed = line.trim_start();
+        if trimmed.starts_with("```") {
+            in_code = !in_code;
+            continue;
+        }
+        if in_code {
+            continue;
+        }
+
+        if trimmed.starts_with('#') {
+            in_section = trimmed.trim_end() == MILESTONES_HEADING;
+            continue;
+        }
+        if !in_section {
+            continue;
+        }
+
+        if let Some(captures) = MILESTONE_REQUIRES.captures(line) {
+            let Some((milestone, earlier)) = milestones.split_last_mut() else {
+                return Err(format!(
+                    "Requires line {} comes before any milestone",
+                    line_idx + 1
+                ));
+            };
+            let requires: Vec<String> = BACKTICKED_ID
+                .captures_iter(&captures[1])
+                .map(|c| c[1].to_string())
+                .collect();
+            if requires.is_empty() && captures[1].trim() != "none" {
+                return Err(format!(
+                    "Requires line {} for `{}` names no milestones; use `none` for a starting milestone",
+                    line_idx + 1,
+                    milestone.id
+                ));
+            }
+            if let Some(required) = requires
+                .iter()
+                .find(|r| !earlier.iter().any(|m| &m.id == *r))
+            {
+                return Err(format!(
+                    "milestone `{}` requires `{}`, which is not defined before it (line {})",
+                    milestone.id,
+                    required,
+                    line_idx + 1
+                ));
+            }
+            milestone.requires = requires;
+            continue;
+        }
+
+        let Some(captures) = MILESTONE_ENTRY.captures(line) else {
+            continue;
+        };
+        let line_number = line_idx + 1;
+        let Some(id) = captures.get(3) else {
+            return Err(format!(
+                "milestone on line {} has no id; expected `N. **Description** (`milestone_id`)`",
+                line_number
+            ));
+        };
+
+        let number: usize = captures[1].parse().unwrap_or(0);
+        if number != milestones.len() + 1 {
+            return Err(format!(
+                "milestone `{}` on line {} is numbered {}, expected {}",
+                id.as_str(),
+                line_number,
+                number,
+                milestones.len() + 1
+            ));
+        }
+        if milestones.iter().any(|m| m.id == id.as_str()) {
+            return Err(format!(
+                "milestone `{}` is defined twice (line {})",
+                id.as_str(),
+                line_number
+            ));
+        }
+
+        let requires = milestones
+            .last()
+            .map(|m| vec![m.id.clone()])
+            .unwrap_or_default();
+        milestones.push(MilestoneDefinition {
+            id: id.as_str().to_string(),
+            description: captures[2].trim().to_string(),
+            requires,
+        });
+    }
+
+    if milestones.is_empty() {
+        return Err(format!(
+            "instructions have no milestones under \"{}\"",
+            MILESTONES_HEADING
+        ));
+    }
+
+    let known: HashSet<&str> = milestones.iter().map(|m| m.id.as_str()).collect();
+    for (line_idx, line) in instructions.lines().enumerate() {
+        for captures in MILESTONE_MARKER.captures_iter(line) {
+            let id = captures[1].trim();
+            if id != EXAMPLE_MILESTONE_ID && !known.contains(id) {
+                return Err(format!(
+                    "MILESTONE[{}] on line {} does not match any milestone under \"{}\"",
+                    id,
+                    line_idx + 1,
+                    MILESTONES_HEADING
+                ));
+            }
+        }
+    }
+
+    Ok(milestones)
+}
+
+/// Every lesson pack the app knows about, plus the one the student has open.
+#[derive(Debug, Default)]
+pub struct LessonCatalog {
+    pub packs: Vec<LessonPack>,
+    pub active: Option<String>,
+}
+
+impl LessonCatalog {
+    pub fn builtin() -> Self {
+        let packs = BUILTIN_PACKS
+            .iter()
+            .map(|(manifest, instructions, lesson, transcript)| {
+                let manifest =
+                    serde_json::from_str(manifest).expect("Built-in lesson manifest is invalid");
+                LessonPack::from_parts(
+                    manifest,
+                    instructions.to_string(),
+                    lesson.to_string(),
+                    transcript.map(str::to_string),
+                )
+                .expect("Built-in lesson pack is invalid")
+            })
+            .collect();
+
+        Self {
+            packs,
+            active: None,
+        }
+    }
+
+    pub fn get(&self, id: &str) -> Option<&LessonPack> {
+        self.packs.iter().find(|pack| pack.manifest.id == id)
+    }
+
+    pub fn active_pack(&self) -> Option<&LessonPack> {
+        self.active.as_deref().and_then(|id| self.get(id))
+    }
+
+    /// `catalog.json` lists the pack directories, in the order they are shown.
+    fn parse_index(index: &str) -> Result<Vec<String>, String> {
+        serde_json::from_str(index).map_err(|e| format!("Invalid lesson catalog: {}", e))
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn load_from_dir(dir: &std::path::Path) -> Result<Self, String> {
+        let mut packs = Vec::new();
+        for name in Self::parse_index(&read_file(&dir.join("catalog.json"))?)? {
+            match LessonPack::load_from_dir(&dir.join(&name)) {
+                Ok(pack) => packs.push(pack),
+                Err(e) => log::error!("Skipping lesson pack '{}': {}", name, e),
+            }
+        }
+
+        Ok(Self {
+            packs,
+            active: None,
+        })
+    }
+
+    #[cfg(target_arch = "wasm32")]
+    pub async fn load_from_url(base_url: &str) -> Result<Self, String> {
+        let base_url = base_url.trim_end_matches('/');
+
+        let mut packs = Vec::new();
+        let index = fetch_text(&format!("{}/catalog.json", base_url)).await?;
+        for name in Self::parse_index(&index)? {
+            match LessonPack::load_from_url(&format!("{}/{}", base_url, name)).await {
+                Ok(pack) => packs.push(pack),
+                Err(e) => log::error!("Skipping lesson pack '{}': {}", name, e),
+            }
+        }
+
+        Ok(Self {
+            packs,
+            active: None,
+        })
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn read_file(path: &std::path::Path) -> Result<String, String> {
+    std::fs::read_to_string(path).map_err(|e| format!("Failed to read {}: {}", path.display(), e))
+}
+
+#[cfg(target_arch = "wasm32")]
+pub(crate) async fn fetch_text(url: &str) -> Result<String, String> {
+    use reqwasm::http::Request;
+
+    let response = Request::get(url)
+        .send()
+        .await
+        .map_err(|e| format!("Network error: {}", e))?;
+
+    if response.ok() {
+        response.text().await.map_err(|e| e.to_string())
+    } else {
+        Err(format!("Failed to fetch {}: {}", url, response.status()))
+    }
+}
+
+lazy_static! {
+    pub static ref LESSON_CATALOG: Mutex<LessonCatalog> = Mutex::new(LessonCatalog::builtin());
+}
+
+pub fn set_active_lesson(id: Option<&str>) {
+    LESSON_CATALOG.lock().unwrap().active = id.map(str::to_string);
+}
+
+fn install_catalog(mut catalog: LessonCatalog) {
+    if catalog.packs.is_empty() {
+        log::warn!("Lesson catalog is empty; using the built-in lessons");
+        return;
+    }
+
+    let mut current = LESSON_CATALOG.lock().unwrap();
+    catalog.active = current.active.take();
+    log::info!("Loaded {} lesson packs", catalog.packs.len());
+    *current = catalog;
+}
+
+/// Loads the catalog from `LESSONS_DIR` (or `lessons`), keeping the built-in
+/// lessons if the directory can't be read.
+#[cfg(not(target_arch = "wasm32"))]
+pub fn load_lesson_catalog(_ctx: &egui::Context) {
+    dotenvy::dotenv().ok();
+    let dir = std::env::var("LESSONS_DIR").unwrap_or_else(|_| DEFAULT_LESSONS_DIR.to_string());
+
+    match LessonCatalog::load_from_dir(std::path::Path::new(&dir)) {
+        Ok(catalog) => install_catalog(catalog),
+        Err(e) => log::warn!("{}; using the built-in lessons", e),
+    }
+}
+
+/// Fetches the catalog named by the `lessons` query parameter (or the bundled
+/// `lessons` asset), keeping the built-in lessons until it arrives.
+#[cfg(target_arch = "wasm32")]
+pub fn load_lesson_catalog(ctx: &egui::Context) {
+    let base_url = web_sys::window()
+        .and_then(|window| window.location().search().ok())
+        .and_then(|search| query_param(&search, "lessons"))
+        .unwrap_or_else(|| DEFAULT_LESSONS_DIR.to_string());
+
+    let ctx = ctx.clone();
+    wasm_bindgen_futures::spawn_local(async move {
+        match LessonCatalog::load_from_url(&base_url).await {
+            Ok(catalog) => {
+                install_catalog(catalog);
+                ctx.request_repaint();
+            }
+            Err(e) => log::warn!("{}; using the built-in lessons", e),
+        }
+    });
+}
+
+#[cfg(target_arch = "wasm32")]
+pub(crate) fn query_param(search: &str, key: &str) -> Option<String> {
+    search
+        .trim_start_matches('?')
+        .split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .find(|(k, _)| *k == key)
+        .map(|(_, v)| v.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Instructions with two milestones, the second requiring `requires`, and
+    /// `extra` appended after the milestones section.
+    fn instructions(requires: &str, extra: &str) -> String {
+        format!(
+            "## Lesson\n\n{{{{LESSON_CONTENT}}}}\n\n{}\n\n\
+             1. **Spotting the problem** (`problem`)\n\
+             Requires: none\n\n\
+             2. **Finding a fix** (`fix`)\n\
+             Requires: {}\n\n\
+             ## Markers\n\nWrite MILESTONE[milestone_id] when one is reached, e.g. MILESTONE[problem].\n{}",
+            MILESTONES_HEADING, requires, extra
+        )
+    }
+
+    fn manifest() -> LessonManifest {
+        serde_json::from_str(r#"{ "id": "test", "title": "Test", "opening_message": "Hi" }"#)
+            .unwrap()
+    }
+
+    #[test]
+    fn milestones_are_read_with_their_prerequisites() {
+        let milestones = parse_milestones(&instructions("`problem`", "")).unwrap();
+        assert_eq!(
+            milestones,
+            vec![
+                MilestoneDefinition {
+                    id: "problem".to_string(),
+                    description: "Spotting the problem".to_string(),
+                    requires: Vec::new(),
+                },
+                MilestoneDefinition {
+                    id: "fix".to_string(),
+                    description: "Finding a fix".to_string(),
+                    requires: vec!["problem".to_string()],
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn duplicate_ids_are_rejected() {
+        let text = instructions("`problem`", "").replace("(`fix`)", "(`problem`)");
+        let error = parse_milestones(&text).unwrap_err();
+        assert!(error.contains("`problem` is defined twice"), "{}", error);
+    }
+
+    #[test]
+    fn requires_must_name_an_earlier_milestone() {
+        let error = parse_milestones(&instructions("`unknown`", "")).unwrap_err();
+        assert!(error.contains("requires `unknown`"), "{}", error);
+
+        let error = parse_milestones(&instructions("the first one", "")).unwrap_err();
+        assert!(error.contains("names no milestones"), "{}", error);
+    }
+
+    #[test]
+    fn markers_must_name_a_core_milestone() {
+        let error =
+            parse_milestones(&instructions("`problem`", "Or MILESTONE[typo].")).unwrap_err();
+        assert!(error.starts_with("MILESTONE[typo] on line"), "{}", error);
+    }
+
+    #[test]
+    fn packs_report_which_lesson_is_invalid() {
+        let error = LessonPack::from_parts(
+            manifest(),
+            instructions("`unknown`", ""),
+            String::new(),
+            None,
+        )
+        .unwrap_err();
+        assert!(error.starts_with("Lesson pack 'test': "), "{}", error);
+
+        let error = LessonPack::from_parts(
+            manifest(),
+            instructions("`problem`", "").replace(LESSON_PLACEHOLDER, ""),
+            String::new(),
+            None,
+        )
+        .unwrap_err();
+        assert!(
+            error.contains("missing the {{LESSON_CONTENT}}"),
+            "{}",
+            error
+        );
+
+        let pack = LessonPack::from_parts(
+            manifest(),
+            instructions("`problem`", ""),
+            "Sorting".to_string(),
+            None,
+        )
+        .unwrap();
+        assert!(pack.system_message().contains("## Lesson\n\nSorting"));
+    }
+
+    #[test]
+    fn transcripts_must_mark_the_lesson_milestones() {
+        let transcript = |reply: &str| {
+            Some(format!(
+                r#"{{ "replies": [{{ "pattern": "fix", "reply": "{}" }}], "fallback": "Go on." }}"#,
+                reply
+            ))
+        };
+        let pack = |reply: &str| {
+            LessonPack::from_parts(
+                manifest(),
+                instructions("`problem`", ""),
+                String::new(),
+                transcript(reply),
+            )
+        };
+
+        assert!(pack("Yes! MILESTONE[fix]").unwrap().transcript.is_some());
+        let error = pack("Yes! MILESTONE[splitting_insight]").unwrap_err();
+        assert!(
+            error.contains("unknown milestone 'splitting_insight'"),
+            "{}",
+            error
+        );
+    }
+
+    #[test]
+    fn builtin_catalog_parses() {
+        let catalog = LessonCatalog::builtin();
+        assert_eq!(catalog.packs.len(), BUILTIN_PACKS.len());
+        let mergesort = catalog.get("mergesort").unwrap();
+        assert_eq!(mergesort.milestones.len(), 5);
+        assert!(mergesort.milestone("splitting_insight").is_some());
+        assert!(mergesort.transcript.is_some());
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    #[test]
+    fn lessons_directory_matches_the_builtin_catalog() {
+        let catalog = LessonCatalog::load_from_dir(std::path::Path::new("lessons")).unwrap();
+        let ids: Vec<&str> = catalog
+            .packs
+            .iter()
+            .map(|p| p.manifest.id.as_str())
+            .collect();
+        let builtin = LessonCatalog::builtin();
+        let builtin_ids: Vec<&str> = builtin
+            .packs
+            .iter()
+            .map(|p| p.manifest.id.as_str())
+            .collect();
+        assert_eq!(ids, builtin_ids);
+    }
+}
@@ -1,12 +1,37 @@
 #![warn(clippy::all)]
 
 mod app;
+mod context;
+mod error;
+mod lesson;
+mod markers;
+mod metadata;
+mod milestones;
+mod providers;
+mod retry;
+mod scripted;
+mod settings;
+mod streaming;
+#[cfg(all(not(target_arch = "wasm32"), any(test, feature = "stub")))]
+pub mod stub;
+mod transport;
 pub use app::LearningApp;
 
 use lazy_static::lazy_static;
 use serde::{Deserialize, Serialize};
 use std::sync::Mutex;
 
+use crate::context::ContextTrim;
+use crate::error::ApiError;
+use crate::providers::{ChatRequest, ChatTurn, Provider};
+use crate::retry::{parse_retry_after, RetryPolicy, RETRY_POLICY};
+use crate::scripted::ScriptedTransport;
+use crate::settings::Settings;
+pub(crate) use crate::transport::RequestHandle;
+use crate::transport::{
+    random_fraction, sleep, spawn_request, HttpRequest, MaybeSend, MaybeSync, Transport,
+};
+
 lazy_static! {
     static ref SUPABASE_URL: String = {
         dotenvy::dotenv().ok();
@@ -35,11 +60,95 @@ struct SupabaseVerifyRequest {
     auth_type: String,
 }
 
+#[derive(Debug, Serialize)]
+struct SupabaseRefreshRequest {
+    refresh_token: String,
+}
+
 #[derive(Debug, Default, Serialize, Deserialize)]
 pub struct AuthState {
     pub signed_in: bool,
     pub email: Option<String>,
     pub access_token: Option<String>,
+    /// Exchanged for a new session before the access token expires.
+    #[serde(default)]
+    pub refresh_token: Option<String>,
+    /// When the access token expires.
+    #[serde(default)]
+    pub expires_at: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+/// How long before its `exp` claim an access token is treated as expired, so
+/// it doesn't run out while a request is under way.
+const TOKEN_EXPIRY_MARGIN_SECS: i64 = 30;
+/// How long before the access token expires the session is refreshed.
+const REFRESH_MARGIN_SECS: i64 = 300;
+
+impl AuthState {
+    /// Stores a session Supabase handed out, on sign-in or refresh.
+    fn start_session(
+        &mut self,
+        response: SupabaseAuthResponse,
+        now: chrono::DateTime<chrono::Utc>,
+    ) {
+        self.signed_in = true;
+        self.expires_at = response
+            .expires_at
+            .and_then(|at| chrono::DateTime::from_timestamp(at, 0))
+            .or_else(|| {
+                response
+                    .expires_in
+                    .map(|secs| now + chrono::Duration::seconds(secs))
+            })
+            .or_else(|| token_expiry(&response.access_token));
+        self.access_token = Some(response.access_token);
+        self.refresh_token = response.refresh_token;
+    }
+
+    /// Forgets the session, keeping the email to sign in with again.
+    pub(crate) fn sign_out(&mut self) {
+        self.signed_in = false;
+        self.access_token = None;
+        self.refresh_token = None;
+        self.expires_at = None;
+    }
+
+    /// When the session should be refreshed, or `None` if it can't be.
+    pub(crate) fn refresh_due(&self) -> Option<chrono::DateTime<chrono::Utc>> {
+        if !self.signed_in || self.refresh_token.is_none() {
+            return None;
+        }
+        let expires_at = self
+            .expires_at
+            .or_else(|| self.access_token.as_deref().and_then(token_expiry))?;
+        Some(expires_at - chrono::Duration::seconds(REFRESH_MARGIN_SECS))
+    }
+
+    /// Whether the access token's `exp` claim has passed at `now`. Tokens
+    /// without a readable claim are left for the server to judge.
+    pub(crate) fn token_expired(&self, now: chrono::DateTime<chrono::Utc>) -> bool {
+        self.access_token
+            .as_deref()
+            .and_then(token_expiry)
+            .is_some_and(|exp| exp - chrono::Duration::seconds(TOKEN_EXPIRY_MARGIN_SECS) <= now)
+    }
+}
+
+/// Reads the `exp` claim of a JWT, without checking its signature.
+fn token_expiry(token: &str) -> Option<chrono::DateTime<chrono::Utc>> {
+    use base64::Engine;
+
+    #[derive(Deserialize)]
+    struct Claims {
+        exp: i64,
+    }
+
+    let payload = token.split('.').nth(1)?;
+    let payload = base64::engine::general_purpose::URL_SAFE_NO_PAD
+        .decode(payload.trim_end_matches('='))
+        .ok()?;
+    let claims: Claims = serde_json::from_slice(&payload).ok()?;
+    chrono::DateTime::from_timestamp(claims.exp, 0)
 }
 
 lazy_static! {
@@ -60,70 +169,179 @@ pub fn save_auth_state(storage: &mut dyn eframe::Storage) {
     eframe::set_value(storage, "auth_state", &*auth_state);
 }
 
-#[derive(Default)]
-struct PendingState {
-    response: Option<String>,
-    error: Option<String>,
+/// A tool the tutor may call, in the shape shared by all providers.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct ToolDefinition {
+    pub(crate) name: String,
+    pub(crate) description: String,
+    pub(crate) input_schema: serde_json::Value,
 }
 
-lazy_static! {
-    static ref PENDING_STATE: Mutex<PendingState> = Mutex::new(PendingState::default());
+/// Arguments of a `mark_milestone` tool call.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub(crate) struct MilestoneCall {
+    pub(crate) milestone_id: String,
+    pub(crate) justification: String,
 }
 
-// Define a structure for the request payload
-#[derive(Serialize, Deserialize)]
-struct AnthropicRequest {
-    messages: Vec<AnthropicMessage>,
-    max_tokens: Option<u32>,
-    temperature: Option<f32>,
-    system: String,
-    email: String,
+/// The tutor's reply: the text shown to the student and the milestones it
+/// marked through tool calls.
+#[derive(Debug, Default)]
+pub(crate) struct TutorReply {
+    pub(crate) text: String,
+    pub(crate) milestone_calls: Vec<MilestoneCall>,
+    /// Token counts, when the provider reports them.
+    pub(crate) usage: Option<Usage>,
 }
 
-#[derive(Serialize, Deserialize)]
-struct AnthropicMessage {
-    role: String,
-    content: String,
-    cacheable: bool,
+/// Tokens a reply used, in Anthropic's terms: `input_tokens` counts only the
+/// part of the prompt that wasn't read from the cache.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(default)]
+pub(crate) struct Usage {
+    pub(crate) input_tokens: u64,
+    pub(crate) output_tokens: u64,
+    pub(crate) cache_creation_input_tokens: u64,
+    pub(crate) cache_read_input_tokens: u64,
 }
 
-// Define a structure for the response
-#[derive(Deserialize)]
-struct AnthropicResponse {
-    content: Vec<ContentItem>,
+impl std::ops::AddAssign for Usage {
+    fn add_assign(&mut self, other: Self) {
+        self.input_tokens += other.input_tokens;
+        self.output_tokens += other.output_tokens;
+        self.cache_creation_input_tokens += other.cache_creation_input_tokens;
+        self.cache_read_input_tokens += other.cache_read_input_tokens;
+    }
 }
 
-#[derive(Deserialize)]
-struct ContentItem {
-    #[serde(default)]
-    text: String,
-    #[serde(rename = "type")]
-    content_type: String,
+impl MilestoneCall {
+    /// Reads a tool call's arguments. Calls to other tools give `None`, as do
+    /// malformed arguments, which are logged.
+    pub(crate) fn from_tool_call(name: &str, arguments: serde_json::Value) -> Option<Self> {
+        if name != MARK_MILESTONE_TOOL {
+            return None;
+        }
+        serde_json::from_value(arguments)
+            .map_err(|e| log::warn!("Ignoring malformed {} call: {}", MARK_MILESTONE_TOOL, e))
+            .ok()
+    }
+
+    /// Like `from_tool_call`, for arguments still encoded as JSON text.
+    pub(crate) fn from_json_arguments(name: &str, arguments: &str) -> Option<Self> {
+        if name != MARK_MILESTONE_TOOL {
+            return None;
+        }
+        serde_json::from_str(arguments)
+            .map_err(|e| log::warn!("Ignoring malformed {} call: {}", MARK_MILESTONE_TOOL, e))
+            .ok()
+    }
 }
 
 // Add ChatMessage to the imports at the top
 use crate::app::ChatMessage;
 
-// Add these at the top, after the existing imports
-const INSTRUCTIONS: &str = include_str!("mergesort-instructions.md");
-const LESSON: &str = include_str!("mergesort-lesson.md");
-
 // Add these constants near the top of the file, after the other constants
-const MAX_TOKENS: u32 = 2048;
-const TEMPERATURE: f32 = 0.0;
+const MARK_MILESTONE_TOOL: &str = "mark_milestone";
+const MARK_MILESTONE_GUIDANCE: &str = "When the student reaches a milestone, call the \
+    `mark_milestone` tool with the milestone id and a one-sentence justification quoting \
+    what the student said or did, alongside your reply. Always write your reply to the \
+    student before calling the tool, and do not also write a MILESTONE marker.";
 
-// Add this new function
+// Build the system prompt from whichever lesson pack is active
 fn get_system_message() -> String {
-    INSTRUCTIONS.replace("{{LESSON_CONTENT}}", LESSON)
+    lesson::LESSON_CATALOG
+        .lock()
+        .unwrap()
+        .active_pack()
+        .map(|pack| {
+            format!(
+                "{}\n\n{}\n\n{}",
+                pack.system_message(),
+                MARK_MILESTONE_GUIDANCE,
+                metadata::METADATA_GUIDANCE
+            )
+        })
+        .unwrap_or_default()
+}
+
+// Lets the tutor mark the active lesson's milestones with a structured call
+fn get_tools() -> Vec<ToolDefinition> {
+    let catalog = lesson::LESSON_CATALOG.lock().unwrap();
+    let Some(pack) = catalog.active_pack() else {
+        return Vec::new();
+    };
+    let milestone_ids: Vec<&str> = pack.milestones.iter().map(|m| m.id.as_str()).collect();
+
+    vec![ToolDefinition {
+        name: MARK_MILESTONE_TOOL.to_string(),
+        description: "Record that the student has reached a lesson milestone.".to_string(),
+        input_schema: serde_json::json!({
+            "type": "object",
+            "properties": {
+                "milestone_id": {
+                    "type": "string",
+                    "enum": milestone_ids,
+                    "description": "Id of the milestone the student reached.",
+                },
+                "justification": {
+                    "type": "string",
+                    "description": "What the student said or did that shows they reached it.",
+                },
+            },
+            "required": ["milestone_id", "justification"],
+        }),
+    }]
+}
+
+// Servers that can't stream answer with a plain JSON body instead
+fn is_streamed(content_type: Option<&str>) -> bool {
+    content_type.is_some_and(|value| {
+        value.starts_with("text/event-stream") || value.starts_with("application/x-ndjson")
+    })
 }
 
 #[derive(Debug, Deserialize)]
 struct SupabaseAuthResponse {
     access_token: String,
+    refresh_token: Option<String>,
+    /// Seconds the access token lasts.
+    expires_in: Option<i64>,
+    /// When the access token expires, as a Unix timestamp.
+    expires_at: Option<i64>,
+}
+
+/// Applies the outcome of a refresh. A refresh token the server turns down
+/// won't work later either, so the student is signed out; network and server
+/// errors leave the session to be refreshed again.
+fn finish_refresh(
+    used: &str,
+    result: Result<SupabaseAuthResponse, ApiError>,
+) -> Result<(), ApiError> {
+    let mut auth_state = AUTH_STATE.lock().unwrap();
+    // Another refresh finished first, and its session is the one to keep
+    if auth_state.refresh_token.as_deref() != Some(used) {
+        return if auth_state.signed_in {
+            Ok(())
+        } else {
+            Err(ApiError::NotAuthenticated)
+        };
+    }
+    match result {
+        Ok(response) if !response.access_token.is_empty() => {
+            auth_state.start_session(response, chrono::Utc::now());
+            Ok(())
+        }
+        Ok(_) => Err(ApiError::Decode("no access token in response".to_string())),
+        Err(e) if e.is_transient() => Err(e),
+        Err(e) => {
+            auth_state.sign_out();
+            Err(e)
+        }
+    }
 }
 
 #[cfg(target_arch = "wasm32")]
-pub async fn request_otp_web(email: &str) -> Result<(), String> {
+pub(crate) async fn request_otp_web(email: &str) -> Result<(), ApiError> {
     use reqwasm::http::Request;
 
     let request = SupabaseOTPRequest {
@@ -134,21 +352,25 @@ pub async fn request_otp_web(email: &str) -> Result<(), String> {
     let response = Request::post(&format!("{}/auth/v1/otp", SUPABASE_URL.as_str()))
         .header("apikey", SUPABASE_ANON_KEY.as_str())
         .header("Content-Type", "application/json")
-        .body(serde_json::to_string(&request).map_err(|e| e.to_string())?)
+        .body(serde_json::to_string(&request).map_err(|e| ApiError::Config(e.to_string()))?)
         .send()
         .await
-        .map_err(|e| format!("Network error: {}", e))?;
+        .map_err(|e| ApiError::Network(e.to_string()))?;
 
     if response.ok() {
         Ok(())
     } else {
-        let error_text = response.text().await.map_err(|e| e.to_string())?;
-        Err(error_text)
+        let status = response.status();
+        let error_text = response
+            .text()
+            .await
+            .map_err(|e| ApiError::Network(e.to_string()))?;
+        Err(ApiError::from_auth_response(status, &error_text))
     }
 }
 
 #[cfg(target_arch = "wasm32")]
-pub async fn verify_otp_web(email: &str, token: &str) -> Result<(), String> {
+pub(crate) async fn verify_otp_web(email: &str, token: &str) -> Result<(), ApiError> {
     use reqwasm::http::Request;
 
     let request = SupabaseVerifyRequest {
@@ -160,35 +382,80 @@ pub async fn verify_otp_web(email: &str, token: &str) -> Result<(), String> {
     let response = Request::post(&format!("{}/auth/v1/verify", SUPABASE_URL.as_str()))
         .header("apikey", SUPABASE_ANON_KEY.as_str())
         .header("Content-Type", "application/json")
-        .body(serde_json::to_string(&request).map_err(|e| e.to_string())?)
+        .body(serde_json::to_string(&request).map_err(|e| ApiError::Config(e.to_string()))?)
         .send()
         .await
-        .map_err(|e| format!("Network error: {}", e))?;
+        .map_err(|e| ApiError::Network(e.to_string()))?;
 
     if response.ok() {
-        let auth_response: SupabaseAuthResponse =
-            response.json().await.map_err(|e| e.to_string())?;
+        let auth_response: SupabaseAuthResponse = response
+            .json()
+            .await
+            .map_err(|e| ApiError::Decode(e.to_string()))?;
 
         // Verify we got a valid access token
         if auth_response.access_token.is_empty() {
-            return Err("Invalid authentication response".to_string());
+            return Err(ApiError::Decode("no access token in response".to_string()));
         }
 
         // Update auth state
         let mut auth_state = AUTH_STATE.lock().unwrap();
-        auth_state.signed_in = true;
         auth_state.email = Some(email.to_string());
-        auth_state.access_token = Some(auth_response.access_token);
+        auth_state.start_session(auth_response, chrono::Utc::now());
 
         Ok(())
     } else {
-        let error_text = response.text().await.map_err(|e| e.to_string())?;
-        Err(format!("Failed to verify OTP: {}", error_text))
+        let status = response.status();
+        let error_text = response
+            .text()
+            .await
+            .map_err(|e| ApiError::Network(e.to_string()))?;
+        Err(ApiError::from_auth_response(status, &error_text))
+    }
+}
+
+/// Exchanges the refresh token for a new session.
+#[cfg(target_arch = "wasm32")]
+pub(crate) async fn refresh_session_web() -> Result<(), ApiError> {
+    use reqwasm::http::Request;
+
+    let Some(refresh_token) = AUTH_STATE.lock().unwrap().refresh_token.clone() else {
+        return Err(ApiError::NotAuthenticated);
+    };
+    let request = SupabaseRefreshRequest { refresh_token };
+
+    let result = async {
+        let response = Request::post(&format!(
+            "{}/auth/v1/token?grant_type=refresh_token",
+            SUPABASE_URL.as_str()
+        ))
+        .header("apikey", SUPABASE_ANON_KEY.as_str())
+        .header("Content-Type", "application/json")
+        .body(serde_json::to_string(&request).map_err(|e| ApiError::Config(e.to_string()))?)
+        .send()
+        .await
+        .map_err(|e| ApiError::Network(e.to_string()))?;
+
+        if response.ok() {
+            response
+                .json()
+                .await
+                .map_err(|e| ApiError::Decode(e.to_string()))
+        } else {
+            let status = response.status();
+            let error_text = response
+                .text()
+                .await
+                .map_err(|e| ApiError::Network(e.to_string()))?;
+            Err(ApiError::from_auth_response(status, &error_text))
+        }
     }
+    .await;
+    finish_refresh(&request.refresh_token, result)
 }
 
 #[cfg(not(target_arch = "wasm32"))]
-pub async fn request_otp_native(email: &str) -> Result<(), String> {
+pub(crate) async fn request_otp_native(email: &str) -> Result<(), ApiError> {
     let client = reqwest::Client::new();
     let request = SupabaseOTPRequest {
         email: email.to_string(),
@@ -201,18 +468,22 @@ pub async fn request_otp_native(email: &str) -> Result<(), String> {
         .json(&request)
         .send()
         .await
-        .map_err(|e| format!("Network error: {}", e))?;
+        .map_err(|e| ApiError::Network(e.to_string()))?;
 
     if response.status().is_success() {
         Ok(())
     } else {
-        let error_text = response.text().await.map_err(|e| e.to_string())?;
-        Err(error_text)
+        let status = response.status().as_u16();
+        let error_text = response
+            .text()
+            .await
+            .map_err(|e| ApiError::Network(e.to_string()))?;
+        Err(ApiError::from_auth_response(status, &error_text))
     }
 }
 
 #[cfg(not(target_arch = "wasm32"))]
-pub async fn verify_otp_native(email: &str, token: &str) -> Result<(), String> {
+pub(crate) async fn verify_otp_native(email: &str, token: &str) -> Result<(), ApiError> {
     let client = reqwest::Client::new();
     let request = SupabaseVerifyRequest {
         email: email.to_string(),
@@ -227,223 +498,842 @@ pub async fn verify_otp_native(email: &str, token: &str) -> Result<(), String> {
         .json(&request)
         .send()
         .await
-        .map_err(|e| e.to_string())?;
+        .map_err(|e| ApiError::Network(e.to_string()))?;
 
     if response.status().is_success() {
-        let auth_response: SupabaseAuthResponse =
-            response.json().await.map_err(|e| e.to_string())?;
+        let auth_response: SupabaseAuthResponse = response
+            .json()
+            .await
+            .map_err(|e| ApiError::Decode(e.to_string()))?;
 
         // Verify we got a valid access token
         if auth_response.access_token.is_empty() {
-            return Err("Invalid authentication response".to_string());
+            return Err(ApiError::Decode("no access token in response".to_string()));
         }
 
         // Update auth state
         let mut auth_state = AUTH_STATE.lock().unwrap();
-        auth_state.signed_in = true;
         auth_state.email = Some(email.to_string());
-        auth_state.access_token = Some(auth_response.access_token);
+        auth_state.start_session(auth_response, chrono::Utc::now());
 
         Ok(())
     } else {
-        let error_text = response.text().await.map_err(|e| e.to_string())?;
-        Err(format!("Failed to verify OTP: {}", error_text))
+        let status = response.status().as_u16();
+        let error_text = response
+            .text()
+            .await
+            .map_err(|e| ApiError::Network(e.to_string()))?;
+        Err(ApiError::from_auth_response(status, &error_text))
     }
 }
 
-#[cfg(target_arch = "wasm32")]
-mod web {
-    use super::*;
-    use reqwasm::http::Request;
-    use wasm_bindgen_futures::spawn_local;
+/// Exchanges the refresh token for a new session.
+#[cfg(not(target_arch = "wasm32"))]
+pub(crate) async fn refresh_session_native() -> Result<(), ApiError> {
+    let Some(refresh_token) = AUTH_STATE.lock().unwrap().refresh_token.clone() else {
+        return Err(ApiError::NotAuthenticated);
+    };
+    let request = SupabaseRefreshRequest { refresh_token };
 
-    pub(crate) fn make_anthropic_request(
-        user_message: String,
-        chat_history: Vec<ChatMessage>,
-        callback: impl Fn(Result<String, String>) + 'static,
-    ) {
-        let url = "https://dhruvdh-anthropic-s-50.deno.dev/";
+    let result = async {
+        let response = reqwest::Client::new()
+            .post(format!(
+                "{}/auth/v1/token?grant_type=refresh_token",
+                SUPABASE_URL.as_str()
+            ))
+            .header("apikey", SUPABASE_ANON_KEY.as_str())
+            .header("Content-Type", "application/json")
+            .json(&request)
+            .send()
+            .await
+            .map_err(|e| ApiError::Network(e.to_string()))?;
 
-        let auth_state = AUTH_STATE.lock().unwrap();
-        let email = match &auth_state.email {
-            Some(email) => email.clone(),
-            None => {
-                callback(Err("Not authenticated".to_string()));
-                return;
-            }
-        };
-        drop(auth_state);
+        if response.status().is_success() {
+            response
+                .json()
+                .await
+                .map_err(|e| ApiError::Decode(e.to_string()))
+        } else {
+            let status = response.status().as_u16();
+            let error_text = response
+                .text()
+                .await
+                .map_err(|e| ApiError::Network(e.to_string()))?;
+            Err(ApiError::from_auth_response(status, &error_text))
+        }
+    }
+    .await;
+    finish_refresh(&request.refresh_token, result)
+}
 
-        let mut messages = Vec::new();
+/// Sent for a tutor reply that was only a tool call, since providers turn
+/// away empty turns.
+const EMPTY_REPLY: &str = "(The tutor marked progress without replying.)";
 
-        // Add all previous messages
-        for msg in chat_history {
-            messages.push(AnthropicMessage {
-                role: if msg.from_user { "user" } else { "assistant" }.to_string(),
-                content: msg.content,
-                cacheable: msg.cacheable,
-            });
+/// The text sent for a message: student messages carry their metadata
+/// block ahead of what the student wrote.
+fn turn_content(message: ChatMessage) -> String {
+    match &message.metadata {
+        Some(metadata) if message.from_user => {
+            format!("{}\n\n{}", metadata.render(), message.content)
         }
+        _ if !message.from_user && message.content.trim().is_empty() => EMPTY_REPLY.to_string(),
+        _ => message.content,
+    }
+}
 
-        // Add the new message
-        messages.push(AnthropicMessage {
-            role: "user".to_string(),
-            content: user_message,
+/// Builds the request for `user_message`, trimming older history if it would
+/// go over the context budget.
+fn build_request(
+    settings: &Settings,
+    user_message: ChatMessage,
+    chat_history: Vec<ChatMessage>,
+    email: String,
+    access_token: Option<String>,
+) -> (ChatRequest, Option<ContextTrim>) {
+    // A milestone's evidence is the tutor message that marked it and the
+    // student message it answered
+    let pinned: Vec<bool> = (0..chat_history.len())
+        .map(|idx| {
+            let earned = |i: usize| {
+                chat_history
+                    .get(i)
+                    .is_some_and(|msg| !msg.found_milestones.is_empty())
+            };
+            earned(idx) || (chat_history[idx].from_user && earned(idx + 1))
+        })
+        .collect();
+
+    let mut messages: Vec<ChatTurn> = chat_history
+        .into_iter()
+        .zip(pinned)
+        .map(|(msg, pinned)| ChatTurn {
+            from_user: msg.from_user,
+            content: turn_content(msg),
             cacheable: false,
-        });
+            pinned,
+        })
+        .collect();
+
+    // Add the new message
+    messages.push(ChatTurn {
+        from_user: true,
+        content: turn_content(user_message),
+        cacheable: false,
+        pinned: false,
+    });
+
+    let mut chat = ChatRequest {
+        system: get_system_message(),
+        messages,
+        tools: get_tools(),
+        max_tokens: settings.max_tokens,
+        temperature: settings.temperature,
+        cache_system: false,
+        email,
+        access_token,
+    };
+    let trim = context::fit_to_budget(
+        &mut chat,
+        settings.context_budget as usize,
+        settings.context_strategy,
+    );
+    context::mark_cache_breakpoints(&mut chat);
+    (chat, trim)
+}
 
-        let request_payload = AnthropicRequest {
-            messages,
-            max_tokens: Some(MAX_TOKENS),
-            temperature: Some(TEMPERATURE),
-            system: get_system_message(),
-            email,
+/// Sends a request and reads the tutor's reply, passing text to `on_delta` as
+/// it arrives when the provider streams. Rate limits, server errors and
+/// network failures are retried under `policy`, with `on_retry` told the retry
+/// number and how many are allowed.
+async fn request_reply(
+    transport: &impl Transport,
+    provider: &Provider,
+    policy: &RetryPolicy,
+    request: HttpRequest,
+    on_delta: impl Fn(String),
+    on_retry: impl Fn(u32, u32),
+) -> Result<TutorReply, ApiError> {
+    let mut retry = 0;
+    loop {
+        let error = match attempt_reply(transport, provider, request.clone(), &on_delta).await {
+            Ok(reply) => return Ok(reply),
+            Err(error) => error,
         };
 
-        let payload = match serde_json::to_string(&request_payload) {
-            Ok(p) => p,
-            Err(e) => {
-                callback(Err(format!("Failed to serialize request: {}", e)));
-                return;
+        retry += 1;
+        let delay = (error.is_transient() && !transport.cancelled())
+            .then(|| policy.delay(retry, error.retry_after(), random_fraction()))
+            .flatten();
+        let Some(delay) = delay else {
+            return Err(error);
+        };
+        log::warn!(
+            "{}; retrying in {:.1}s ({}/{})",
+            error,
+            delay.as_secs_f32(),
+            retry,
+            policy.max_retries
+        );
+        on_retry(retry, policy.max_retries);
+        sleep(delay).await;
+    }
+}
+
+async fn attempt_reply(
+    transport: &impl Transport,
+    provider: &Provider,
+    request: HttpRequest,
+    on_delta: &impl Fn(String),
+) -> Result<TutorReply, ApiError> {
+    let mut response = transport.post(request).await.map_err(ApiError::Network)?;
+
+    if !response.is_success() {
+        // The course proxy turns away students whose session has ended
+        if response.status == 401 && provider.uses_sign_in() {
+            return Err(ApiError::SessionExpired);
+        }
+        return Err(ApiError::Status {
+            status: response.status,
+            retry_after: response.retry_after.as_deref().and_then(parse_retry_after),
+        });
+    }
+
+    if is_streamed(response.content_type.as_deref()) {
+        let mut stream = provider.reply_stream();
+        // Once the student has seen part of a reply, starting over would
+        // repeat it
+        let mut streamed = false;
+        let cut_off = |e, streamed| {
+            if streamed {
+                ApiError::Interrupted(e)
+            } else {
+                ApiError::Network(e)
             }
         };
+        while let Some(chunk) = response
+            .body
+            .next_chunk()
+            .await
+            .map_err(|e| cut_off(e, streamed))?
+        {
+            let text = stream.push(&chunk)?;
+            if !text.is_empty() {
+                streamed = true;
+                on_delta(text);
+            }
+        }
+        // A stream that closes without its end event was cut off too
+        stream.finish().map_err(|e| cut_off(e, streamed))
+    } else {
+        let body = response.bytes().await.map_err(ApiError::Network)?;
+        provider.parse_reply(&body)
+    }
+}
+
+/// A tutor request that's under way.
+pub(crate) struct StartedRequest {
+    pub(crate) handle: RequestHandle,
+    /// Set when older history was trimmed to fit the context budget.
+    pub(crate) trim: Option<ContextTrim>,
+}
+
+pub(crate) fn make_tutor_request(
+    settings: &Settings,
+    user_message: ChatMessage,
+    chat_history: Vec<ChatMessage>,
+    on_delta: impl Fn(String) + MaybeSync + 'static,
+    on_retry: impl Fn(u32, u32) + MaybeSync + 'static,
+    callback: impl Fn(Result<TutorReply, ApiError>) + MaybeSend + 'static,
+) -> Option<StartedRequest> {
+    let auth_state = AUTH_STATE.lock().unwrap();
+    let email = match (&auth_state.email, &settings.provider) {
+        (Some(email), _) => email.clone(),
+        (None, Provider::Scripted) => String::new(),
+        (None, _) => {
+            callback(Err(ApiError::NotAuthenticated));
+            return None;
+        }
+    };
+    // An expired token is refreshed before the request is sent
+    let expired = settings.provider.uses_sign_in() && auth_state.token_expired(chrono::Utc::now());
+    if expired && auth_state.refresh_token.is_none() {
+        callback(Err(ApiError::SessionExpired));
+        return None;
+    }
+    let access_token = if settings.provider.uses_sign_in() {
+        match &auth_state.access_token {
+            Some(token) => Some(token.clone()),
+            None => {
+                callback(Err(ApiError::NotAuthenticated));
+                return None;
+            }
+        }
+    } else {
+        None
+    };
+    drop(auth_state);
 
-        spawn_local(async move {
-            let response = Request::post(url)
-                .header("Content-Type", "application/json")
-                .body(payload)
-                .send()
-                .await;
-
-            match response {
-                Ok(resp) => {
-                    if resp.status() == 200 {
-                        match resp.text().await {
-                            Ok(body) => match serde_json::from_str::<AnthropicResponse>(&body) {
-                                Ok(data) => {
-                                    // Extract text from content items
-                                    let text = data
-                                        .content
-                                        .into_iter()
-                                        .filter(|item| item.content_type == "text")
-                                        .map(|item| item.text)
-                                        .collect::<Vec<_>>()
-                                        .join("");
-                                    callback(Ok(text))
-                                }
-                                Err(e) => callback(Err(format!("Failed to parse response: {}", e))),
-                            },
-                            Err(e) => callback(Err(format!("Failed to get response text: {}", e))),
-                        }
-                    } else {
-                        callback(Err(format!("Server error: {}", resp.status())))
-                    }
+    // Scripted replies come from the open lesson's transcript
+    let scripted = match settings.provider {
+        Provider::Scripted => {
+            let catalog = lesson::LESSON_CATALOG.lock().unwrap();
+            match ScriptedTransport::for_lesson(catalog.active_pack()) {
+                Ok(scripted) => Some(scripted),
+                Err(e) => {
+                    callback(Err(e));
+                    return None;
                 }
-                Err(e) => callback(Err(format!("Network error: {}", e))),
             }
-        });
+        }
+        _ => None,
+    };
+
+    let provider = settings.provider.clone();
+    let (chat, trim) = build_request(settings, user_message, chat_history, email, access_token);
+    let request = match provider.http_request(&chat) {
+        Ok(request) => request,
+        Err(e) => {
+            callback(Err(e));
+            return None;
+        }
+    };
+
+    let handle = spawn_request(move |transport| async move {
+        let result = match scripted {
+            Some(scripted) => {
+                request_reply(
+                    &scripted,
+                    &provider,
+                    &RETRY_POLICY,
+                    request,
+                    on_delta,
+                    on_retry,
+                )
+                .await
+            }
+            None => {
+                request_reply_signed_in(
+                    &transport, &provider, chat, request, expired, on_delta, on_retry,
+                )
+                .await
+            }
+        };
+        callback(result);
+    });
+    Some(StartedRequest { handle, trim })
+}
+
+/// Sends a request to a provider the student signs in for. The session is
+/// refreshed first if its token has expired, or once the proxy turns the
+/// token away and the request is then sent again, so the student is only
+/// signed out when the refresh itself is refused.
+async fn request_reply_signed_in(
+    transport: &impl Transport,
+    provider: &Provider,
+    mut chat: ChatRequest,
+    mut request: HttpRequest,
+    expired: bool,
+    on_delta: impl Fn(String),
+    on_retry: impl Fn(u32, u32),
+) -> Result<TutorReply, ApiError> {
+    if expired {
+        chat.access_token = Some(refresh_access_token().await?);
+        request = provider.http_request(&chat)?;
+    }
+    match request_reply(
+        transport,
+        provider,
+        &RETRY_POLICY,
+        request,
+        &on_delta,
+        &on_retry,
+    )
+    .await
+    {
+        Err(ApiError::SessionExpired) if !expired && provider.uses_sign_in() => {
+            log::info!("Access token was turned away, refreshing the session");
+            chat.access_token = Some(refresh_access_token().await?);
+            let request = provider.http_request(&chat)?;
+            request_reply(
+                transport,
+                provider,
+                &RETRY_POLICY,
+                request,
+                on_delta,
+                on_retry,
+            )
+            .await
+        }
+        result => result,
     }
 }
 
-#[cfg(not(target_arch = "wasm32"))]
-mod native {
+/// Refreshes the session and returns its new access token. A refresh that's
+/// refused has already signed the student out, and is reported as the
+/// session having expired.
+async fn refresh_access_token() -> Result<String, ApiError> {
+    #[cfg(target_arch = "wasm32")]
+    let result = refresh_session_web().await;
+    #[cfg(not(target_arch = "wasm32"))]
+    let result = refresh_session_native().await;
+
+    match result {
+        Ok(()) => AUTH_STATE
+            .lock()
+            .unwrap()
+            .access_token
+            .clone()
+            .ok_or(ApiError::SessionExpired),
+        Err(e) if e.is_transient() => Err(e),
+        Err(_) => Err(ApiError::SessionExpired),
+    }
+}
+
+#[cfg(test)]
+mod tests {
     use super::*;
-    use std::sync::Arc;
+    use crate::transport::MemoryTransport;
+    use std::time::Duration;
 
-    pub(crate) fn make_anthropic_request(
-        user_message: String,
-        chat_history: Vec<ChatMessage>,
-        callback: impl Fn(Result<String, String>) + Send + Sync + 'static,
-    ) {
-        let url = "https://dhruvdh-anthropic-s-50.deno.dev/";
+    const STREAM: &[&str] = &[
+        "event: content_block_start\ndata: {\"type\":\"content_block_start\",\"index\":0,\"content_block\":{\"type\":\"text\",\"text\":\"\"}}\n\n",
+        "event: content_block_delta\ndata: {\"type\":\"content_block_delta\",\"index\":0,\"delta\":{\"type\":\"text_delta\",\"text\":\"How many \"}}\n\nevent: content_",
+        "block_delta\ndata: {\"type\":\"content_block_delta\",\"index\":0,\"delta\":{\"type\":\"text_delta\",\"text\":\"comparisons?\"}}\n\n",
+        "event: message_stop\ndata: {\"type\":\"message_stop\"}\n\n",
+    ];
 
-        let auth_state = AUTH_STATE.lock().unwrap();
-        let email = match &auth_state.email {
-            Some(email) => email.clone(),
-            None => {
-                callback(Err("Not authenticated".to_string()));
-                return;
+    const NO_DELAY: RetryPolicy = RetryPolicy {
+        base_delay: Duration::ZERO,
+        ..RETRY_POLICY
+    };
+
+    fn request() -> HttpRequest {
+        HttpRequest {
+            url: "http://tutor.test/".to_string(),
+            headers: Vec::new(),
+            body: "{}".to_string(),
+        }
+    }
+
+    #[tokio::test]
+    async fn streamed_reply_is_passed_on_as_it_arrives() {
+        let transport = MemoryTransport::new(200, "text/event-stream; charset=utf-8", STREAM);
+        let deltas = Mutex::new(Vec::new());
+
+        let reply = request_reply(
+            &transport,
+            &Provider::default(),
+            &NO_DELAY,
+            request(),
+            |text| deltas.lock().unwrap().push(text),
+            |_, _| panic!("nothing failed"),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(reply.text, "How many comparisons?");
+        assert_eq!(*deltas.lock().unwrap(), vec!["How many ", "comparisons?"]);
+
+        let requests = transport.requests.lock().unwrap();
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0], request());
+    }
+
+    #[tokio::test]
+    async fn plain_json_reply_is_still_understood() {
+        let transport = MemoryTransport::new(
+            200,
+            "application/json",
+            &[
+                r#"{"content": [{"type": "text", "text": "Try a smaller "}"#,
+                r#", {"type": "text", "text": "array."}]}"#,
+            ],
+        );
+
+        let reply = request_reply(
+            &transport,
+            &Provider::default(),
+            &NO_DELAY,
+            request(),
+            |_| panic!("a JSON reply has nothing to stream"),
+            |_, _| panic!("nothing failed"),
+        )
+        .await
+        .unwrap();
+        assert_eq!(reply.text, "Try a smaller array.");
+    }
+
+    #[tokio::test]
+    async fn error_status_is_reported_once_retries_run_out() {
+        let transport = MemoryTransport::new(529, "application/json", &["{}"]);
+        let retries = Mutex::new(Vec::new());
+        let error = request_reply(
+            &transport,
+            &Provider::default(),
+            &NO_DELAY,
+            request(),
+            |_| {},
+            |retry, of| retries.lock().unwrap().push((retry, of)),
+        )
+        .await
+        .unwrap_err();
+
+        assert_eq!(
+            error,
+            ApiError::Status {
+                status: 529,
+                retry_after: None,
             }
-        };
-        drop(auth_state);
+        );
+        assert_eq!(
+            *retries.lock().unwrap(),
+            vec![(1, 4), (2, 4), (3, 4), (4, 4)]
+        );
+        assert_eq!(transport.requests.lock().unwrap().len(), 5);
+    }
+
+    #[tokio::test]
+    async fn network_errors_and_rate_limits_are_retried() {
+        let transport = MemoryTransport::failing("connection reset")
+            .then(429, "application/json", &["{}"])
+            .with_retry_after("0")
+            .then(200, "text/event-stream", STREAM);
+        let retries = Mutex::new(Vec::new());
 
-        let callback = Arc::new(callback);
+        let reply = request_reply(
+            &transport,
+            &Provider::default(),
+            &NO_DELAY,
+            request(),
+            |_| {},
+            |retry, of| retries.lock().unwrap().push((retry, of)),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(reply.text, "How many comparisons?");
+        assert_eq!(*retries.lock().unwrap(), vec![(1, 4), (2, 4)]);
+        assert_eq!(transport.requests.lock().unwrap().len(), 3);
+    }
 
-        let mut messages = Vec::new();
+    #[tokio::test]
+    async fn streams_without_message_stop_are_not_taken_as_replies() {
+        // Cut off after part of the reply was shown
+        let transport = MemoryTransport::new(200, "text/event-stream", &STREAM[..3]);
+        let error = request_reply(
+            &transport,
+            &Provider::default(),
+            &NO_DELAY,
+            request(),
+            |_| {},
+            |_, _| panic!("the student has already seen part of the reply"),
+        )
+        .await
+        .unwrap_err();
+        assert!(matches!(error, ApiError::Interrupted(_)), "{}", error);
+
+        // Cut off before any text, so it's sent again
+        let transport = MemoryTransport::new(200, "text/event-stream", &STREAM[..1]).then(
+            200,
+            "text/event-stream",
+            STREAM,
+        );
+        let reply = request_reply(
+            &transport,
+            &Provider::default(),
+            &NO_DELAY,
+            request(),
+            |_| {},
+            |_, _| {},
+        )
+        .await
+        .unwrap();
+        assert_eq!(reply.text, "How many comparisons?");
+        assert_eq!(transport.requests.lock().unwrap().len(), 2);
+    }
 
-        // Add all previous messages
-        for msg in chat_history {
-            messages.push(AnthropicMessage {
-                role: if msg.from_user { "user" } else { "assistant" }.to_string(),
-                content: msg.content,
-                cacheable: msg.cacheable,
-            });
+    #[tokio::test]
+    async fn client_errors_and_long_waits_are_not_retried() {
+        for transport in [
+            MemoryTransport::new(400, "application/json", &["{}"]),
+            MemoryTransport::new(503, "application/json", &["{}"]).with_retry_after("3600"),
+        ] {
+            request_reply(
+                &transport,
+                &Provider::default(),
+                &NO_DELAY,
+                request(),
+                |_| {},
+                |_, _| panic!("should give up straight away"),
+            )
+            .await
+            .unwrap_err();
+            assert_eq!(transport.requests.lock().unwrap().len(), 1);
         }
+    }
 
-        // Add the new message
-        messages.push(AnthropicMessage {
-            role: "user".to_string(),
-            content: user_message,
-            cacheable: false,
-        });
+    #[tokio::test]
+    async fn malformed_reply_is_reported() {
+        let transport = MemoryTransport::new(200, "application/json", &["<html>"]);
+        let error = request_reply(
+            &transport,
+            &Provider::default(),
+            &NO_DELAY,
+            request(),
+            |_| {},
+            |_, _| panic!("a malformed reply won't improve"),
+        )
+        .await
+        .unwrap_err();
+        assert!(matches!(error, ApiError::Decode(_)), "{}", error);
+    }
 
-        let request_payload = AnthropicRequest {
-            messages,
-            max_tokens: Some(MAX_TOKENS),
-            temperature: Some(TEMPERATURE),
-            system: get_system_message(),
-            email,
+    fn message(from_user: bool, content: &str, milestone: Option<&str>) -> ChatMessage {
+        ChatMessage {
+            content: content.to_string(),
+            from_user,
+            analyzed_for_milestones: true,
+            found_milestones: milestone
+                .map(|id| crate::app::MilestoneMatch {
+                    milestone_id: id.to_string(),
+                    description: String::new(),
+                })
+                .into_iter()
+                .collect(),
+            unsent: false,
+            sent_at: None,
+            metadata: None,
+        }
+    }
+
+    #[test]
+    fn request_ends_with_the_new_user_message() {
+        let history = vec![message(false, "Let's sort [7, 2, 4, 1].", None)];
+        let (request, trim) = build_request(
+            &Settings::default(),
+            message(true, "Compare pairs?", None),
+            history,
+            "a@b.edu".to_string(),
+            None,
+        );
+
+        let from_user: Vec<bool> = request.messages.iter().map(|m| m.from_user).collect();
+        assert_eq!(from_user, vec![false, true]);
+        assert_eq!(request.messages[1].content, "Compare pairs?");
+        assert!(request.messages[0].cacheable);
+        assert!(request.cache_system);
+        assert_eq!(trim, None);
+    }
+
+    #[test]
+    fn milestone_evidence_is_pinned() {
+        let history = vec![
+            message(true, "I am ready, please begin.", None),
+            message(false, "How would you sort [7, 2, 4, 1]?", None),
+            message(true, "That's 10 comparisons for 5 numbers!", None),
+            message(false, "Exactly.", Some("inefficiency_discovery")),
+        ];
+        let (request, _) = build_request(
+            &Settings::default(),
+            message(true, "What if we split it?", None),
+            history,
+            "a@b.edu".to_string(),
+            None,
+        );
+
+        let pinned: Vec<bool> = request.messages.iter().map(|m| m.pinned).collect();
+        assert_eq!(pinned, vec![false, false, true, true, false]);
+    }
+
+    #[tokio::test]
+    async fn a_reply_that_only_marks_a_milestone_can_be_answered() {
+        let transport = MemoryTransport::new(
+            200,
+            "application/json",
+            &[
+                r#"{"content": [{"type": "tool_use", "id": "toolu_01", "name": "mark_milestone", "input": {"milestone_id": "splitting_insight", "justification": "Split the list."}}]}"#,
+            ],
+        );
+        let reply = request_reply(
+            &transport,
+            &Provider::default(),
+            &NO_DELAY,
+            request(),
+            |_| {},
+            |_, _| panic!("nothing failed"),
+        )
+        .await
+        .unwrap();
+        assert_eq!(reply.text, "");
+        assert_eq!(reply.milestone_calls.len(), 1);
+
+        let history = vec![
+            message(true, "Split it in half?", None),
+            message(false, &reply.text, Some("splitting_insight")),
+        ];
+        let (chat, _) = build_request(
+            &Settings::default(),
+            message(true, "Then what?", None),
+            history,
+            "a@b.edu".to_string(),
+            None,
+        );
+        let provider = Provider::Anthropic {
+            api_key: "sk-ant-test".to_string(),
+            model: "claude-3-5-sonnet-latest".to_string(),
         };
+        let body: serde_json::Value =
+            serde_json::from_str(&provider.http_request(&chat).unwrap().body).unwrap();
+        let texts: Vec<&str> = body["messages"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|message| message["content"][0]["text"].as_str().unwrap())
+            .collect();
+        assert_eq!(texts.len(), 3);
+        assert!(
+            texts.iter().all(|text| !text.trim().is_empty()),
+            "{:?}",
+            texts
+        );
+        assert_eq!(texts[1], EMPTY_REPLY);
+    }
 
-        let payload = match serde_json::to_string(&request_payload) {
-            Ok(p) => p,
-            Err(e) => {
-                callback(Err(format!("Failed to serialize request: {}", e)));
-                return;
-            }
+    #[tokio::test]
+    async fn scripted_provider_answers_from_its_transcript() {
+        let provider =
+            Provider::from_parts("scripted", None, None, None).expect("scripted needs no settings");
+        let (chat, _) = build_request(
+            &Settings::default(),
+            message(true, "What if we split it in half?", None),
+            Vec::new(),
+            "a@b.edu".to_string(),
+            None,
+        );
+        let catalog = lesson::LessonCatalog::builtin();
+        let transport = ScriptedTransport::for_lesson(catalog.get("mergesort")).unwrap();
+        let deltas = Mutex::new(Vec::new());
+        let reply = request_reply(
+            &transport,
+            &provider,
+            &NO_DELAY,
+            provider.http_request(&chat).unwrap(),
+            |text| deltas.lock().unwrap().push(text),
+            |_, _| panic!("scripted replies don't fail"),
+        )
+        .await
+        .unwrap();
+
+        assert!(reply.text.ends_with("MILESTONE[splitting_insight]"));
+        assert!(deltas.lock().unwrap().len() > 1);
+        assert_eq!(deltas.lock().unwrap().concat(), reply.text);
+
+        let mut request = provider.http_request(&chat).unwrap();
+        request.body = "{}".to_string();
+        let error = request_reply(
+            &transport,
+            &provider,
+            &NO_DELAY,
+            request,
+            |_| {},
+            |_, _| panic!("an unreadable request won't become readable"),
+        )
+        .await;
+        assert!(matches!(error, Err(ApiError::Provider(_))), "{:?}", error);
+    }
+
+    #[test]
+    fn student_turns_carry_their_metadata() {
+        let submitted_at = chrono::Utc::now();
+        let metadata = metadata::TurnMetadata {
+            last_tutor_message_at: None,
+            started_typing_at: None,
+            submitted_at,
+            checkpoint: None,
+        };
+        let tutor = ChatMessage {
+            metadata: Some(metadata.clone()),
+            ..message(false, "Let's sort [7, 2, 4, 1].", None)
+        };
+        let student = ChatMessage {
+            metadata: Some(metadata.clone()),
+            ..message(true, "Compare pairs?", None)
         };
+        let (request, _) = build_request(
+            &Settings::default(),
+            student,
+            vec![tutor],
+            "a@b.edu".to_string(),
+            None,
+        );
 
-        tokio::spawn(async move {
-            let client = reqwest::Client::new();
-            let response = client
-                .post(url)
-                .header("Content-Type", "application/json")
-                .body(payload)
-                .send()
-                .await;
-
-            match response {
-                Ok(resp) => {
-                    if resp.status().is_success() {
-                        match resp.text().await {
-                            Ok(body) => match serde_json::from_str::<AnthropicResponse>(&body) {
-                                Ok(data) => {
-                                    // Extract text from content items
-                                    let text = data
-                                        .content
-                                        .into_iter()
-                                        .filter(|item| item.content_type == "text")
-                                        .map(|item| item.text)
-                                        .collect::<Vec<_>>()
-                                        .join("");
-                                    callback(Ok(text))
-                                }
-                                Err(e) => callback(Err(format!("Failed to parse response: {}", e))),
-                            },
-                            Err(e) => callback(Err(format!("Failed to get response text: {}", e))),
-                        }
-                    } else {
-                        callback(Err(format!("Server error: {}", resp.status())))
-                    }
-                }
-                Err(e) => callback(Err(format!("Network error: {}", e))),
-            }
-        });
+        assert_eq!(request.messages[0].content, "Let's sort [7, 2, 4, 1].");
+        assert_eq!(
+            request.messages[1].content,
+            format!("{}\n\nCompare pairs?", metadata.render())
+        );
     }
-}
 
-#[cfg(target_arch = "wasm32")]
-pub(crate) use web::make_anthropic_request;
+    #[test]
+    fn access_tokens_expire_by_their_claim() {
+        // {"alg":"HS256","typ":"JWT"}.{"sub":"1","exp":1700000000}
+        let token =
+            "eyJhbGciOiJIUzI1NiIsInR5cCI6IkpXVCJ9.eyJzdWIiOiIxIiwiZXhwIjoxNzAwMDAwMDAwfQ.c2ln";
+        let exp = chrono::DateTime::from_timestamp(1_700_000_000, 0).unwrap();
+        assert_eq!(token_expiry(token), Some(exp));
 
-#[cfg(not(target_arch = "wasm32"))]
-pub(crate) use native::make_anthropic_request;
+        let auth = AuthState {
+            signed_in: true,
+            email: Some("a@b.edu".to_string()),
+            access_token: Some(token.to_string()),
+            ..AuthState::default()
+        };
+        assert!(!auth.token_expired(exp - chrono::Duration::minutes(5)));
+        assert!(auth.token_expired(exp - chrono::Duration::seconds(10)));
+        assert!(auth.token_expired(exp));
+
+        let opaque = AuthState {
+            access_token: Some("opaque-token".to_string()),
+            ..auth
+        };
+        assert!(!opaque.token_expired(exp));
+    }
+
+    #[test]
+    fn sessions_are_refreshed_before_they_expire() {
+        let now = chrono::DateTime::from_timestamp(1_700_000_000, 0).unwrap();
+        let response = |expires_at, expires_in| SupabaseAuthResponse {
+            access_token: "opaque-token".to_string(),
+            refresh_token: Some("refresh".to_string()),
+            expires_in,
+            expires_at,
+        };
+
+        let mut auth = AuthState::default();
+        auth.start_session(response(Some(1_700_003_600), Some(60)), now);
+        assert_eq!(auth.expires_at, Some(now + chrono::Duration::hours(1)));
+        assert_eq!(
+            auth.refresh_due(),
+            Some(now + chrono::Duration::minutes(55))
+        );
+
+        auth.start_session(response(None, Some(600)), now);
+        assert_eq!(auth.refresh_due(), Some(now + chrono::Duration::minutes(5)));
+
+        auth.sign_out();
+        assert_eq!(auth.refresh_due(), None);
+        assert_eq!(auth.refresh_token, None);
+
+        // Without a refresh token the session just runs out
+        auth.start_session(
+            SupabaseAuthResponse {
+                refresh_token: None,
+                ..response(None, Some(600))
+            },
+            now,
+        );
+        assert!(auth.signed_in);
+        assert_eq!(auth.refresh_due(), None);
+    }
+}
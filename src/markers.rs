@@ -0,0 +1,327 @@
+#![warn(clippy::all)]
+
+use lazy_static::lazy_static;
+use regex::Regex;
+use std::borrow::Cow;
+
+const MARKER_PREFIX: &str = "MILESTONE[";
+
+lazy_static! {
+    // Also matches a marker cut off at the end of a line, e.g. by a stopped stream
+    static ref MARKER: Regex = Regex::new(r"(?m)MILESTONE\[[^\]\n]*(?:\]|$)").unwrap();
+    static ref INLINE_CODE: Regex = Regex::new(r"`+[^`]*`+").unwrap();
+}
+
+/// A well-formed marker naming a known milestone.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Marker {
+    pub(crate) milestone_id: String,
+    /// 1-based line of the message the marker was found on.
+    pub(crate) line: usize,
+}
+
+/// Something in a message that looked like a marker but couldn't be used.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum MarkerDiagnostic {
+    UnknownMilestone { milestone_id: String, line: usize },
+    Malformed { text: String, line: usize },
+}
+
+impl std::fmt::Display for MarkerDiagnostic {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::UnknownMilestone { milestone_id, line } => {
+                write!(f, "line {}: unknown milestone '{}'", line, milestone_id)
+            }
+            Self::Malformed { text, line } => {
+                write!(f, "line {}: malformed milestone marker '{}'", line, text)
+            }
+        }
+    }
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub(crate) struct MarkerScan {
+    pub(crate) markers: Vec<Marker>,
+    pub(crate) diagnostics: Vec<MarkerDiagnostic>,
+}
+
+/// Finds every `MILESTONE[id]` marker in a tutor message.
+///
+/// Markers inside fenced code blocks, inline code and block quotes are examples
+/// rather than claims, so they are skipped. A milestone marked more than once
+/// is only reported the first time.
+pub(crate) fn scan(content: &str, known_ids: &[&str]) -> MarkerScan {
+    let mut result = MarkerScan::default();
+    let mut fence: Option<&str> = None;
+
+    for (line_idx, line) in content.lines().enumerate() {
+        let line_number = line_idx + 1;
+        let trimmed = line.trim_start();
+
+        if let Some(open) = fence {
+            if trimmed.starts_with(open) {
+                fence = None;
+            }
+            continue;
+        }
+        if let Some(open) = ["```", "~~~"].into_iter().find(|f| trimmed.starts_with(f)) {
+            fence = Some(open);
+            continue;
+        }
+        if trimmed.starts_with('>') {
+            continue;
+        }
+
+        let code_spans: Vec<_> = INLINE_CODE.find_iter(line).map(|m| m.range()).collect();
+        let starts = line
+            .match_indices(MARKER_PREFIX)
+            .map(|(start, _)| start)
+            .filter(|start| !code_spans.iter().any(|span| span.contains(start)));
+
+        for start in starts {
+            let rest = &line[start + MARKER_PREFIX.len()..];
+            // The id ends at the first `]`, unless another marker starts first
+            let end = rest
+                .find(']')
+                .filter(|end| !rest[..*end].contains(MARKER_PREFIX));
+            let Some(end) = end else {
+                result.diagnostics.push(MarkerDiagnostic::Malformed {
+                    text: line[start..].trim_end().to_string(),
+                    line: line_number,
+                });
+                continue;
+            };
+
+            let id = rest[..end].trim();
+            if id.is_empty() || !id.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+                result.diagnostics.push(MarkerDiagnostic::Malformed {
+                    text: line[start..start + MARKER_PREFIX.len() + end + 1].to_string(),
+                    line: line_number,
+                });
+            } else if !known_ids.contains(&id) {
+                result.diagnostics.push(MarkerDiagnostic::UnknownMilestone {
+                    milestone_id: id.to_string(),
+                    line: line_number,
+                });
+            } else if !result.markers.iter().any(|m| m.milestone_id == id) {
+                result.markers.push(Marker {
+                    milestone_id: id.to_string(),
+                    line: line_number,
+                });
+            }
+        }
+    }
+
+    result
+}
+
+/// Removes `MILESTONE[...]` markers from a tutor message so students never see
+/// milestone ids, including markers missing their closing `]`. Lines that held
+/// only a marker are dropped entirely.
+pub(crate) fn strip_markers(content: &str) -> Cow<'_, str> {
+    if !MARKER.is_match(content) {
+        return Cow::Borrowed(content);
+    }
+
+    let lines: Vec<String> = content
+        .lines()
+        .filter_map(|line| {
+            if !MARKER.is_match(line) {
+                return Some(line.to_string());
+            }
+            let stripped = MARKER.replace_all(line, "");
+            (!stripped.trim().is_empty()).then(|| stripped.trim_end().to_string())
+        })
+        .collect();
+
+    Cow::Owned(lines.join("\n").trim_end().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MERGESORT_IDS: &[&str] = &[
+        "inefficiency_discovery",
+        "splitting_insight",
+        "merging_development",
+        "recursive_pattern",
+        "efficiency_analysis",
+    ];
+
+    fn marker(milestone_id: &str, line: usize) -> Marker {
+        Marker {
+            milestone_id: milestone_id.to_string(),
+            line,
+        }
+    }
+
+    fn unknown(milestone_id: &str, line: usize) -> MarkerDiagnostic {
+        MarkerDiagnostic::UnknownMilestone {
+            milestone_id: milestone_id.to_string(),
+            line,
+        }
+    }
+
+    fn malformed(text: &str, line: usize) -> MarkerDiagnostic {
+        MarkerDiagnostic::Malformed {
+            text: text.to_string(),
+            line,
+        }
+    }
+
+    #[test]
+    fn scan_tutor_responses() {
+        struct Case {
+            name: &'static str,
+            response: &'static str,
+            markers: Vec<Marker>,
+            diagnostics: Vec<MarkerDiagnostic>,
+        }
+
+        let cases = vec![
+            Case {
+                name: "no marker",
+                response: "Good start! How many comparisons did you make for `[7, 4, 2, 1]`?",
+                markers: vec![],
+                diagnostics: vec![],
+            },
+            Case {
+                name: "marker on its own line",
+                response: "Exactly - with 5 numbers that's 4+3+2+1 = 10 comparisons, and it keeps growing.\n\
+                           MILESTONE[inefficiency_discovery]\n\
+                           \n\
+                           Now let's try something different. What if we split `[7, 2, 4, 1]` in half?",
+                markers: vec![marker("inefficiency_discovery", 2)],
+                diagnostics: vec![],
+            },
+            Case {
+                name: "two markers on one line",
+                response: "You've nailed both ideas!\n\
+                           MILESTONE[merging_development] MILESTONE[recursive_pattern]\n\
+                           So how many levels of splitting does an 8-element array need?",
+                markers: vec![
+                    marker("merging_development", 2),
+                    marker("recursive_pattern", 2),
+                ],
+                diagnostics: vec![],
+            },
+            Case {
+                name: "whitespace inside brackets",
+                response: "That's the key insight.\nMILESTONE[ splitting_insight ]",
+                markers: vec![marker("splitting_insight", 2)],
+                diagnostics: vec![],
+            },
+            Case {
+                name: "marker inside a fenced code block",
+                response: "Here's how I'll mark progress:\n\
+                           ```\n\
+                           MILESTONE[splitting_insight]\n\
+                           ```\n\
+                           Let's keep going - what do you notice about `[2, 7]` and `[1, 4]`?",
+                markers: vec![],
+                diagnostics: vec![],
+            },
+            Case {
+                name: "marker after a tilde fence closes",
+                response: "~~~\n\
+                           [2,7] and [1,4] → [1,2,4,7]\n\
+                           ~~~\n\
+                           Exactly right!\n\
+                           MILESTONE[merging_development]",
+                markers: vec![marker("merging_development", 5)],
+                diagnostics: vec![],
+            },
+            Case {
+                name: "marker in inline code",
+                response: "I write `MILESTONE[efficiency_analysis]` only when you can explain n log n.",
+                markers: vec![],
+                diagnostics: vec![],
+            },
+            Case {
+                name: "marker in a block quote",
+                response: "> Student: \"maybe we could split it?\"\n\
+                           > MILESTONE[splitting_insight]\n\
+                           That would be too early - we need to verify first.",
+                markers: vec![],
+                diagnostics: vec![],
+            },
+            Case {
+                name: "unknown milestone id",
+                response: "Great!\nMILESTONE[binary_search_insight]",
+                markers: vec![],
+                diagnostics: vec![unknown("binary_search_insight", 2)],
+            },
+            Case {
+                name: "unclosed marker",
+                response: "Well done!\nMILESTONE[recursive_pattern\nNext question...",
+                markers: vec![],
+                diagnostics: vec![malformed("MILESTONE[recursive_pattern", 2)],
+            },
+            Case {
+                name: "unclosed marker followed by a valid one",
+                response: "MILESTONE[splitting_insight MILESTONE[merging_development]",
+                markers: vec![marker("merging_development", 1)],
+                diagnostics: vec![malformed(
+                    "MILESTONE[splitting_insight MILESTONE[merging_development]",
+                    1,
+                )],
+            },
+            Case {
+                name: "empty and invalid ids",
+                response: "MILESTONE[]\nMILESTONE[splitting insight]",
+                markers: vec![],
+                diagnostics: vec![
+                    malformed("MILESTONE[]", 1),
+                    malformed("MILESTONE[splitting insight]", 2),
+                ],
+            },
+            Case {
+                name: "repeated marker is reported once",
+                response: "MILESTONE[splitting_insight]\nAs I said:\nMILESTONE[splitting_insight]",
+                markers: vec![marker("splitting_insight", 1)],
+                diagnostics: vec![],
+            },
+        ];
+
+        for case in cases {
+            let result = scan(case.response, MERGESORT_IDS);
+            assert_eq!(result.markers, case.markers, "markers for '{}'", case.name);
+            assert_eq!(
+                result.diagnostics, case.diagnostics,
+                "diagnostics for '{}'",
+                case.name
+            );
+        }
+    }
+
+    #[test]
+    fn strip_markers_hides_marker_lines_and_inline_markers() {
+        let content = "Exactly - 4+3+2+1 comparisons!\nMILESTONE[inefficiency_discovery]\n\nNow, what if we split it? MILESTONE[splitting_insight]";
+        assert_eq!(
+            strip_markers(content),
+            "Exactly - 4+3+2+1 comparisons!\n\nNow, what if we split it?"
+        );
+    }
+
+    #[test]
+    fn strip_markers_hides_unclosed_markers() {
+        let content =
+            "So you'd keep splitting?\nMILESTONE[recursive_pattern\nWhat next? MILESTONE[merg";
+        assert_eq!(
+            strip_markers(content),
+            "So you'd keep splitting?\nWhat next?"
+        );
+        assert_eq!(
+            strip_markers("Nice! MILESTONE[splitting_insight] MILESTONE[merging"),
+            "Nice!"
+        );
+    }
+
+    #[test]
+    fn strip_markers_leaves_plain_messages_untouched() {
+        let content = "How many comparisons did that take?";
+        assert!(matches!(strip_markers(content), Cow::Borrowed(_)));
+    }
+}
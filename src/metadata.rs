@@ -0,0 +1,150 @@
+#![warn(clippy::all)]
+
+use chrono::{DateTime, SecondsFormat, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::milestones::{MilestoneStatus, MilestoneTracker};
+
+const OPEN_TAG: &str = "<turn_metadata>";
+const CLOSE_TAG: &str = "</turn_metadata>";
+
+/// Told to the tutor in the system prompt so it reads the metadata block.
+pub(crate) const METADATA_GUIDANCE: &str = "Each student message starts with a \
+    <turn_metadata> block that the student cannot see. It gives when your previous \
+    message arrived, when the student started typing and submitted their reply, \
+    the checkpoint they are working on and how long they have spent on it. Use it to \
+    adapt your pacing, e.g. offer a hint to a student who has been stuck for a long \
+    time, but never mention or quote the block.";
+
+/// The checkpoint a student was working on when they sent a message.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(crate) struct Checkpoint {
+    /// Position in the lesson, counting from 1.
+    pub(crate) number: usize,
+    pub(crate) total: usize,
+    pub(crate) milestone_id: String,
+    /// Time spent on it so far, in seconds.
+    pub(crate) elapsed_secs: i64,
+}
+
+/// Pacing information sent to the tutor with a student message, but never
+/// shown to the student.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(crate) struct TurnMetadata {
+    pub(crate) last_tutor_message_at: Option<DateTime<Utc>>,
+    pub(crate) started_typing_at: Option<DateTime<Utc>>,
+    pub(crate) submitted_at: DateTime<Utc>,
+    pub(crate) checkpoint: Option<Checkpoint>,
+}
+
+impl TurnMetadata {
+    /// Records a message submitted `now`. The current checkpoint is the first
+    /// milestone in progress, in lesson order.
+    pub(crate) fn capture(
+        milestones: &MilestoneTracker,
+        last_tutor_message_at: Option<DateTime<Utc>>,
+        started_typing_at: Option<DateTime<Utc>>,
+        now: DateTime<Utc>,
+    ) -> Self {
+        let checkpoint = milestones
+            .iter()
+            .enumerate()
+            .find(|(_, m)| m.status == MilestoneStatus::InProgress)
+            .map(|(idx, m)| Checkpoint {
+                number: idx + 1,
+                total: milestones.len(),
+                milestone_id: m.id.clone(),
+                elapsed_secs: m.elapsed(now).map_or(0, |e| e.num_seconds().max(0)),
+            });
+
+        Self {
+            last_tutor_message_at,
+            started_typing_at,
+            submitted_at: now,
+            checkpoint,
+        }
+    }
+
+    /// The tagged block prepended to the message sent to the tutor.
+    pub(crate) fn render(&self) -> String {
+        let time = |at: Option<DateTime<Utc>>| {
+            at.map_or("unknown".to_string(), |at| {
+                at.to_rfc3339_opts(SecondsFormat::Secs, true)
+            })
+        };
+        let checkpoint = match &self.checkpoint {
+            Some(c) => format!(
+                "checkpoint: {} of {} ({})\ntime_on_checkpoint: {}s",
+                c.number, c.total, c.milestone_id, c.elapsed_secs
+            ),
+            None => "checkpoint: none (all completed)".to_string(),
+        };
+        format!(
+            "{}\nlast_tutor_message_at: {}\nstarted_typing_at: {}\nsubmitted_at: {}\n{}\n{}",
+            OPEN_TAG,
+            time(self.last_tutor_message_at),
+            time(self.started_typing_at),
+            time(Some(self.submitted_at)),
+            checkpoint,
+            CLOSE_TAG
+        )
+    }
+}
+
+/// The message without a leading metadata block.
+pub(crate) fn strip_block(content: &str) -> &str {
+    content
+        .strip_prefix(OPEN_TAG)
+        .and_then(|rest| rest.split_once(CLOSE_TAG))
+        .map_or(content, |(_, message)| message.trim_start())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lesson::MilestoneDefinition;
+    use chrono::TimeZone;
+
+    fn at(minute: u32, second: u32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2026, 3, 2, 10, minute, second)
+            .unwrap()
+    }
+
+    #[test]
+    fn metadata_names_the_checkpoint_in_progress() {
+        let definition = |id: &str, requires: &[&str]| MilestoneDefinition {
+            id: id.to_string(),
+            description: String::new(),
+            requires: requires.iter().map(|r| r.to_string()).collect(),
+        };
+        let definitions = vec![
+            definition("inefficiency_discovery", &[]),
+            definition("splitting_insight", &["inefficiency_discovery"]),
+        ];
+        let mut milestones = MilestoneTracker::from_definitions(
+            &definitions,
+            &MilestoneTracker::default(),
+            at(0, 0),
+        );
+        milestones
+            .complete("inefficiency_discovery", Default::default(), at(4, 0))
+            .unwrap();
+
+        let metadata = TurnMetadata::capture(&milestones, Some(at(6, 0)), None, at(7, 30));
+        let block = metadata.render();
+
+        assert_eq!(
+            block,
+            "<turn_metadata>\n\
+             last_tutor_message_at: 2026-03-02T10:06:00Z\n\
+             started_typing_at: unknown\n\
+             submitted_at: 2026-03-02T10:07:30Z\n\
+             checkpoint: 2 of 2 (splitting_insight)\n\
+             time_on_checkpoint: 210s\n\
+             </turn_metadata>"
+        );
+        let sent = format!("{}\n\nWhat if we split it?", block);
+        assert_eq!(strip_block(&sent), "What if we split it?");
+        assert_eq!(strip_block("No block here"), "No block here");
+    }
+}
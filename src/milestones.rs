@@ -0,0 +1,479 @@
+#![warn(clippy::all)]
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::lesson::MilestoneDefinition;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub(crate) enum MilestoneStatus {
+    NotStarted,
+    InProgress,
+    Completed,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct Milestone {
+    pub(crate) id: String,
+    pub(crate) description: String,
+    pub(crate) status: MilestoneStatus,
+    /// Ids of the milestones that must be completed before this one.
+    #[serde(default)]
+    pub(crate) requires: Vec<String>,
+    /// Set when the milestone was completed before its prerequisites.
+    #[serde(default)]
+    pub(crate) out_of_order: bool,
+    #[serde(default)]
+    pub(crate) started_at: Option<DateTime<Utc>>,
+    #[serde(default)]
+    pub(crate) completed_at: Option<DateTime<Utc>>,
+    /// The tutor's explanation of why the milestone was reached.
+    #[serde(default)]
+    pub(crate) evidence: Option<String>,
+}
+
+impl Milestone {
+    /// Time spent on the milestone so far, or in total once it's completed.
+    pub(crate) fn elapsed(&self, now: DateTime<Utc>) -> Option<chrono::Duration> {
+        let started_at = self.started_at?;
+        Some(self.completed_at.unwrap_or(now) - started_at)
+    }
+}
+
+/// What to do when the tutor marks a milestone whose prerequisites aren't done.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OutOfOrderPolicy {
+    /// Complete it anyway, but flag it for review.
+    #[default]
+    Flag,
+    /// Leave it untouched.
+    Reject,
+}
+
+/// A single status change of one milestone.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct MilestoneEvent {
+    pub(crate) milestone_id: String,
+    pub(crate) from: MilestoneStatus,
+    pub(crate) to: MilestoneStatus,
+    pub(crate) out_of_order: bool,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum TransitionError {
+    UnknownMilestone(String),
+    AlreadyCompleted(String),
+    MissingPrerequisites {
+        milestone_id: String,
+        missing: Vec<String>,
+    },
+}
+
+impl std::fmt::Display for TransitionError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::UnknownMilestone(id) => write!(f, "unknown milestone '{}'", id),
+            Self::AlreadyCompleted(id) => write!(f, "milestone '{}' is already completed", id),
+            Self::MissingPrerequisites {
+                milestone_id,
+                missing,
+            } => write!(
+                f,
+                "milestone '{}' requires {} first",
+                milestone_id,
+                missing.join(", ")
+            ),
+        }
+    }
+}
+
+/// A lesson's milestones as a dependency graph, in lesson order.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(transparent)]
+pub(crate) struct MilestoneTracker {
+    milestones: Vec<Milestone>,
+}
+
+impl MilestoneTracker {
+    /// Builds the tracker from the lesson's definitions, keeping the progress of
+    /// milestones from `previous` that still exist.
+    pub(crate) fn from_definitions(
+        definitions: &[MilestoneDefinition],
+        previous: &MilestoneTracker,
+        now: DateTime<Utc>,
+    ) -> Self {
+        let mut tracker = Self {
+            milestones: definitions
+                .iter()
+                .map(|definition| match previous.get(&definition.id) {
+                    Some(existing) => Milestone {
+                        description: definition.description.clone(),
+                        requires: definition.requires.clone(),
+                        ..existing.clone()
+                    },
+                    None => Milestone {
+                        id: definition.id.clone(),
+                        description: definition.description.clone(),
+                        status: MilestoneStatus::NotStarted,
+                        requires: definition.requires.clone(),
+                        out_of_order: false,
+                        started_at: None,
+                        completed_at: None,
+                        evidence: None,
+                    },
+                })
+                .collect(),
+        };
+
+        tracker.advance(now);
+        tracker
+    }
+
+    /// Completes `id` and moves every milestone it unlocks to `InProgress`,
+    /// returning one event per status change.
+    pub(crate) fn complete(
+        &mut self,
+        id: &str,
+        policy: OutOfOrderPolicy,
+        now: DateTime<Utc>,
+    ) -> Result<Vec<MilestoneEvent>, TransitionError> {
+        let milestone = self
+            .get(id)
+            .ok_or_else(|| TransitionError::UnknownMilestone(id.to_string()))?;
+        if milestone.status == MilestoneStatus::Completed {
+            return Err(TransitionError::AlreadyCompleted(id.to_string()));
+        }
+
+        let missing: Vec<String> = milestone
+            .requires
+            .iter()
+            .filter(|r| !self.is_completed(r))
+            .cloned()
+            .collect();
+        let out_of_order = !missing.is_empty();
+        if out_of_order && policy == OutOfOrderPolicy::Reject {
+            return Err(TransitionError::MissingPrerequisites {
+                milestone_id: id.to_string(),
+                missing,
+            });
+        }
+
+        let milestone = self.get_mut(id).expect("milestone exists");
+        let event = MilestoneEvent {
+            milestone_id: milestone.id.clone(),
+            from: milestone.status,
+            to: MilestoneStatus::Completed,
+            out_of_order,
+        };
+        milestone.status = MilestoneStatus::Completed;
+        milestone.out_of_order = out_of_order;
+        milestone.started_at.get_or_insert(now);
+        milestone.completed_at = Some(now);
+
+        let mut events = vec![event];
+        events.extend(self.advance(now));
+        Ok(events)
+    }
+
+    /// Moves every reachable milestone that hasn't started to `InProgress`.
+    fn advance(&mut self, now: DateTime<Utc>) -> Vec<MilestoneEvent> {
+        let unlocked: Vec<String> = self
+            .milestones
+            .iter()
+            .filter(|m| m.status == MilestoneStatus::NotStarted && self.is_reachable(&m.id))
+            .map(|m| m.id.clone())
+            .collect();
+
+        unlocked
+            .into_iter()
+            .map(|id| {
+                let milestone = self.get_mut(&id).expect("milestone exists");
+                milestone.status = MilestoneStatus::InProgress;
+                milestone.started_at = Some(now);
+                MilestoneEvent {
+                    milestone_id: id,
+                    from: MilestoneStatus::NotStarted,
+                    to: MilestoneStatus::InProgress,
+                    out_of_order: false,
+                }
+            })
+            .collect()
+    }
+
+    pub(crate) fn iter(&self) -> impl Iterator<Item = &Milestone> {
+        self.milestones.iter()
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.milestones.len()
+    }
+
+    pub(crate) fn get(&self, id: &str) -> Option<&Milestone> {
+        self.milestones.iter().find(|m| m.id == id)
+    }
+
+    pub(crate) fn get_mut(&mut self, id: &str) -> Option<&mut Milestone> {
+        self.milestones.iter_mut().find(|m| m.id == id)
+    }
+
+    pub(crate) fn is_completed(&self, id: &str) -> bool {
+        self.get(id)
+            .is_some_and(|m| m.status == MilestoneStatus::Completed)
+    }
+
+    pub(crate) fn completed_count(&self) -> usize {
+        self.milestones
+            .iter()
+            .filter(|m| m.status == MilestoneStatus::Completed)
+            .count()
+    }
+
+    /// A milestone is reachable when it isn't completed yet and all of its
+    /// prerequisites are.
+    pub(crate) fn is_reachable(&self, id: &str) -> bool {
+        self.get(id).is_some_and(|m| {
+            m.status != MilestoneStatus::Completed
+                && m.requires.iter().all(|r| self.is_completed(r))
+        })
+    }
+
+    /// Depth of each milestone in the graph (longest chain of prerequisites),
+    /// in lesson order. Prerequisites always come earlier in the lesson.
+    pub(crate) fn depths(&self) -> Vec<usize> {
+        let mut depths: Vec<usize> = Vec::with_capacity(self.milestones.len());
+        for milestone in &self.milestones {
+            let depth = milestone
+                .requires
+                .iter()
+                .filter_map(|r| self.milestones.iter().position(|m| &m.id == r))
+                .filter_map(|index| depths.get(index))
+                .map(|depth| depth + 1)
+                .max()
+                .unwrap_or(0);
+            depths.push(depth);
+        }
+        depths
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn at(minute: u32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2024, 11, 4, 10, minute, 0).unwrap()
+    }
+
+    fn definition(id: &str, requires: &[&str]) -> MilestoneDefinition {
+        MilestoneDefinition {
+            id: id.to_string(),
+            description: id.to_string(),
+            requires: requires.iter().map(|r| r.to_string()).collect(),
+        }
+    }
+
+    /// The merge sort graph: `merging` and `recursion` can happen in either order.
+    fn tracker() -> MilestoneTracker {
+        MilestoneTracker::from_definitions(
+            &[
+                definition("inefficiency", &[]),
+                definition("splitting", &["inefficiency"]),
+                definition("merging", &["splitting"]),
+                definition("recursion", &["splitting"]),
+                definition("efficiency", &["merging", "recursion"]),
+            ],
+            &MilestoneTracker::default(),
+            at(0),
+        )
+    }
+
+    fn status(tracker: &MilestoneTracker, id: &str) -> MilestoneStatus {
+        tracker.get(id).unwrap().status
+    }
+
+    fn event(id: &str, from: MilestoneStatus, to: MilestoneStatus) -> MilestoneEvent {
+        MilestoneEvent {
+            milestone_id: id.to_string(),
+            from,
+            to,
+            out_of_order: false,
+        }
+    }
+
+    #[test]
+    fn only_roots_start_in_progress() {
+        let tracker = tracker();
+        assert_eq!(
+            status(&tracker, "inefficiency"),
+            MilestoneStatus::InProgress
+        );
+        assert_eq!(status(&tracker, "splitting"), MilestoneStatus::NotStarted);
+        assert_eq!(status(&tracker, "efficiency"), MilestoneStatus::NotStarted);
+    }
+
+    #[test]
+    fn completing_advances_to_next_milestone() {
+        let mut tracker = tracker();
+        let events = tracker
+            .complete("inefficiency", OutOfOrderPolicy::Reject, at(1))
+            .unwrap();
+
+        assert_eq!(
+            events,
+            vec![
+                event(
+                    "inefficiency",
+                    MilestoneStatus::InProgress,
+                    MilestoneStatus::Completed
+                ),
+                event(
+                    "splitting",
+                    MilestoneStatus::NotStarted,
+                    MilestoneStatus::InProgress
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn completing_a_branch_point_unlocks_every_branch() {
+        let mut tracker = tracker();
+        tracker
+            .complete("inefficiency", OutOfOrderPolicy::Reject, at(1))
+            .unwrap();
+        let events = tracker
+            .complete("splitting", OutOfOrderPolicy::Reject, at(1))
+            .unwrap();
+
+        assert_eq!(events.len(), 3);
+        assert_eq!(status(&tracker, "merging"), MilestoneStatus::InProgress);
+        assert_eq!(status(&tracker, "recursion"), MilestoneStatus::InProgress);
+    }
+
+    #[test]
+    fn join_waits_for_all_prerequisites() {
+        let mut tracker = tracker();
+        for id in ["inefficiency", "splitting", "recursion"] {
+            tracker
+                .complete(id, OutOfOrderPolicy::Reject, at(1))
+                .unwrap();
+        }
+        assert_eq!(status(&tracker, "efficiency"), MilestoneStatus::NotStarted);
+
+        let events = tracker
+            .complete("merging", OutOfOrderPolicy::Reject, at(1))
+            .unwrap();
+        assert_eq!(
+            events.last(),
+            Some(&event(
+                "efficiency",
+                MilestoneStatus::NotStarted,
+                MilestoneStatus::InProgress
+            ))
+        );
+    }
+
+    #[test]
+    fn reject_policy_refuses_out_of_order_completion() {
+        let mut tracker = tracker();
+        let error = tracker
+            .complete("merging", OutOfOrderPolicy::Reject, at(1))
+            .unwrap_err();
+
+        assert_eq!(
+            error,
+            TransitionError::MissingPrerequisites {
+                milestone_id: "merging".to_string(),
+                missing: vec!["splitting".to_string()],
+            }
+        );
+        assert_eq!(status(&tracker, "merging"), MilestoneStatus::NotStarted);
+    }
+
+    #[test]
+    fn flag_policy_completes_and_flags_out_of_order_completion() {
+        let mut tracker = tracker();
+        let events = tracker
+            .complete("merging", OutOfOrderPolicy::Flag, at(1))
+            .unwrap();
+
+        assert_eq!(events.len(), 1);
+        assert!(events[0].out_of_order);
+        assert_eq!(events[0].from, MilestoneStatus::NotStarted);
+        assert!(tracker.get("merging").unwrap().out_of_order);
+        assert_eq!(status(&tracker, "merging"), MilestoneStatus::Completed);
+    }
+
+    #[test]
+    fn completing_twice_is_an_error() {
+        let mut tracker = tracker();
+        tracker
+            .complete("inefficiency", OutOfOrderPolicy::Reject, at(1))
+            .unwrap();
+        assert_eq!(
+            tracker.complete("inefficiency", OutOfOrderPolicy::Flag, at(1)),
+            Err(TransitionError::AlreadyCompleted(
+                "inefficiency".to_string()
+            ))
+        );
+    }
+
+    #[test]
+    fn unknown_milestone_is_an_error() {
+        let mut tracker = tracker();
+        assert_eq!(
+            tracker.complete("heap_property", OutOfOrderPolicy::Flag, at(1)),
+            Err(TransitionError::UnknownMilestone(
+                "heap_property".to_string()
+            ))
+        );
+    }
+
+    #[test]
+    fn resync_keeps_progress_and_advances() {
+        let mut tracker = tracker();
+        tracker
+            .complete("inefficiency", OutOfOrderPolicy::Reject, at(1))
+            .unwrap();
+
+        let resynced = MilestoneTracker::from_definitions(
+            &[
+                definition("inefficiency", &[]),
+                definition("splitting", &["inefficiency"]),
+            ],
+            &tracker,
+            at(2),
+        );
+        assert_eq!(
+            status(&resynced, "inefficiency"),
+            MilestoneStatus::Completed
+        );
+        assert_eq!(status(&resynced, "splitting"), MilestoneStatus::InProgress);
+    }
+
+    #[test]
+    fn transitions_record_timestamps() {
+        let mut tracker = tracker();
+        assert_eq!(tracker.get("inefficiency").unwrap().started_at, Some(at(0)));
+
+        tracker
+            .complete("inefficiency", OutOfOrderPolicy::Reject, at(7))
+            .unwrap();
+        let inefficiency = tracker.get("inefficiency").unwrap();
+        assert_eq!(inefficiency.completed_at, Some(at(7)));
+        assert_eq!(
+            inefficiency.elapsed(at(30)),
+            Some(chrono::Duration::minutes(7))
+        );
+
+        let splitting = tracker.get("splitting").unwrap();
+        assert_eq!(splitting.started_at, Some(at(7)));
+        assert_eq!(splitting.completed_at, None);
+        assert_eq!(
+            splitting.elapsed(at(10)),
+            Some(chrono::Duration::minutes(3))
+        );
+    }
+}
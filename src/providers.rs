@@ -0,0 +1,866 @@
+#![warn(clippy::all)]
+
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::error::ApiError;
+use crate::streaming::{AnthropicStream, OllamaStream, OpenAiStream, ReplyStream};
+use crate::transport::HttpRequest;
+use crate::{MilestoneCall, ToolDefinition, TutorReply, Usage};
+
+const PROXY_URL: &str = "https://dhruvdh-anthropic-s-50.deno.dev/";
+/// Proxies trusted with the student's access token, besides local servers.
+const TRUSTED_PROXY_ORIGINS: &[&str] = &["https://dhruvdh-anthropic-s-50.deno.dev"];
+const ANTHROPIC_URL: &str = "https://api.anthropic.com/v1/messages";
+const ANTHROPIC_VERSION: &str = "2023-06-01";
+const DEFAULT_ANTHROPIC_MODEL: &str = "claude-3-5-sonnet-latest";
+const DEFAULT_OLLAMA_URL: &str = "http://localhost:11434";
+const DEFAULT_OLLAMA_MODEL: &str = "llama3.1";
+
+/// Where tutor requests are sent. API keys are never persisted with the app;
+/// they come back from the environment or config file at startup.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub(crate) enum Provider {
+    /// The course proxy, which holds the API key and identifies students by email.
+    Proxy { url: String },
+    /// The Anthropic Messages API, called directly with a key.
+    Anthropic {
+        #[serde(skip_serializing, default)]
+        api_key: String,
+        model: String,
+    },
+    /// Any server implementing OpenAI's `/chat/completions`.
+    OpenAiCompatible {
+        base_url: String,
+        #[serde(skip_serializing, default)]
+        api_key: Option<String>,
+        model: String,
+    },
+    /// A local Ollama server, through its native `/api/chat`.
+    Ollama { base_url: String, model: String },
+    /// Canned replies from the open lesson's transcript, for demos and tests
+    /// without a network.
+    Scripted,
+}
+
+impl Default for Provider {
+    fn default() -> Self {
+        Self::Proxy {
+            url: PROXY_URL.to_string(),
+        }
+    }
+}
+
+/// One turn of the conversation, before it's put in a provider's format.
+#[derive(Debug, Clone)]
+pub(crate) struct ChatTurn {
+    pub(crate) from_user: bool,
+    pub(crate) content: String,
+    pub(crate) cacheable: bool,
+    /// Kept even when older turns are trimmed to fit the context budget.
+    pub(crate) pinned: bool,
+}
+
+/// A tutor request, before it's put in a provider's format.
+#[derive(Debug, Clone)]
+pub(crate) struct ChatRequest {
+    pub(crate) system: String,
+    pub(crate) messages: Vec<ChatTurn>,
+    pub(crate) tools: Vec<ToolDefinition>,
+    pub(crate) max_tokens: u32,
+    pub(crate) temperature: f32,
+    /// Whether the system prompt (and the tools before it) should be cached.
+    pub(crate) cache_system: bool,
+    /// Identifies the student to the course proxy.
+    pub(crate) email: String,
+    /// The student's Supabase access token, which the course proxy requires
+    /// as a bearer token.
+    pub(crate) access_token: Option<String>,
+}
+
+impl Provider {
+    /// Builds a provider of the given kind (`proxy`, `anthropic`, `openai`,
+    /// `ollama` or `scripted`), using its defaults for anything not given.
+    pub(crate) fn from_parts(
+        kind: &str,
+        base_url: Option<String>,
+        api_key: Option<String>,
+        model: Option<String>,
+    ) -> Result<Self, String> {
+        match kind {
+            "proxy" => Ok(Self::Proxy {
+                url: base_url.unwrap_or_else(|| PROXY_URL.to_string()),
+            }),
+            "anthropic" => Ok(Self::Anthropic {
+                api_key: api_key.ok_or("The anthropic provider needs an API key")?,
+                model: model.unwrap_or_else(|| DEFAULT_ANTHROPIC_MODEL.to_string()),
+            }),
+            "openai" => Ok(Self::OpenAiCompatible {
+                base_url: base_url.ok_or("The openai provider needs a base URL")?,
+                api_key,
+                model: model.ok_or("The openai provider needs a model")?,
+            }),
+            "ollama" => Ok(Self::Ollama {
+                base_url: base_url.unwrap_or_else(|| DEFAULT_OLLAMA_URL.to_string()),
+                model: model.unwrap_or_else(|| DEFAULT_OLLAMA_MODEL.to_string()),
+            }),
+            "scripted" => Ok(Self::Scripted),
+            other => Err(format!("Unknown provider '{}'", other)),
+        }
+    }
+
+    /// Whether requests go to a trusted course proxy, so the student has to
+    /// be signed in to make them. Other proxies are never sent the student's
+    /// access token.
+    pub(crate) fn uses_sign_in(&self) -> bool {
+        matches!(self, Self::Proxy { url } if is_trusted_proxy(url))
+    }
+
+    pub(crate) fn kind(&self) -> &'static str {
+        match self {
+            Self::Proxy { .. } => "proxy",
+            Self::Anthropic { .. } => "anthropic",
+            Self::OpenAiCompatible { .. } => "openai",
+            Self::Ollama { .. } => "ollama",
+            Self::Scripted => "scripted",
+        }
+    }
+
+    /// The configurable endpoint; Anthropic's is fixed and scripted replies
+    /// come from the lesson.
+    pub(crate) fn endpoint(&self) -> Option<&str> {
+        match self {
+            Self::Proxy { url } => Some(url),
+            Self::Anthropic { .. } | Self::Scripted => None,
+            Self::OpenAiCompatible { base_url, .. } | Self::Ollama { base_url, .. } => {
+                Some(base_url)
+            }
+        }
+    }
+
+    pub(crate) fn endpoint_mut(&mut self) -> Option<&mut String> {
+        match self {
+            Self::Proxy { url } => Some(url),
+            Self::Anthropic { .. } | Self::Scripted => None,
+            Self::OpenAiCompatible { base_url, .. } | Self::Ollama { base_url, .. } => {
+                Some(base_url)
+            }
+        }
+    }
+
+    /// The model name; the proxy picks its own.
+    pub(crate) fn model(&self) -> Option<&str> {
+        match self {
+            Self::Proxy { .. } | Self::Scripted => None,
+            Self::Anthropic { model, .. }
+            | Self::OpenAiCompatible { model, .. }
+            | Self::Ollama { model, .. } => Some(model),
+        }
+    }
+
+    pub(crate) fn model_mut(&mut self) -> Option<&mut String> {
+        match self {
+            Self::Proxy { .. } | Self::Scripted => None,
+            Self::Anthropic { model, .. }
+            | Self::OpenAiCompatible { model, .. }
+            | Self::Ollama { model, .. } => Some(model),
+        }
+    }
+
+    pub(crate) fn api_key(&self) -> Option<&str> {
+        match self {
+            Self::Anthropic { api_key, .. } => Some(api_key),
+            Self::OpenAiCompatible { api_key, .. } => api_key.as_deref(),
+            Self::Proxy { .. } | Self::Ollama { .. } | Self::Scripted => None,
+        }
+    }
+
+    /// Puts a request in this provider's wire format.
+    pub(crate) fn http_request(&self, chat: &ChatRequest) -> Result<HttpRequest, ApiError> {
+        let mut headers = vec![("Content-Type".to_string(), "application/json".to_string())];
+
+        let (url, body) = match self {
+            Self::Proxy { url } => {
+                if let Some(token) = chat.access_token.as_ref().filter(|_| self.uses_sign_in()) {
+                    headers.push(("Authorization".to_string(), format!("Bearer {}", token)));
+                }
+                (url.clone(), proxy_body(chat))
+            }
+            // Scripted replies are read from the proxy format, locally
+            Self::Scripted => (String::new(), proxy_body(chat)),
+            Self::Anthropic { api_key, model } => {
+                if api_key.is_empty() {
+                    return Err(ApiError::Config(
+                        "No API key is configured for the anthropic provider".to_string(),
+                    ));
+                }
+                headers.extend([
+                    ("x-api-key".to_string(), api_key.clone()),
+                    (
+                        "anthropic-version".to_string(),
+                        ANTHROPIC_VERSION.to_string(),
+                    ),
+                    (
+                        "anthropic-dangerous-direct-browser-access".to_string(),
+                        "true".to_string(),
+                    ),
+                ]);
+                (ANTHROPIC_URL.to_string(), anthropic_body(chat, model))
+            }
+            Self::OpenAiCompatible {
+                base_url,
+                api_key,
+                model,
+            } => {
+                if let Some(api_key) = api_key {
+                    headers.push(("Authorization".to_string(), format!("Bearer {}", api_key)));
+                }
+                (
+                    format!("{}/chat/completions", base_url.trim_end_matches('/')),
+                    openai_body(chat, model),
+                )
+            }
+            Self::Ollama { base_url, model } => (
+                format!("{}/api/chat", base_url.trim_end_matches('/')),
+                ollama_body(chat, model),
+            ),
+        };
+
+        Ok(HttpRequest {
+            url,
+            headers,
+            body: serde_json::to_string(&body)
+                .map_err(|e| ApiError::Config(format!("Failed to serialize request: {}", e)))?,
+        })
+    }
+
+    /// Parses a complete, non-streamed response body.
+    pub(crate) fn parse_reply(&self, body: &[u8]) -> Result<TutorReply, ApiError> {
+        let reply = match self {
+            Self::Proxy { .. } | Self::Anthropic { .. } | Self::Scripted => {
+                serde_json::from_slice::<AnthropicResponse>(body).map(AnthropicResponse::into_reply)
+            }
+            Self::OpenAiCompatible { .. } => {
+                serde_json::from_slice::<OpenAiResponse>(body).map(OpenAiResponse::into_reply)
+            }
+            Self::Ollama { .. } => {
+                serde_json::from_slice::<OllamaResponse>(body).map(OllamaResponse::into_reply)
+            }
+        };
+        reply.map_err(|e| ApiError::Decode(e.to_string()))
+    }
+
+    /// A decoder for this provider's streamed responses.
+    pub(crate) fn reply_stream(&self) -> ReplyStream {
+        match self {
+            Self::Proxy { .. } | Self::Anthropic { .. } | Self::Scripted => {
+                ReplyStream::Anthropic(AnthropicStream::default())
+            }
+            Self::OpenAiCompatible { .. } => ReplyStream::OpenAi(OpenAiStream::default()),
+            Self::Ollama { .. } => ReplyStream::Ollama(OllamaStream::default()),
+        }
+    }
+}
+
+fn role(turn: &ChatTurn) -> &'static str {
+    if turn.from_user {
+        "user"
+    } else {
+        "assistant"
+    }
+}
+
+// The proxy's own format: Anthropic's, plus the student's email and
+// `cacheable` / `cache_system` flags that it turns into cache breakpoints
+#[derive(Serialize, Deserialize)]
+struct ProxyRequest {
+    messages: Vec<ProxyMessage>,
+    max_tokens: Option<u32>,
+    temperature: Option<f32>,
+    system: String,
+    #[serde(default)]
+    cache_system: bool,
+    email: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    tools: Vec<ToolDefinition>,
+    #[serde(default)]
+    stream: bool,
+}
+
+#[derive(Serialize, Deserialize)]
+struct ProxyMessage {
+    role: String,
+    content: String,
+    cacheable: bool,
+}
+
+fn proxy_body(chat: &ChatRequest) -> Value {
+    json!(ProxyRequest {
+        messages: chat
+            .messages
+            .iter()
+            .map(|turn| ProxyMessage {
+                role: role(turn).to_string(),
+                content: turn.content.clone(),
+                cacheable: turn.cacheable,
+            })
+            .collect(),
+        max_tokens: Some(chat.max_tokens),
+        temperature: Some(chat.temperature),
+        system: chat.system.clone(),
+        cache_system: chat.cache_system,
+        email: chat.email.clone(),
+        tools: chat.tools.clone(),
+        stream: true,
+    })
+}
+
+fn anthropic_body(chat: &ChatRequest, model: &str) -> Value {
+    let messages: Vec<Value> = chat
+        .messages
+        .iter()
+        .map(|turn| {
+            let mut block = json!({ "type": "text", "text": turn.content });
+            if turn.cacheable {
+                block["cache_control"] = json!({ "type": "ephemeral" });
+            }
+            json!({ "role": role(turn), "content": [block] })
+        })
+        .collect();
+
+    let system = if chat.cache_system {
+        json!([{
+            "type": "text",
+            "text": chat.system,
+            "cache_control": { "type": "ephemeral" },
+        }])
+    } else {
+        json!(chat.system)
+    };
+
+    let mut body = json!({
+        "model": model,
+        "max_tokens": chat.max_tokens,
+        "temperature": chat.temperature,
+        "system": system,
+        "messages": messages,
+        "stream": true,
+    });
+    if !chat.tools.is_empty() {
+        body["tools"] = json!(chat.tools);
+    }
+    body
+}
+
+/// Messages in the OpenAI shape, which Ollama shares.
+fn openai_messages(chat: &ChatRequest) -> Vec<Value> {
+    std::iter::once(json!({ "role": "system", "content": chat.system }))
+        .chain(
+            chat.messages
+                .iter()
+                .map(|turn| json!({ "role": role(turn), "content": turn.content })),
+        )
+        .collect()
+}
+
+fn openai_tools(chat: &ChatRequest) -> Vec<Value> {
+    chat.tools
+        .iter()
+        .map(|tool| {
+            json!({
+                "type": "function",
+                "function": {
+                    "name": tool.name,
+                    "description": tool.description,
+                    "parameters": tool.input_schema,
+                },
+            })
+        })
+        .collect()
+}
+
+fn openai_body(chat: &ChatRequest, model: &str) -> Value {
+    let mut body = json!({
+        "model": model,
+        "max_tokens": chat.max_tokens,
+        "temperature": chat.temperature,
+        "messages": openai_messages(chat),
+        "stream": true,
+        // Token counts, including cached prompt tokens, come in a final chunk
+        "stream_options": { "include_usage": true },
+    });
+    if !chat.tools.is_empty() {
+        body["tools"] = json!(openai_tools(chat));
+    }
+    body
+}
+
+fn ollama_body(chat: &ChatRequest, model: &str) -> Value {
+    let mut body = json!({
+        "model": model,
+        "messages": openai_messages(chat),
+        "stream": true,
+        "options": {
+            "temperature": chat.temperature,
+            "num_predict": chat.max_tokens,
+        },
+    });
+    if !chat.tools.is_empty() {
+        body["tools"] = json!(openai_tools(chat));
+    }
+    body
+}
+
+#[derive(Deserialize)]
+struct AnthropicResponse {
+    content: Vec<ContentItem>,
+    usage: Option<Usage>,
+}
+
+#[derive(Deserialize)]
+struct ContentItem {
+    #[serde(default)]
+    text: String,
+    #[serde(rename = "type")]
+    content_type: String,
+    #[serde(default)]
+    name: String,
+    #[serde(default)]
+    input: Value,
+}
+
+impl AnthropicResponse {
+    fn into_reply(self) -> TutorReply {
+        let mut reply = TutorReply {
+            usage: self.usage,
+            ..TutorReply::default()
+        };
+        for item in self.content {
+            match item.content_type.as_str() {
+                "text" => reply.text.push_str(&item.text),
+                "tool_use" => reply
+                    .milestone_calls
+                    .extend(MilestoneCall::from_tool_call(&item.name, item.input)),
+                _ => {}
+            }
+        }
+        reply
+    }
+}
+
+#[derive(Deserialize)]
+struct OpenAiResponse {
+    choices: Vec<OpenAiChoice>,
+    usage: Option<OpenAiUsage>,
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct OpenAiUsage {
+    prompt_tokens: u64,
+    completion_tokens: u64,
+    prompt_tokens_details: Option<OpenAiPromptDetails>,
+}
+
+#[derive(Debug, Deserialize)]
+struct OpenAiPromptDetails {
+    #[serde(default)]
+    cached_tokens: u64,
+}
+
+impl From<OpenAiUsage> for Usage {
+    fn from(usage: OpenAiUsage) -> Self {
+        // OpenAI counts cached tokens as part of the prompt
+        let cached = usage.prompt_tokens_details.map_or(0, |d| d.cached_tokens);
+        Self {
+            input_tokens: usage.prompt_tokens.saturating_sub(cached),
+            output_tokens: usage.completion_tokens,
+            cache_creation_input_tokens: 0,
+            cache_read_input_tokens: cached,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct OpenAiChoice {
+    message: OpenAiMessage,
+}
+
+#[derive(Deserialize)]
+struct OpenAiMessage {
+    content: Option<String>,
+    #[serde(default)]
+    tool_calls: Vec<OpenAiToolCall>,
+}
+
+#[derive(Deserialize)]
+struct OpenAiToolCall {
+    function: OpenAiFunction,
+}
+
+#[derive(Deserialize)]
+struct OpenAiFunction {
+    name: String,
+    /// JSON-encoded, unlike Anthropic and Ollama.
+    arguments: String,
+}
+
+impl OpenAiResponse {
+    fn into_reply(self) -> TutorReply {
+        let mut reply = TutorReply {
+            usage: self.usage.map(Usage::from),
+            ..TutorReply::default()
+        };
+        for choice in self.choices {
+            reply
+                .text
+                .push_str(&choice.message.content.unwrap_or_default());
+            reply
+                .milestone_calls
+                .extend(choice.message.tool_calls.iter().filter_map(|call| {
+                    MilestoneCall::from_json_arguments(
+                        &call.function.name,
+                        &call.function.arguments,
+                    )
+                }));
+        }
+        reply
+    }
+}
+
+#[derive(Deserialize)]
+struct OllamaResponse {
+    message: OllamaMessage,
+    #[serde(flatten)]
+    counts: OllamaCounts,
+}
+
+/// Token counts Ollama adds to its final message.
+#[derive(Debug, Default, Deserialize)]
+pub(crate) struct OllamaCounts {
+    prompt_eval_count: Option<u64>,
+    eval_count: Option<u64>,
+}
+
+impl OllamaCounts {
+    pub(crate) fn usage(&self) -> Option<Usage> {
+        Some(Usage {
+            input_tokens: self.prompt_eval_count?,
+            output_tokens: self.eval_count.unwrap_or_default(),
+            ..Usage::default()
+        })
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct OllamaMessage {
+    #[serde(default)]
+    pub(crate) content: String,
+    #[serde(default)]
+    tool_calls: Vec<OllamaToolCall>,
+}
+
+#[derive(Debug, Deserialize)]
+struct OllamaToolCall {
+    function: OllamaFunction,
+}
+
+#[derive(Debug, Deserialize)]
+struct OllamaFunction {
+    name: String,
+    arguments: Value,
+}
+
+impl OllamaMessage {
+    pub(crate) fn into_milestone_calls(self) -> Vec<MilestoneCall> {
+        self.tool_calls
+            .into_iter()
+            .filter_map(|call| {
+                MilestoneCall::from_tool_call(&call.function.name, call.function.arguments)
+            })
+            .collect()
+    }
+}
+
+impl OllamaResponse {
+    fn into_reply(self) -> TutorReply {
+        TutorReply {
+            text: self.message.content.clone(),
+            milestone_calls: self.message.into_milestone_calls(),
+            usage: self.counts.usage(),
+        }
+    }
+}
+
+/// Whether `url` is one of [`TRUSTED_PROXY_ORIGINS`] or a server on the
+/// student's own machine, such as the stub server.
+fn is_trusted_proxy(url: &str) -> bool {
+    let Some((scheme, rest)) = url.split_once("://") else {
+        return false;
+    };
+    let authority = rest
+        .split(['/', '?', '#'])
+        .next()
+        .unwrap_or_default()
+        .to_ascii_lowercase();
+    // Credentials in the URL could hide the real host
+    if authority.contains(['@', '\\']) {
+        return false;
+    }
+
+    let origin = format!("{}://{}", scheme.to_ascii_lowercase(), authority);
+    if TRUSTED_PROXY_ORIGINS.contains(&origin.as_str()) {
+        return true;
+    }
+    let host = authority
+        .rsplit_once(':')
+        .filter(|(_, port)| !port.is_empty() && port.chars().all(|c| c.is_ascii_digit()))
+        .map_or(authority.as_str(), |(host, _)| host);
+    matches!(host, "localhost" | "127.0.0.1" | "[::1]")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chat() -> ChatRequest {
+        ChatRequest {
+            system: "You are a patient tutor.".to_string(),
+            messages: vec![
+                ChatTurn {
+                    from_user: false,
+                    content: "Let's sort [7, 2, 4, 1].".to_string(),
+                    cacheable: true,
+                    pinned: false,
+                },
+                ChatTurn {
+                    from_user: true,
+                    content: "Split it in half?".to_string(),
+                    cacheable: false,
+                    pinned: false,
+                },
+            ],
+            tools: vec![ToolDefinition {
+                name: "mark_milestone".to_string(),
+                description: "Record a milestone.".to_string(),
+                input_schema: json!({ "type": "object" }),
+            }],
+            max_tokens: 512,
+            temperature: 0.0,
+            cache_system: true,
+            email: "student@uni.edu".to_string(),
+            access_token: Some("student-jwt".to_string()),
+        }
+    }
+
+    fn body(request: &HttpRequest) -> Value {
+        serde_json::from_str(&request.body).unwrap()
+    }
+
+    fn header<'a>(request: &'a HttpRequest, name: &str) -> Option<&'a str> {
+        request
+            .headers
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    #[test]
+    fn proxy_request_keeps_the_proxy_format() {
+        let request = Provider::default().http_request(&chat()).unwrap();
+        let body = body(&request);
+
+        assert_eq!(request.url, PROXY_URL);
+        assert_eq!(
+            header(&request, "Authorization"),
+            Some("Bearer student-jwt")
+        );
+        assert_eq!(body["email"], "student@uni.edu");
+        assert_eq!(body["system"], "You are a patient tutor.");
+        assert_eq!(body["cache_system"], true);
+        assert_eq!(body["messages"][0]["cacheable"], true);
+        assert_eq!(body["tools"][0]["input_schema"]["type"], "object");
+    }
+
+    #[test]
+    fn access_token_is_only_sent_to_trusted_proxies() {
+        let token_sent_to = |url: &str| {
+            let provider = Provider::Proxy {
+                url: url.to_string(),
+            };
+            let request = provider.http_request(&chat()).unwrap();
+            assert_eq!(
+                provider.uses_sign_in(),
+                header(&request, "Authorization").is_some()
+            );
+            provider.uses_sign_in()
+        };
+
+        assert!(token_sent_to(
+            "https://dhruvdh-anthropic-s-50.deno.dev/chat"
+        ));
+        assert!(token_sent_to("HTTPS://DHRUVDH-ANTHROPIC-S-50.deno.dev"));
+        assert!(token_sent_to("http://127.0.0.1:54321/"));
+        assert!(token_sent_to("http://localhost:8080/"));
+        assert!(token_sent_to("http://[::1]:8080/"));
+
+        assert!(!token_sent_to("https://evil.example/"));
+        assert!(!token_sent_to("http://dhruvdh-anthropic-s-50.deno.dev/"));
+        assert!(!token_sent_to(
+            "https://dhruvdh-anthropic-s-50.deno.dev.evil.example/"
+        ));
+        assert!(!token_sent_to(
+            "https://dhruvdh-anthropic-s-50.deno.dev@evil.example/"
+        ));
+        assert!(!token_sent_to(
+            "https://evil.example/?https://dhruvdh-anthropic-s-50.deno.dev"
+        ));
+        assert!(!token_sent_to("https://localhost.evil.example/"));
+        assert!(!token_sent_to("dhruvdh-anthropic-s-50.deno.dev"));
+    }
+
+    #[test]
+    fn anthropic_request_uses_cache_control_and_key() {
+        let provider = Provider::Anthropic {
+            api_key: "sk-ant-test".to_string(),
+            model: "claude-test".to_string(),
+        };
+        let request = provider.http_request(&chat()).unwrap();
+        let body = body(&request);
+
+        assert_eq!(request.url, ANTHROPIC_URL);
+        assert_eq!(header(&request, "x-api-key"), Some("sk-ant-test"));
+        // The student's token is only for the course proxy
+        assert_eq!(header(&request, "Authorization"), None);
+        assert_eq!(body["model"], "claude-test");
+        assert_eq!(
+            body["messages"][0]["content"][0]["cache_control"]["type"],
+            "ephemeral"
+        );
+        assert!(body["messages"][1]["content"][0]
+            .get("cache_control")
+            .is_none());
+        assert_eq!(body["system"][0]["text"], "You are a patient tutor.");
+        assert_eq!(body["system"][0]["cache_control"]["type"], "ephemeral");
+        assert!(body.get("email").is_none());
+    }
+
+    #[test]
+    fn openai_request_puts_system_prompt_first() {
+        let provider = Provider::OpenAiCompatible {
+            base_url: "https://llm.cs.uni.edu/v1/".to_string(),
+            api_key: Some("key".to_string()),
+            model: "gpt-test".to_string(),
+        };
+        let request = provider.http_request(&chat()).unwrap();
+        let body = body(&request);
+
+        assert_eq!(request.url, "https://llm.cs.uni.edu/v1/chat/completions");
+        assert_eq!(header(&request, "Authorization"), Some("Bearer key"));
+        assert_eq!(body["messages"][0]["role"], "system");
+        assert_eq!(body["messages"][1]["role"], "assistant");
+        assert_eq!(body["messages"][2]["content"], "Split it in half?");
+        assert_eq!(body["tools"][0]["function"]["name"], "mark_milestone");
+    }
+
+    #[test]
+    fn ollama_request_uses_native_chat_api() {
+        let provider = Provider::Ollama {
+            base_url: DEFAULT_OLLAMA_URL.to_string(),
+            model: "llama-test".to_string(),
+        };
+        let request = provider.http_request(&chat()).unwrap();
+        let body = body(&request);
+
+        assert_eq!(request.url, "http://localhost:11434/api/chat");
+        assert_eq!(header(&request, "Authorization"), None);
+        assert_eq!(body["options"]["num_predict"], 512);
+        assert_eq!(body["messages"].as_array().unwrap().len(), 3);
+    }
+
+    #[test]
+    fn saved_scripted_provider_still_loads() {
+        // Scripted providers used to be saved with a transcript path
+        let provider: Provider =
+            serde_json::from_str(r#"{ "kind": "scripted", "transcript": "transcript.json" }"#)
+                .unwrap();
+        assert_eq!(provider, Provider::Scripted);
+        assert_eq!(provider.endpoint(), None);
+    }
+
+    #[test]
+    fn anthropic_reply_collects_text_and_milestone_calls() {
+        let body = r#"{
+            "content": [
+                {"type": "text", "text": "Exactly - splitting in half is the key idea. "},
+                {"type": "text", "text": "What happens to each half next?"},
+                {
+                    "type": "tool_use",
+                    "id": "toolu_01",
+                    "name": "mark_milestone",
+                    "input": {
+                        "milestone_id": "splitting_insight",
+                        "justification": "Suggested sorting [7, 2] and [4, 1] separately."
+                    }
+                },
+                {"type": "tool_use", "id": "toolu_02", "name": "mark_milestone", "input": {}},
+                {"type": "tool_use", "id": "toolu_03", "name": "other_tool", "input": {}}
+            ]
+        }"#;
+
+        let reply = Provider::default().parse_reply(body.as_bytes()).unwrap();
+        assert_eq!(
+            reply.text,
+            "Exactly - splitting in half is the key idea. What happens to each half next?"
+        );
+        assert_eq!(
+            reply.milestone_calls,
+            vec![MilestoneCall {
+                milestone_id: "splitting_insight".to_string(),
+                justification: "Suggested sorting [7, 2] and [4, 1] separately.".to_string(),
+            }]
+        );
+    }
+
+    #[test]
+    fn openai_reply_decodes_json_arguments() {
+        let body = r#"{
+            "choices": [{
+                "message": {
+                    "role": "assistant",
+                    "content": "Right, log n levels.",
+                    "tool_calls": [{
+                        "id": "call_1",
+                        "type": "function",
+                        "function": {
+                            "name": "mark_milestone",
+                            "arguments": "{\"milestone_id\": \"efficiency_analysis\", \"justification\": \"Counted log n levels.\"}"
+                        }
+                    }]
+                }
+            }]
+        }"#;
+        let provider = Provider::OpenAiCompatible {
+            base_url: "http://localhost:8000/v1".to_string(),
+            api_key: None,
+            model: "local".to_string(),
+        };
+
+        let reply = provider.parse_reply(body.as_bytes()).unwrap();
+        assert_eq!(reply.text, "Right, log n levels.");
+        assert_eq!(reply.milestone_calls[0].milestone_id, "efficiency_analysis");
+    }
+
+    #[test]
+    fn ollama_reply_reads_message() {
+        let body = r#"{"model": "llama3.1", "message": {"role": "assistant", "content": "Try [3, 1]."}, "done": true}"#;
+        let provider = Provider::Ollama {
+            base_url: DEFAULT_OLLAMA_URL.to_string(),
+            model: DEFAULT_OLLAMA_MODEL.to_string(),
+        };
+
+        let reply = provider.parse_reply(body.as_bytes()).unwrap();
+        assert_eq!(reply.text, "Try [3, 1].");
+        assert!(reply.milestone_calls.is_empty());
+    }
+}
@@ -0,0 +1,108 @@
+#![warn(clippy::all)]
+
+use std::time::Duration;
+
+/// How failed tutor requests are retried.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct RetryPolicy {
+    pub(crate) max_retries: u32,
+    /// Wait before the first retry, doubled for each one after.
+    pub(crate) base_delay: Duration,
+    pub(crate) max_delay: Duration,
+    /// Longest `Retry-After` worth waiting for; a server asking for more
+    /// gets the error shown instead.
+    pub(crate) max_retry_after: Duration,
+}
+
+pub(crate) const RETRY_POLICY: RetryPolicy = RetryPolicy {
+    max_retries: 4,
+    base_delay: Duration::from_secs(1),
+    max_delay: Duration::from_secs(16),
+    max_retry_after: Duration::from_secs(60),
+};
+
+impl RetryPolicy {
+    /// The wait before retry number `retry` (counting from 1), or `None` if
+    /// it shouldn't be attempted. `jitter` is a random number in `[0, 1)`
+    /// that spreads the wait over its upper half, so clients that failed
+    /// together don't retry together.
+    pub(crate) fn delay(
+        &self,
+        retry: u32,
+        retry_after: Option<Duration>,
+        jitter: f64,
+    ) -> Option<Duration> {
+        if retry > self.max_retries {
+            return None;
+        }
+        let backoff = self
+            .base_delay
+            .saturating_mul(1 << (retry - 1).min(16))
+            .min(self.max_delay)
+            .mul_f64(0.5 + jitter / 2.0);
+
+        match retry_after {
+            Some(wait) if wait > self.max_retry_after => None,
+            Some(wait) => Some(wait.max(backoff)),
+            None => Some(backoff),
+        }
+    }
+}
+
+/// Reads a `Retry-After` header, given either in seconds or as an HTTP date.
+pub(crate) fn parse_retry_after(value: &str) -> Option<Duration> {
+    let value = value.trim();
+    if let Ok(seconds) = value.parse::<u64>() {
+        return Some(Duration::from_secs(seconds));
+    }
+    let date = chrono::DateTime::parse_from_rfc2822(value).ok()?;
+    Some(
+        (date.with_timezone(&chrono::Utc) - chrono::Utc::now())
+            .to_std()
+            .unwrap_or_default(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backoff_doubles_up_to_the_cap() {
+        let delays: Vec<_> = (1..=5)
+            .map(|retry| RETRY_POLICY.delay(retry, None, 1.0))
+            .collect();
+        assert_eq!(
+            delays,
+            vec![
+                Some(Duration::from_secs(1)),
+                Some(Duration::from_secs(2)),
+                Some(Duration::from_secs(4)),
+                Some(Duration::from_secs(8)),
+                None,
+            ]
+        );
+        assert_eq!(
+            RETRY_POLICY.delay(1, None, 0.0),
+            Some(Duration::from_millis(500))
+        );
+    }
+
+    #[test]
+    fn retry_after_is_honoured_within_reason() {
+        let wait = |seconds| Some(Duration::from_secs(seconds));
+        assert_eq!(RETRY_POLICY.delay(1, wait(10), 0.0), wait(10));
+        assert_eq!(RETRY_POLICY.delay(4, wait(0), 1.0), wait(8));
+        assert_eq!(RETRY_POLICY.delay(1, wait(120), 0.0), None);
+    }
+
+    #[test]
+    fn retry_after_parses_seconds_and_dates() {
+        assert_eq!(parse_retry_after("30"), Some(Duration::from_secs(30)));
+        assert_eq!(
+            parse_retry_after("Wed, 21 Oct 2015 07:28:00 GMT"),
+            Some(Duration::ZERO)
+        );
+        assert_eq!(parse_retry_after("soon"), None);
+    }
+}
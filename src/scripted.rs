@@ -0,0 +1,281 @@
+#![warn(clippy::all)]
+
+use regex::Regex;
+use serde::Deserialize;
+use std::collections::VecDeque;
+
+use crate::error::ApiError;
+use crate::lesson::LessonPack;
+use crate::markers;
+use crate::metadata;
+use crate::transport::{BoxFuture, HttpRequest, HttpResponse, ResponseBody, Transport};
+
+/// A transcript file: replies tried in order against the student's latest
+/// message, and what to say when none matches.
+#[derive(Deserialize)]
+struct TranscriptFile {
+    replies: Vec<ScriptedReply>,
+    fallback: String,
+}
+
+#[derive(Deserialize)]
+struct ScriptedReply {
+    /// A regex matched anywhere in the message; start it with `(?i)` to
+    /// ignore case.
+    pattern: String,
+    /// Sent as the tutor's reply, `MILESTONE[id]` markers included.
+    reply: String,
+}
+
+/// Canned tutor replies, for demos and tests without a model provider.
+#[derive(Debug, Clone)]
+pub(crate) struct Transcript {
+    replies: Vec<(Regex, String)>,
+    fallback: String,
+}
+
+impl Transcript {
+    pub(crate) fn parse(text: &str) -> Result<Self, String> {
+        let file: TranscriptFile =
+            serde_json::from_str(text).map_err(|e| format!("Invalid transcript: {}", e))?;
+        let replies = file
+            .replies
+            .into_iter()
+            .map(|entry| {
+                Regex::new(&entry.pattern)
+                    .map(|pattern| (pattern, entry.reply))
+                    .map_err(|e| format!("Invalid pattern '{}': {}", entry.pattern, e))
+            })
+            .collect::<Result<_, _>>()?;
+
+        Ok(Self {
+            replies,
+            fallback: file.fallback,
+        })
+    }
+
+    /// Checks that every marker in the replies names one of `known_ids`, so a
+    /// transcript can't claim milestones its lesson doesn't have.
+    pub(crate) fn check_markers(&self, known_ids: &[&str]) -> Result<(), String> {
+        let replies = self.replies.iter().map(|(_, reply)| reply);
+        for (idx, reply) in replies.chain([&self.fallback]).enumerate() {
+            if let Some(diagnostic) = markers::scan(reply, known_ids).diagnostics.first() {
+                return Err(format!("transcript reply {}: {}", idx + 1, diagnostic));
+            }
+        }
+        Ok(())
+    }
+
+    /// The reply to the first pattern `message` matches.
+    pub(crate) fn reply_to(&self, message: &str) -> &str {
+        let message = metadata::strip_block(message);
+        self.replies
+            .iter()
+            .find(|(pattern, _)| pattern.is_match(message))
+            .map_or(&self.fallback, |(_, reply)| reply)
+    }
+
+    /// Answers a request body in the proxy format.
+    pub(crate) fn respond(&self, body: &str) -> Result<&str, String> {
+        #[derive(Deserialize)]
+        struct Body {
+            messages: Vec<Message>,
+        }
+        #[derive(Deserialize)]
+        struct Message {
+            role: String,
+            content: String,
+        }
+
+        let body: Body =
+            serde_json::from_str(body).map_err(|e| format!("Invalid request: {}", e))?;
+        let message = body
+            .messages
+            .iter()
+            .rev()
+            .find(|message| message.role == "user")
+            .ok_or("The request has no student message")?;
+        Ok(self.reply_to(&message.content))
+    }
+}
+
+/// `text` as an Anthropic Messages stream, one event per word.
+pub(crate) fn reply_events(text: &str) -> Vec<String> {
+    let event =
+        |name: &str, data: serde_json::Value| format!("event: {}\ndata: {}\n\n", name, data);
+
+    let mut events = vec![
+        event(
+            "message_start",
+            serde_json::json!({ "type": "message_start", "message": { "content": [] } }),
+        ),
+        event(
+            "content_block_start",
+            serde_json::json!({
+                "type": "content_block_start",
+                "index": 0,
+                "content_block": { "type": "text", "text": "" },
+            }),
+        ),
+    ];
+    events.extend(text.split_inclusive(' ').map(|word| {
+        event(
+            "content_block_delta",
+            serde_json::json!({
+                "type": "content_block_delta",
+                "index": 0,
+                "delta": { "type": "text_delta", "text": word },
+            }),
+        )
+    }));
+    events.push(event(
+        "content_block_stop",
+        serde_json::json!({ "type": "content_block_stop", "index": 0 }),
+    ));
+    events.push(event(
+        "message_stop",
+        serde_json::json!({ "type": "message_stop" }),
+    ));
+    events
+}
+
+fn error_event(message: &str) -> String {
+    let data = serde_json::json!({
+        "type": "error",
+        "error": { "type": "invalid_request_error", "message": message },
+    });
+    format!("event: error\ndata: {}\n\n", data)
+}
+
+/// Answers tutor requests from the open lesson's transcript, without going
+/// over the network. A request the transcript can't answer is reported as a
+/// provider error rather than a failed request, so it isn't retried.
+pub(crate) struct ScriptedTransport {
+    transcript: Transcript,
+}
+
+impl ScriptedTransport {
+    /// Answers from `pack`'s transcript; lessons without one can't be scripted.
+    pub(crate) fn for_lesson(pack: Option<&LessonPack>) -> Result<Self, ApiError> {
+        let pack = pack.ok_or_else(|| {
+            ApiError::Config("no lesson is open for the scripted tutor".to_string())
+        })?;
+        let transcript = pack.transcript.clone().ok_or_else(|| {
+            ApiError::Config(format!(
+                "the lesson \"{}\" has no transcript for the scripted tutor",
+                pack.manifest.title
+            ))
+        })?;
+        Ok(Self { transcript })
+    }
+}
+
+impl Transport for ScriptedTransport {
+    fn post(&self, request: HttpRequest) -> BoxFuture<'_, Result<HttpResponse, String>> {
+        Box::pin(async move {
+            let events = match self.transcript.respond(&request.body) {
+                Ok(reply) => reply_events(reply),
+                Err(e) => vec![error_event(&e)],
+            };
+
+            Ok(HttpResponse {
+                status: 200,
+                content_type: Some("text/event-stream".to_string()),
+                retry_after: None,
+                body: Box::new(ScriptedBody(
+                    events.into_iter().map(String::into_bytes).collect(),
+                )),
+            })
+        })
+    }
+}
+
+struct ScriptedBody(VecDeque<Vec<u8>>);
+
+impl ResponseBody for ScriptedBody {
+    fn next_chunk(&mut self) -> BoxFuture<'_, Result<Option<Vec<u8>>, String>> {
+        let chunk = self.0.pop_front();
+        Box::pin(async move { Ok(chunk) })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TRANSCRIPT: &str = r#"{
+        "replies": [
+            { "pattern": "(?i)split", "reply": "What made you think of splitting? MILESTONE[splitting_insight]" },
+            { "pattern": "\\d+ comparisons", "reply": "Predict what happens with 7 numbers." }
+        ],
+        "fallback": "Tell me more about your idea."
+    }"#;
+
+    #[test]
+    fn first_matching_pattern_wins() {
+        let transcript = Transcript::parse(TRANSCRIPT).unwrap();
+
+        assert_eq!(
+            transcript.reply_to("What if we SPLIT it? That's 6 comparisons"),
+            "What made you think of splitting? MILESTONE[splitting_insight]"
+        );
+        assert_eq!(
+            transcript.reply_to("That's 10 comparisons"),
+            "Predict what happens with 7 numbers."
+        );
+        assert_eq!(
+            transcript.reply_to("<turn_metadata>\nsplit\n</turn_metadata>\n\nI'm not sure"),
+            "Tell me more about your idea."
+        );
+    }
+
+    #[test]
+    fn invalid_transcripts_are_rejected() {
+        let error = Transcript::parse(
+            r#"{ "replies": [{ "pattern": "(", "reply": "" }], "fallback": "" }"#,
+        )
+        .unwrap_err();
+        assert!(error.starts_with("Invalid pattern '('"), "{}", error);
+        assert!(Transcript::parse("not json").is_err());
+    }
+
+    #[test]
+    fn markers_must_name_known_milestones() {
+        let transcript = Transcript::parse(TRANSCRIPT).unwrap();
+        assert!(transcript.check_markers(&["splitting_insight"]).is_ok());
+
+        let error = transcript.check_markers(&["pivot_choice"]).unwrap_err();
+        assert_eq!(
+            error,
+            "transcript reply 1: line 1: unknown milestone 'splitting_insight'"
+        );
+    }
+
+    #[test]
+    fn lessons_without_a_transcript_cant_be_scripted() {
+        let catalog = crate::lesson::LessonCatalog::builtin();
+        assert!(ScriptedTransport::for_lesson(catalog.get("mergesort")).is_ok());
+
+        let error = ScriptedTransport::for_lesson(catalog.get("binary-search"))
+            .err()
+            .unwrap();
+        assert!(
+            matches!(&error, ApiError::Config(message) if message.contains("has no transcript")),
+            "{:?}",
+            error
+        );
+        assert!(ScriptedTransport::for_lesson(None).is_err());
+    }
+
+    #[test]
+    fn bundled_transcript_is_valid() {
+        let transcript =
+            Transcript::parse(include_str!("../lessons/mergesort/transcript.json")).unwrap();
+        assert!(transcript
+            .reply_to("With 5 numbers that's 4+3+2+1 comparisons")
+            .ends_with("MILESTONE[inefficiency_discovery]"));
+        assert!(!transcript
+            .reply_to("I'd compare every pair")
+            .contains("MILESTONE["));
+    }
+}
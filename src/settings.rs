@@ -0,0 +1,419 @@
+#![warn(clippy::all)]
+
+use serde::{Deserialize, Serialize};
+
+use crate::context::ContextStrategy;
+use crate::providers::Provider;
+
+const DEFAULT_MAX_TOKENS: u32 = 2048;
+const DEFAULT_TEMPERATURE: f32 = 0.0;
+const DEFAULT_CONTEXT_BUDGET: u32 = 32_000;
+#[cfg(not(target_arch = "wasm32"))]
+const DEFAULT_CONFIG_FILE: &str = "tutor.json";
+
+/// How tutor requests are made. Persisted with the app, so changes made in the
+/// settings panel survive a restart.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub(crate) struct Settings {
+    pub(crate) provider: Provider,
+    pub(crate) max_tokens: u32,
+    pub(crate) temperature: f32,
+    /// Estimated tokens a request may use before older turns are trimmed.
+    pub(crate) context_budget: u32,
+    pub(crate) context_strategy: ContextStrategy,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            provider: Provider::default(),
+            max_tokens: DEFAULT_MAX_TOKENS,
+            temperature: DEFAULT_TEMPERATURE,
+            context_budget: DEFAULT_CONTEXT_BUDGET,
+            context_strategy: ContextStrategy::default(),
+        }
+    }
+}
+
+/// Settings given by the deployment, which replace the persisted ones at
+/// startup. Anything left out keeps its persisted value.
+#[derive(Debug, Default, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct SettingsOverrides {
+    /// `proxy`, `anthropic`, `openai`, `ollama` or `scripted`.
+    pub(crate) provider: Option<String>,
+    pub(crate) base_url: Option<String>,
+    pub(crate) api_key: Option<String>,
+    pub(crate) model: Option<String>,
+    pub(crate) max_tokens: Option<u32>,
+    pub(crate) temperature: Option<f32>,
+    pub(crate) context_budget: Option<u32>,
+    pub(crate) context_strategy: Option<ContextStrategy>,
+}
+
+impl SettingsOverrides {
+    /// Reads each setting through `var`, by its field name.
+    fn from_vars(var: impl Fn(&str) -> Option<String>) -> Result<Self, String> {
+        let tokens = |name: &str| match var(name) {
+            Some(value) => value
+                .parse()
+                .ok()
+                .filter(|tokens| *tokens > 0)
+                .map(Some)
+                .ok_or_else(|| format!("Invalid {} '{}'", name, value)),
+            None => Ok(None),
+        };
+        let max_tokens = tokens("max_tokens")?;
+        let context_budget = tokens("context_budget")?;
+        let temperature = match var("temperature") {
+            Some(value) => Some(
+                value
+                    .parse()
+                    .ok()
+                    .filter(|temperature: &f32| (0.0..=2.0).contains(temperature))
+                    .ok_or_else(|| format!("Invalid temperature '{}'", value))?,
+            ),
+            None => None,
+        };
+        let context_strategy = match var("context_strategy") {
+            Some(value) => Some(
+                ContextStrategy::parse(&value)
+                    .ok_or_else(|| format!("Invalid context_strategy '{}'", value))?,
+            ),
+            None => None,
+        };
+
+        Ok(Self {
+            provider: var("provider"),
+            base_url: var("base_url"),
+            api_key: var("api_key"),
+            model: var("model"),
+            max_tokens,
+            temperature,
+            context_budget,
+            context_strategy,
+        })
+    }
+
+    /// Combines two sources, preferring `other` where both set a value.
+    #[cfg(any(not(target_arch = "wasm32"), test))]
+    fn or(self, other: Self) -> Self {
+        Self {
+            provider: other.provider.or(self.provider),
+            base_url: other.base_url.or(self.base_url),
+            api_key: other.api_key.or(self.api_key),
+            model: other.model.or(self.model),
+            max_tokens: other.max_tokens.or(self.max_tokens),
+            temperature: other.temperature.or(self.temperature),
+            context_budget: other.context_budget.or(self.context_budget),
+            context_strategy: other.context_strategy.or(self.context_strategy),
+        }
+    }
+
+    /// Reads the config file named by `TUTOR_CONFIG` (or `tutor.json`, if it
+    /// exists), then `LLM_PROVIDER`, `LLM_BASE_URL`, `LLM_API_KEY`,
+    /// `LLM_MODEL`, `LLM_MAX_TOKENS`, `LLM_TEMPERATURE`, `LLM_CONTEXT_BUDGET`
+    /// and `LLM_CONTEXT_STRATEGY`, which win over the file.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub(crate) fn load() -> Result<Self, String> {
+        dotenvy::dotenv().ok();
+        let env = |name: &str| std::env::var(name).ok().filter(|value| !value.is_empty());
+
+        let file = match env("TUTOR_CONFIG") {
+            Some(path) => Some(path),
+            None => std::path::Path::new(DEFAULT_CONFIG_FILE)
+                .exists()
+                .then(|| DEFAULT_CONFIG_FILE.to_string()),
+        };
+        let from_file = match file {
+            Some(path) => {
+                let text = std::fs::read_to_string(&path)
+                    .map_err(|e| format!("Failed to read {}: {}", path, e))?;
+                serde_json::from_str(&text)
+                    .map_err(|e| format!("Failed to parse {}: {}", path, e))?
+            }
+            None => Self::default(),
+        };
+
+        let from_env = Self::from_vars(|name| env(&format!("LLM_{}", name.to_ascii_uppercase())))?;
+        Ok(from_file.or(from_env))
+    }
+
+    /// Reads the `provider`, `base_url` (for Ollama only), `model`,
+    /// `max_tokens`, `temperature`, `context_budget` and `context_strategy`
+    /// query parameters.
+    #[cfg(target_arch = "wasm32")]
+    pub(crate) fn load() -> Result<Self, String> {
+        let search = web_sys::window()
+            .and_then(|window| window.location().search().ok())
+            .unwrap_or_default();
+
+        Self::from_query(|name| {
+            crate::lesson::query_param(&search, name)
+                .map(|value| {
+                    web_sys::js_sys::decode_uri_component(&value)
+                        .map(String::from)
+                        .unwrap_or(value)
+                })
+                .filter(|value| !value.is_empty())
+        })
+    }
+
+    /// Overrides from a link, which anyone could have crafted. API keys are
+    /// never taken from it. Nor is the endpoint of any provider that's sent
+    /// credentials: the proxy gets the student's email and sign-in, and an
+    /// OpenAI-compatible server gets the API key typed into the settings
+    /// panel. Only a self-hosted Ollama server can be picked from a link.
+    #[cfg(any(target_arch = "wasm32", test))]
+    fn from_query(param: impl Fn(&str) -> Option<String>) -> Result<Self, String> {
+        let mut overrides = Self::from_vars(param)?;
+        overrides.api_key = None;
+        if overrides.provider.as_deref() != Some("ollama") {
+            overrides.base_url = None;
+        }
+        Ok(overrides)
+    }
+}
+
+impl Settings {
+    /// Applies overrides, leaving the settings untouched if they're invalid.
+    pub(crate) fn apply(&mut self, overrides: SettingsOverrides) -> Result<(), String> {
+        let SettingsOverrides {
+            provider,
+            base_url,
+            api_key,
+            model,
+            max_tokens,
+            temperature,
+            context_budget,
+            context_strategy,
+        } = overrides;
+
+        if provider.is_some() || base_url.is_some() || api_key.is_some() || model.is_some() {
+            let kind = provider.as_deref().unwrap_or(self.provider.kind());
+            // Switching provider starts from its defaults rather than
+            // carrying over the old one's endpoint and model
+            let current = (kind == self.provider.kind()).then_some(&self.provider);
+            let endpoint = current.and_then(|p| p.endpoint());
+            // A key is only sent on to the endpoint it was given for
+            let moved = base_url.as_deref().is_some_and(|url| Some(url) != endpoint);
+            let base_url = base_url.or_else(|| endpoint.map(str::to_string));
+            let model = model.or_else(|| current.and_then(|p| p.model()).map(str::to_string));
+            let api_key = api_key.or_else(|| {
+                current
+                    .filter(|_| !moved)
+                    .and_then(|p| p.api_key())
+                    .filter(|key| !key.is_empty())
+                    .map(str::to_string)
+            });
+            self.provider = Provider::from_parts(kind, base_url, api_key, model)?;
+        }
+        if let Some(max_tokens) = max_tokens {
+            self.max_tokens = max_tokens;
+        }
+        if let Some(temperature) = temperature {
+            self.temperature = temperature;
+        }
+        if let Some(context_budget) = context_budget {
+            self.context_budget = context_budget;
+        }
+        if let Some(context_strategy) = context_strategy {
+            self.context_strategy = context_strategy;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vars<'a>(pairs: &'a [(&'a str, &'a str)]) -> impl Fn(&str) -> Option<String> + 'a {
+        move |name| {
+            pairs
+                .iter()
+                .find(|(key, _)| *key == name)
+                .map(|(_, value)| value.to_string())
+        }
+    }
+
+    #[test]
+    fn overrides_replace_only_what_they_name() {
+        let mut settings = Settings {
+            provider: Provider::Ollama {
+                base_url: "http://gpu-box:11434".to_string(),
+                model: "llama3.1".to_string(),
+            },
+            max_tokens: 1024,
+            temperature: 0.3,
+            ..Settings::default()
+        };
+        let overrides =
+            SettingsOverrides::from_vars(vars(&[("model", "qwen2.5"), ("temperature", "0.7")]))
+                .unwrap();
+        settings.apply(overrides).unwrap();
+
+        assert_eq!(
+            settings,
+            Settings {
+                provider: Provider::Ollama {
+                    base_url: "http://gpu-box:11434".to_string(),
+                    model: "qwen2.5".to_string(),
+                },
+                max_tokens: 1024,
+                temperature: 0.7,
+                ..Settings::default()
+            }
+        );
+    }
+
+    #[test]
+    fn switching_provider_starts_from_its_defaults() {
+        let mut settings = Settings::default();
+        let overrides = SettingsOverrides::from_vars(vars(&[("provider", "ollama")])).unwrap();
+        settings.apply(overrides).unwrap();
+
+        assert_eq!(
+            settings.provider,
+            Provider::Ollama {
+                base_url: "http://localhost:11434".to_string(),
+                model: "llama3.1".to_string(),
+            }
+        );
+    }
+
+    #[test]
+    fn later_sources_win() {
+        let file: SettingsOverrides =
+            serde_json::from_str(r#"{ "provider": "ollama", "max_tokens": 512 }"#).unwrap();
+        let env = SettingsOverrides::from_vars(vars(&[
+            ("max_tokens", "4096"),
+            ("context_strategy", "drop"),
+        ]))
+        .unwrap();
+        let merged = file.or(env);
+
+        assert_eq!(merged.provider.as_deref(), Some("ollama"));
+        assert_eq!(merged.max_tokens, Some(4096));
+        assert_eq!(merged.context_strategy, Some(ContextStrategy::Drop));
+    }
+
+    #[test]
+    fn invalid_overrides_are_rejected() {
+        assert!(SettingsOverrides::from_vars(vars(&[("temperature", "hot")])).is_err());
+        assert!(SettingsOverrides::from_vars(vars(&[("temperature", "5")])).is_err());
+        assert!(SettingsOverrides::from_vars(vars(&[("max_tokens", "0")])).is_err());
+        assert!(SettingsOverrides::from_vars(vars(&[("context_strategy", "forget")])).is_err());
+
+        let mut settings = Settings::default();
+        let overrides = SettingsOverrides::from_vars(vars(&[
+            ("provider", "anthropic"),
+            ("temperature", "1.0"),
+        ]))
+        .unwrap();
+        assert!(settings.apply(overrides).is_err());
+        assert_eq!(settings, Settings::default());
+    }
+
+    #[test]
+    fn links_cant_point_the_proxy_elsewhere() {
+        let mut settings = Settings::default();
+        let overrides = SettingsOverrides::from_query(vars(&[
+            ("provider", "proxy"),
+            ("base_url", "https://evil.example/"),
+            ("max_tokens", "512"),
+        ]))
+        .unwrap();
+        settings.apply(overrides).unwrap();
+        assert_eq!(settings.provider, Provider::default());
+        assert_eq!(settings.max_tokens, 512);
+
+        let overrides =
+            SettingsOverrides::from_query(vars(&[("base_url", "https://evil.example/")])).unwrap();
+        assert_eq!(overrides.base_url, None);
+
+        // Nor can it name an endpoint for a provider with an API key
+        let overrides = SettingsOverrides::from_query(vars(&[
+            ("provider", "openai"),
+            ("base_url", "https://evil.example/v1"),
+            ("model", "x"),
+        ]))
+        .unwrap();
+        assert_eq!(overrides.base_url, None);
+
+        // Self-hosted models can still be picked from a link
+        let overrides = SettingsOverrides::from_query(vars(&[
+            ("provider", "ollama"),
+            ("base_url", "http://gpu-box:11434"),
+            ("api_key", "sk-secret"),
+        ]))
+        .unwrap();
+        assert_eq!(overrides.base_url.as_deref(), Some("http://gpu-box:11434"));
+        assert_eq!(overrides.api_key, None);
+    }
+
+    #[test]
+    fn saved_keys_stay_with_their_endpoint() {
+        let openai = Settings {
+            provider: Provider::OpenAiCompatible {
+                base_url: "https://api.openai.com/v1".to_string(),
+                api_key: Some("sk-saved".to_string()),
+                model: "gpt-4o".to_string(),
+            },
+            ..Settings::default()
+        };
+        let anthropic = Settings {
+            provider: Provider::Anthropic {
+                api_key: "sk-ant-saved".to_string(),
+                model: "claude-3-5-sonnet-latest".to_string(),
+            },
+            ..Settings::default()
+        };
+
+        for saved in [&openai, &anthropic] {
+            for kind in ["openai", "anthropic"] {
+                let link = [
+                    ("provider", kind),
+                    ("base_url", "https://evil.example/v1"),
+                    ("model", "x"),
+                ];
+                let mut settings = saved.clone();
+                let overrides = SettingsOverrides::from_query(vars(&link)).unwrap();
+                // Switching without the key or endpoint it needs is refused
+                let _ = settings.apply(overrides);
+                assert_ne!(
+                    settings.provider.endpoint(),
+                    Some("https://evil.example/v1")
+                );
+                if settings.provider.api_key().is_some() {
+                    assert_eq!(settings.provider.endpoint(), saved.provider.endpoint());
+                }
+            }
+        }
+
+        // Even a trusted override that moves the endpoint drops the key
+        let mut settings = openai.clone();
+        let overrides =
+            SettingsOverrides::from_vars(vars(&[("base_url", "http://gpu-box:8000/v1")])).unwrap();
+        settings.apply(overrides).unwrap();
+        assert_eq!(settings.provider.endpoint(), Some("http://gpu-box:8000/v1"));
+        assert_eq!(settings.provider.api_key(), None);
+    }
+
+    #[test]
+    fn api_keys_are_not_persisted() {
+        let settings = Settings {
+            provider: Provider::Anthropic {
+                api_key: "sk-ant-secret".to_string(),
+                model: "claude-3-5-sonnet-latest".to_string(),
+            },
+            ..Settings::default()
+        };
+        let json = serde_json::to_string(&settings).unwrap();
+        assert!(!json.contains("sk-ant-secret"));
+
+        let restored: Settings = serde_json::from_str(&json).unwrap();
+        assert_eq!(restored.provider.api_key(), Some(""));
+    }
+}
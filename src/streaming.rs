@@ -0,0 +1,622 @@
+#![warn(clippy::all)]
+
+use serde::Deserialize;
+
+use crate::error::ApiError;
+use crate::providers::{OllamaCounts, OpenAiUsage};
+use crate::{MilestoneCall, TutorReply, Usage};
+
+/// One server-sent event.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub(crate) struct SseEvent {
+    pub(crate) event: Option<String>,
+    pub(crate) data: String,
+}
+
+/// Splits a server-sent event stream into events. Chunks can end anywhere,
+/// including in the middle of a line or a UTF-8 character.
+#[derive(Debug, Default)]
+pub(crate) struct SseParser {
+    buffer: Vec<u8>,
+    current: SseEvent,
+    has_data: bool,
+}
+
+impl SseParser {
+    pub(crate) fn push(&mut self, chunk: &[u8]) -> Vec<SseEvent> {
+        self.buffer.extend_from_slice(chunk);
+
+        let mut events = Vec::new();
+        while let Some(newline) = self.buffer.iter().position(|&b| b == b'\n') {
+            let raw: Vec<u8> = self.buffer.drain(..=newline).collect();
+            let line = String::from_utf8_lossy(&raw);
+            let line = line.trim_end_matches(['\n', '\r']);
+
+            if line.is_empty() {
+                if self.has_data {
+                    events.push(std::mem::take(&mut self.current));
+                    self.has_data = false;
+                } else {
+                    self.current = SseEvent::default();
+                }
+                continue;
+            }
+            if line.starts_with(':') {
+                continue;
+            }
+
+            let (field, value) = line.split_once(':').unwrap_or((line, ""));
+            let value = value.strip_prefix(' ').unwrap_or(value);
+            match field {
+                "event" => self.current.event = Some(value.to_string()),
+                "data" => {
+                    if self.has_data {
+                        self.current.data.push('\n');
+                    }
+                    self.current.data.push_str(value);
+                    self.has_data = true;
+                }
+                _ => {}
+            }
+        }
+        events
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum StreamEvent {
+    MessageStart {
+        message: MessageStart,
+    },
+    MessageDelta {
+        usage: Option<Usage>,
+    },
+    ContentBlockStart {
+        index: usize,
+        content_block: BlockStart,
+    },
+    ContentBlockDelta {
+        index: usize,
+        delta: BlockDelta,
+    },
+    MessageStop,
+    Error {
+        error: StreamError,
+    },
+    #[serde(other)]
+    Other,
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum BlockStart {
+    Text {
+        #[serde(default)]
+        text: String,
+    },
+    ToolUse {
+        name: String,
+    },
+    #[serde(other)]
+    Other,
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum BlockDelta {
+    TextDelta {
+        text: String,
+    },
+    InputJsonDelta {
+        partial_json: String,
+    },
+    #[serde(other)]
+    Other,
+}
+
+#[derive(Deserialize)]
+struct MessageStart {
+    usage: Option<Usage>,
+}
+
+#[derive(Deserialize)]
+struct StreamError {
+    message: String,
+}
+
+#[derive(Debug)]
+enum Block {
+    Text(String),
+    ToolUse { name: String, input_json: String },
+    Other,
+}
+
+/// Why `finish` turns down a stream that closed before its end event.
+const CUT_OFF: &str = "the reply stopped before it was complete";
+
+/// Builds a `TutorReply` from an Anthropic Messages stream as it arrives.
+#[derive(Debug, Default)]
+pub(crate) struct AnthropicStream {
+    parser: SseParser,
+    blocks: Vec<Block>,
+    usage: Option<Usage>,
+    /// Set by `message_stop`.
+    done: bool,
+}
+
+impl AnthropicStream {
+    /// Feeds the next chunk of the response body, returning the text it added.
+    pub(crate) fn push(&mut self, chunk: &[u8]) -> Result<String, ApiError> {
+        let mut text = String::new();
+
+        for event in self.parser.push(chunk) {
+            let event: StreamEvent = serde_json::from_str(&event.data)
+                .map_err(|e| ApiError::Decode(format!("bad stream event: {}", e)))?;
+
+            match event {
+                // The prompt's counts come first, the output's at the end
+                StreamEvent::MessageStart { message } => self.usage = message.usage,
+                StreamEvent::MessageDelta { usage } => {
+                    if let (Some(total), Some(delta)) = (&mut self.usage, usage) {
+                        total.output_tokens = delta.output_tokens;
+                    }
+                }
+                StreamEvent::ContentBlockStart {
+                    index,
+                    content_block,
+                } => {
+                    let block = match content_block {
+                        BlockStart::Text { text: initial } => {
+                            text.push_str(&initial);
+                            Block::Text(initial)
+                        }
+                        BlockStart::ToolUse { name } => Block::ToolUse {
+                            name,
+                            input_json: String::new(),
+                        },
+                        BlockStart::Other => Block::Other,
+                    };
+                    if index >= self.blocks.len() {
+                        self.blocks.resize_with(index + 1, || Block::Other);
+                    }
+                    self.blocks[index] = block;
+                }
+                StreamEvent::ContentBlockDelta { index, delta } => {
+                    match (self.blocks.get_mut(index), delta) {
+                        (Some(Block::Text(block_text)), BlockDelta::TextDelta { text: delta }) => {
+                            block_text.push_str(&delta);
+                            text.push_str(&delta);
+                        }
+                        (
+                            Some(Block::ToolUse { input_json, .. }),
+                            BlockDelta::InputJsonDelta { partial_json },
+                        ) => input_json.push_str(&partial_json),
+                        _ => {}
+                    }
+                }
+                StreamEvent::MessageStop => self.done = true,
+                StreamEvent::Error { error } => return Err(ApiError::Provider(error.message)),
+                StreamEvent::Other => {}
+            }
+        }
+
+        Ok(text)
+    }
+
+    /// The whole reply, or why it's incomplete if the stream closed before
+    /// `message_stop`.
+    pub(crate) fn finish(self) -> Result<TutorReply, String> {
+        if !self.done {
+            return Err(CUT_OFF.to_string());
+        }
+        let mut reply = TutorReply {
+            usage: self.usage,
+            ..TutorReply::default()
+        };
+        for block in self.blocks {
+            match block {
+                Block::Text(text) => reply.text.push_str(&text),
+                Block::ToolUse { name, input_json } => {
+                    reply
+                        .milestone_calls
+                        .extend(MilestoneCall::from_json_arguments(&name, &input_json));
+                }
+                Block::Other => {}
+            }
+        }
+        Ok(reply)
+    }
+}
+
+#[derive(Deserialize)]
+struct OpenAiChunk {
+    #[serde(default)]
+    choices: Vec<OpenAiChunkChoice>,
+    usage: Option<OpenAiUsage>,
+    error: Option<OpenAiError>,
+}
+
+#[derive(Deserialize)]
+struct OpenAiChunkChoice {
+    delta: OpenAiDelta,
+}
+
+#[derive(Deserialize)]
+struct OpenAiDelta {
+    content: Option<String>,
+    #[serde(default)]
+    tool_calls: Vec<OpenAiToolCallDelta>,
+}
+
+#[derive(Deserialize)]
+struct OpenAiToolCallDelta {
+    #[serde(default)]
+    index: usize,
+    function: Option<OpenAiFunctionDelta>,
+}
+
+#[derive(Deserialize)]
+struct OpenAiFunctionDelta {
+    name: Option<String>,
+    arguments: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct OpenAiError {
+    message: String,
+}
+
+/// Builds a `TutorReply` from an OpenAI-compatible chat completion stream.
+#[derive(Debug, Default)]
+pub(crate) struct OpenAiStream {
+    parser: SseParser,
+    text: String,
+    /// Name and argument JSON of each tool call, by index.
+    tool_calls: Vec<(String, String)>,
+    usage: Option<Usage>,
+    /// Set by `[DONE]`.
+    done: bool,
+}
+
+impl OpenAiStream {
+    pub(crate) fn push(&mut self, chunk: &[u8]) -> Result<String, ApiError> {
+        let mut text = String::new();
+
+        for event in self.parser.push(chunk) {
+            if event.data == "[DONE]" {
+                self.done = true;
+                continue;
+            }
+            let chunk: OpenAiChunk = serde_json::from_str(&event.data)
+                .map_err(|e| ApiError::Decode(format!("bad stream event: {}", e)))?;
+            if let Some(error) = chunk.error {
+                return Err(ApiError::Provider(error.message));
+            }
+            if let Some(usage) = chunk.usage {
+                self.usage = Some(usage.into());
+            }
+
+            for choice in chunk.choices {
+                if let Some(content) = choice.delta.content {
+                    text.push_str(&content);
+                }
+                for call in choice.delta.tool_calls {
+                    if call.index >= self.tool_calls.len() {
+                        self.tool_calls
+                            .resize_with(call.index + 1, Default::default);
+                    }
+                    let (name, arguments) = &mut self.tool_calls[call.index];
+                    if let Some(function) = call.function {
+                        name.push_str(&function.name.unwrap_or_default());
+                        arguments.push_str(&function.arguments.unwrap_or_default());
+                    }
+                }
+            }
+        }
+
+        self.text.push_str(&text);
+        Ok(text)
+    }
+
+    /// The whole reply, or why it's incomplete if the stream closed before
+    /// `[DONE]`.
+    pub(crate) fn finish(self) -> Result<TutorReply, String> {
+        if !self.done {
+            return Err(CUT_OFF.to_string());
+        }
+        Ok(TutorReply {
+            text: self.text,
+            milestone_calls: self
+                .tool_calls
+                .iter()
+                .filter_map(|(name, arguments)| MilestoneCall::from_json_arguments(name, arguments))
+                .collect(),
+            usage: self.usage,
+        })
+    }
+}
+
+#[derive(Deserialize)]
+struct OllamaChunk {
+    message: Option<crate::providers::OllamaMessage>,
+    error: Option<String>,
+    #[serde(default)]
+    done: bool,
+    #[serde(flatten)]
+    counts: OllamaCounts,
+}
+
+/// Builds a `TutorReply` from Ollama's newline-delimited JSON chat stream.
+#[derive(Debug, Default)]
+pub(crate) struct OllamaStream {
+    buffer: Vec<u8>,
+    reply: TutorReply,
+    /// Set by the line with `"done": true`.
+    done: bool,
+}
+
+impl OllamaStream {
+    pub(crate) fn push(&mut self, chunk: &[u8]) -> Result<String, ApiError> {
+        self.buffer.extend_from_slice(chunk);
+
+        let mut text = String::new();
+        while let Some(newline) = self.buffer.iter().position(|&b| b == b'\n') {
+            let line: Vec<u8> = self.buffer.drain(..=newline).collect();
+            if line.iter().all(u8::is_ascii_whitespace) {
+                continue;
+            }
+            let chunk: OllamaChunk = serde_json::from_slice(&line)
+                .map_err(|e| ApiError::Decode(format!("bad stream event: {}", e)))?;
+            if let Some(error) = chunk.error {
+                return Err(ApiError::Provider(error));
+            }
+            if let Some(usage) = chunk.counts.usage() {
+                self.reply.usage = Some(usage);
+            }
+            self.done |= chunk.done;
+
+            if let Some(message) = chunk.message {
+                text.push_str(&message.content);
+                self.reply
+                    .milestone_calls
+                    .extend(message.into_milestone_calls());
+            }
+        }
+
+        self.reply.text.push_str(&text);
+        Ok(text)
+    }
+
+    /// The whole reply, or why it's incomplete if the stream closed before
+    /// its `done` line.
+    pub(crate) fn finish(self) -> Result<TutorReply, String> {
+        if !self.done {
+            return Err(CUT_OFF.to_string());
+        }
+        Ok(self.reply)
+    }
+}
+
+/// Decodes a streamed reply in whichever format the provider streams.
+#[derive(Debug)]
+pub(crate) enum ReplyStream {
+    Anthropic(AnthropicStream),
+    OpenAi(OpenAiStream),
+    Ollama(OllamaStream),
+}
+
+impl ReplyStream {
+    /// Feeds the next chunk of the response body, returning the text it added.
+    pub(crate) fn push(&mut self, chunk: &[u8]) -> Result<String, ApiError> {
+        match self {
+            Self::Anthropic(stream) => stream.push(chunk),
+            Self::OpenAi(stream) => stream.push(chunk),
+            Self::Ollama(stream) => stream.push(chunk),
+        }
+    }
+
+    pub(crate) fn finish(self) -> Result<TutorReply, String> {
+        match self {
+            Self::Anthropic(stream) => stream.finish(),
+            Self::OpenAi(stream) => stream.finish(),
+            Self::Ollama(stream) => stream.finish(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const STREAM: &str = "event: message_start\n\
+        data: {\"type\":\"message_start\",\"message\":{\"id\":\"msg_01\",\"content\":[],\"usage\":{\"input_tokens\":12,\"cache_creation_input_tokens\":0,\"cache_read_input_tokens\":5120,\"output_tokens\":1}}}\n\
+        \n\
+        event: content_block_start\n\
+        data: {\"type\":\"content_block_start\",\"index\":0,\"content_block\":{\"type\":\"text\",\"text\":\"\"}}\n\
+        \n\
+        event: ping\n\
+        data: {\"type\": \"ping\"}\n\
+        \n\
+        event: content_block_delta\n\
+        data: {\"type\":\"content_block_delta\",\"index\":0,\"delta\":{\"type\":\"text_delta\",\"text\":\"Exactly — \"}}\n\
+        \n\
+        event: content_block_delta\n\
+        data: {\"type\":\"content_block_delta\",\"index\":0,\"delta\":{\"type\":\"text_delta\",\"text\":\"split it in half!\"}}\n\
+        \n\
+        event: content_block_stop\n\
+        data: {\"type\":\"content_block_stop\",\"index\":0}\n\
+        \n\
+        event: content_block_start\n\
+        data: {\"type\":\"content_block_start\",\"index\":1,\"content_block\":{\"type\":\"tool_use\",\"id\":\"toolu_01\",\"name\":\"mark_milestone\",\"input\":{}}}\n\
+        \n\
+        event: content_block_delta\n\
+        data: {\"type\":\"content_block_delta\",\"index\":1,\"delta\":{\"type\":\"input_json_delta\",\"partial_json\":\"{\\\"milestone_id\\\": \\\"splitting_insight\\\", \"}}\n\
+        \n\
+        event: content_block_delta\n\
+        data: {\"type\":\"content_block_delta\",\"index\":1,\"delta\":{\"type\":\"input_json_delta\",\"partial_json\":\"\\\"justification\\\": \\\"Proposed halving.\\\"}\"}}\n\
+        \n\
+        event: message_delta\n\
+        data: {\"type\":\"message_delta\",\"delta\":{\"stop_reason\":\"tool_use\"},\"usage\":{\"output_tokens\":48}}\n\
+        \n\
+        event: message_stop\n\
+        data: {\"type\":\"message_stop\"}\n\
+        \n";
+
+    #[test]
+    fn sse_parser_handles_split_chunks() {
+        let mut parser = SseParser::default();
+        let bytes =
+            "event: ping\r\ndata: {\"a\":\ndata: 1}\r\n\r\n: comment\n\ndata: é\n\n".as_bytes();
+
+        let mut events = Vec::new();
+        for chunk in bytes.chunks(3) {
+            events.extend(parser.push(chunk));
+        }
+        assert_eq!(
+            events,
+            vec![
+                SseEvent {
+                    event: Some("ping".to_string()),
+                    data: "{\"a\":\n1}".to_string(),
+                },
+                SseEvent {
+                    event: None,
+                    data: "é".to_string(),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn accumulator_streams_text_and_collects_tool_calls() {
+        let mut stream = AnthropicStream::default();
+        let mut streamed = String::new();
+        for chunk in STREAM.as_bytes().chunks(17) {
+            streamed.push_str(&stream.push(chunk).unwrap());
+        }
+        assert_eq!(streamed, "Exactly — split it in half!");
+
+        let reply = stream.finish().unwrap();
+        assert_eq!(reply.text, "Exactly — split it in half!");
+        assert_eq!(
+            reply.milestone_calls,
+            vec![MilestoneCall {
+                milestone_id: "splitting_insight".to_string(),
+                justification: "Proposed halving.".to_string(),
+            }]
+        );
+        assert_eq!(
+            reply.usage,
+            Some(Usage {
+                input_tokens: 12,
+                output_tokens: 48,
+                cache_creation_input_tokens: 0,
+                cache_read_input_tokens: 5120,
+            })
+        );
+    }
+
+    #[test]
+    fn streams_that_stop_early_are_incomplete() {
+        let (cut, _) = STREAM.split_once("event: message_stop").unwrap();
+        let mut stream = AnthropicStream::default();
+        assert_eq!(
+            stream.push(cut.as_bytes()).unwrap(),
+            "Exactly — split it in half!"
+        );
+        assert_eq!(stream.finish().unwrap_err(), CUT_OFF);
+
+        let mut stream = OpenAiStream::default();
+        stream
+            .push(b"data: {\"choices\":[{\"index\":0,\"delta\":{\"content\":\"Nice \"}}]}\n\n")
+            .unwrap();
+        assert!(stream.finish().is_err());
+
+        let mut stream = OllamaStream::default();
+        stream
+            .push(b"{\"message\":{\"role\":\"assistant\",\"content\":\"Which \"},\"done\":false}\n")
+            .unwrap();
+        assert!(stream.finish().is_err());
+    }
+
+    #[test]
+    fn accumulator_surfaces_stream_errors() {
+        let mut stream = AnthropicStream::default();
+        let error = stream
+            .push(b"event: error\ndata: {\"type\":\"error\",\"error\":{\"type\":\"overloaded_error\",\"message\":\"Overloaded\"}}\n\n")
+            .unwrap_err();
+        assert_eq!(error, ApiError::Provider("Overloaded".to_string()));
+    }
+
+    #[test]
+    fn openai_stream_collects_text_and_tool_calls() {
+        let chunks = [
+            "data: {\"choices\":[{\"index\":0,\"delta\":{\"role\":\"assistant\",\"content\":\"Nice \"}}]}\n\n",
+            "data: {\"choices\":[{\"index\":0,\"delta\":{\"content\":\"work!\"}}]}\n\n",
+            "data: {\"choices\":[{\"index\":0,\"delta\":{\"tool_calls\":[{\"index\":0,\"id\":\"call_1\",\"type\":\"function\",\"function\":{\"name\":\"mark_milestone\",\"arguments\":\"{\\\"milestone_id\\\":\"}}]}}]}\n\n",
+            "data: {\"choices\":[{\"index\":0,\"delta\":{\"tool_calls\":[{\"index\":0,\"function\":{\"arguments\":\"\\\"recursive_pattern\\\",\\\"justification\\\":\\\"Called sort on each half.\\\"}\"}}]}}]}\n\n",
+            "data: {\"choices\":[],\"usage\":{\"prompt_tokens\":2100,\"completion_tokens\":30,\"prompt_tokens_details\":{\"cached_tokens\":2048}}}\n\n",
+            "data: [DONE]\n\n",
+        ];
+
+        let mut stream = OpenAiStream::default();
+        let mut streamed = String::new();
+        for chunk in chunks {
+            streamed.push_str(&stream.push(chunk.as_bytes()).unwrap());
+        }
+        assert_eq!(streamed, "Nice work!");
+
+        let reply = stream.finish().unwrap();
+        assert_eq!(reply.text, "Nice work!");
+        assert_eq!(
+            reply.milestone_calls,
+            vec![MilestoneCall {
+                milestone_id: "recursive_pattern".to_string(),
+                justification: "Called sort on each half.".to_string(),
+            }]
+        );
+        assert_eq!(
+            reply.usage,
+            Some(Usage {
+                input_tokens: 52,
+                output_tokens: 30,
+                cache_creation_input_tokens: 0,
+                cache_read_input_tokens: 2048,
+            })
+        );
+    }
+
+    #[test]
+    fn ollama_stream_reads_json_lines() {
+        let body = "{\"message\":{\"role\":\"assistant\",\"content\":\"Which half \"},\"done\":false}\n\
+            {\"message\":{\"role\":\"assistant\",\"content\":\"is smaller?\"},\"done\":false}\n\
+            {\"message\":{\"role\":\"assistant\",\"content\":\"\",\"tool_calls\":[{\"function\":{\"name\":\"mark_milestone\",\"arguments\":{\"milestone_id\":\"splitting_insight\",\"justification\":\"Split the list.\"}}}]},\"done\":false}\n\
+            {\"message\":{\"role\":\"assistant\",\"content\":\"\"},\"done\":true,\"prompt_eval_count\":800,\"eval_count\":25}\n";
+
+        let mut stream = ReplyStream::Ollama(OllamaStream::default());
+        let mut streamed = String::new();
+        for chunk in body.as_bytes().chunks(11) {
+            streamed.push_str(&stream.push(chunk).unwrap());
+        }
+        assert_eq!(streamed, "Which half is smaller?");
+
+        let reply = stream.finish().unwrap();
+        assert_eq!(reply.text, "Which half is smaller?");
+        assert_eq!(reply.milestone_calls.len(), 1);
+        assert_eq!(reply.milestone_calls[0].milestone_id, "splitting_insight");
+        assert_eq!(reply.usage.map(|u| u.input_tokens), Some(800));
+    }
+
+    #[test]
+    fn ollama_stream_surfaces_errors() {
+        let mut stream = OllamaStream::default();
+        let error = stream
+            .push(b"{\"error\":\"model 'llama3.1' not found\"}\n")
+            .unwrap_err();
+        assert_eq!(
+            error,
+            ApiError::Provider("model 'llama3.1' not found".to_string())
+        );
+    }
+}
@@ -0,0 +1,867 @@
+#![warn(clippy::all)]
+
+//! A stand-in for Supabase auth and the tutoring proxy, for running the app
+//! and its tests without a network. Point `SUPABASE_URL` and the proxy
+//! provider's URL at [`StubServer::url`].
+
+use serde::Deserialize;
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+use crate::scripted::{reply_events, Transcript};
+
+const DEFAULT_OTP: &str = "123456";
+const DEFAULT_TOKEN_LIFETIME_SECS: i64 = 3600;
+const DEFAULT_TRANSCRIPT: &str = "lessons/mergesort/transcript.json";
+
+/// What the stub answers with.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct StubConfig {
+    /// The one-time code `/auth/v1/verify` accepts.
+    pub otp: String,
+    /// How long issued access tokens last; negative issues expired ones.
+    pub token_lifetime_secs: i64,
+    /// The transcript the chat endpoint answers from.
+    pub transcript: String,
+    /// Checked in order before each request is answered.
+    pub failures: Vec<StubFailure>,
+}
+
+impl Default for StubConfig {
+    fn default() -> Self {
+        Self {
+            otp: DEFAULT_OTP.to_string(),
+            token_lifetime_secs: DEFAULT_TOKEN_LIFETIME_SECS,
+            transcript: DEFAULT_TRANSCRIPT.to_string(),
+            failures: Vec::new(),
+        }
+    }
+}
+
+/// A canned failure for requests to one endpoint.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct StubFailure {
+    /// `/auth/v1/otp`, `/auth/v1/verify`, `/auth/v1/token` or `/` for chat.
+    pub path: String,
+    /// Only fail requests for this student; all of them when unset.
+    pub email: Option<String>,
+    pub status: u16,
+    pub body: String,
+    pub retry_after: Option<String>,
+    /// Close the connection without answering, as a dropped network would.
+    pub disconnect: bool,
+    /// How many requests to fail before answering normally; every one when
+    /// unset.
+    pub times: Option<u32>,
+}
+
+impl Default for StubFailure {
+    fn default() -> Self {
+        Self {
+            path: "/".to_string(),
+            email: None,
+            status: 500,
+            body: String::new(),
+            retry_after: None,
+            disconnect: false,
+            times: None,
+        }
+    }
+}
+
+/// A request the stub received.
+#[derive(Debug, Clone)]
+pub struct StubRequest {
+    pub method: String,
+    /// The path and query.
+    pub path: String,
+    /// Header names are lowercase.
+    pub headers: Vec<(String, String)>,
+    pub body: String,
+}
+
+impl StubRequest {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    fn route(&self) -> &str {
+        self.path.split('?').next().unwrap_or_default()
+    }
+
+    /// The `email` field of a JSON body, which every endpoint but the
+    /// session refresh is sent.
+    pub fn email(&self) -> Option<String> {
+        json_field(&self.body, "email")
+    }
+}
+
+enum StubResponse {
+    Reply {
+        status: u16,
+        content_type: &'static str,
+        retry_after: Option<String>,
+        body: String,
+    },
+    Disconnect,
+}
+
+impl StubResponse {
+    fn json(status: u16, body: serde_json::Value) -> Self {
+        Self::Reply {
+            status,
+            content_type: "application/json",
+            retry_after: None,
+            body: body.to_string(),
+        }
+    }
+}
+
+struct State {
+    config: StubConfig,
+    transcript: Transcript,
+    requests: Vec<StubRequest>,
+    /// Access tokens handed out by `/auth/v1/verify` and `/auth/v1/token`.
+    issued: Vec<String>,
+    /// Refresh tokens not yet exchanged, and whose they are.
+    refresh_tokens: Vec<(String, String)>,
+}
+
+impl State {
+    fn respond(&mut self, request: StubRequest) -> StubResponse {
+        let email = request
+            .email()
+            .or_else(|| self.refresh_token_owner(&request));
+        let route = request.route().to_string();
+        self.requests.push(request.clone());
+
+        let failure = self.config.failures.iter_mut().find(|failure| {
+            failure.path == route
+                && failure.times != Some(0)
+                && (failure.email.is_none() || failure.email == email)
+        });
+        if let Some(failure) = failure {
+            if let Some(times) = &mut failure.times {
+                *times -= 1;
+            }
+            if failure.disconnect {
+                return StubResponse::Disconnect;
+            }
+            return StubResponse::Reply {
+                status: failure.status,
+                content_type: "application/json",
+                retry_after: failure.retry_after.clone(),
+                body: failure.body.clone(),
+            };
+        }
+
+        if route.starts_with("/auth/") && request.header("apikey").is_none() {
+            return StubResponse::json(
+                401,
+                serde_json::json!({ "message": "No API key found in request" }),
+            );
+        }
+
+        match (request.method.as_str(), route.as_str()) {
+            ("POST", "/auth/v1/otp") => StubResponse::json(200, serde_json::json!({})),
+            ("POST", "/auth/v1/verify") => self.verify(&request),
+            ("POST", "/auth/v1/token") => self.refresh(&request),
+            ("POST", "/") if !self.authorized(&request) => StubResponse::json(
+                401,
+                serde_json::json!({ "error": "Missing or expired access token" }),
+            ),
+            ("POST", "/") => match self.transcript.respond(&request.body) {
+                Ok(reply) => StubResponse::Reply {
+                    status: 200,
+                    content_type: "text/event-stream",
+                    retry_after: None,
+                    body: reply_events(reply).concat(),
+                },
+                Err(e) => StubResponse::json(400, serde_json::json!({ "error": e })),
+            },
+            _ => StubResponse::json(404, serde_json::json!({ "error": "Not found" })),
+        }
+    }
+
+    /// A JWT in Supabase's shape, signed with nothing in particular.
+    fn issue_token(&mut self, email: &str) -> String {
+        use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+        use base64::Engine;
+
+        let exp = chrono::Utc::now().timestamp() + self.config.token_lifetime_secs;
+        let header = serde_json::json!({ "alg": "HS256", "typ": "JWT" });
+        let claims = serde_json::json!({
+            "sub": format!("stub-{}", self.issued.len() + 1),
+            "email": email,
+            "role": "authenticated",
+            "exp": exp,
+        });
+        let token = format!(
+            "{}.{}.{}",
+            URL_SAFE_NO_PAD.encode(header.to_string()),
+            URL_SAFE_NO_PAD.encode(claims.to_string()),
+            URL_SAFE_NO_PAD.encode("stub-signature"),
+        );
+        self.issued.push(token.clone());
+        token
+    }
+
+    /// Who the refresh token in a `/auth/v1/token` request was issued to.
+    fn refresh_token_owner(&self, request: &StubRequest) -> Option<String> {
+        let token = json_field(&request.body, "refresh_token")?;
+        self.refresh_tokens
+            .iter()
+            .find(|(issued, _)| *issued == token)
+            .map(|(_, email)| email.clone())
+    }
+
+    /// A session as Supabase returns it from sign-in and refresh.
+    fn session(&mut self, email: &str) -> StubResponse {
+        let refresh_token = format!("stub-refresh-{}", self.issued.len() + 1);
+        self.refresh_tokens
+            .push((refresh_token.clone(), email.to_string()));
+        StubResponse::json(
+            200,
+            serde_json::json!({
+                "access_token": self.issue_token(email),
+                "token_type": "bearer",
+                "expires_in": self.config.token_lifetime_secs,
+                "expires_at": chrono::Utc::now().timestamp() + self.config.token_lifetime_secs,
+                "refresh_token": refresh_token,
+                "user": { "email": email },
+            }),
+        )
+    }
+
+    /// Whether a chat request carries an unexpired token issued by the stub.
+    fn authorized(&self, request: &StubRequest) -> bool {
+        request
+            .header("authorization")
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .is_some_and(|token| {
+                self.issued.iter().any(|issued| issued == token)
+                    && crate::token_expiry(token).is_some_and(|exp| exp > chrono::Utc::now())
+            })
+    }
+
+    fn verify(&mut self, request: &StubRequest) -> StubResponse {
+        if json_field(&request.body, "token").as_deref() != Some(self.config.otp.as_str()) {
+            return StubResponse::json(
+                403,
+                serde_json::json!({
+                    "code": 403,
+                    "error_code": "otp_expired",
+                    "msg": "Token has expired or is invalid",
+                }),
+            );
+        }
+        let email = request.email().unwrap_or_default();
+        self.session(&email)
+    }
+
+    /// Exchanges a refresh token for a new session. Each refresh token works
+    /// once, as Supabase rotates them.
+    fn refresh(&mut self, request: &StubRequest) -> StubResponse {
+        if !request.path.contains("grant_type=refresh_token") {
+            return StubResponse::json(
+                400,
+                serde_json::json!({
+                    "code": 400,
+                    "error_code": "validation_failed",
+                    "msg": "Unsupported grant type",
+                }),
+            );
+        }
+        let Some(email) = self.refresh_token_owner(request) else {
+            return StubResponse::json(
+                400,
+                serde_json::json!({
+                    "code": 400,
+                    "error_code": "refresh_token_not_found",
+                    "msg": "Invalid Refresh Token: Refresh Token Not Found",
+                }),
+            );
+        };
+        let token = json_field(&request.body, "refresh_token");
+        self.refresh_tokens
+            .retain(|(issued, _)| Some(issued) != token.as_ref());
+        self.session(&email)
+    }
+}
+
+/// A string field of a JSON body.
+fn json_field(body: &str, name: &str) -> Option<String> {
+    serde_json::from_str::<serde_json::Value>(body)
+        .ok()?
+        .get(name)?
+        .as_str()
+        .map(str::to_string)
+}
+
+/// A running stub, serving on its own thread until it's dropped.
+pub struct StubServer {
+    url: String,
+    state: Arc<Mutex<State>>,
+    shutdown: Option<tokio::sync::oneshot::Sender<()>>,
+}
+
+impl StubServer {
+    /// Starts serving on `addr`; port 0 picks a free one.
+    pub fn start(addr: &str, config: StubConfig) -> Result<Self, String> {
+        let text = std::fs::read_to_string(&config.transcript)
+            .map_err(|e| format!("Failed to read {}: {}", config.transcript, e))?;
+        let transcript = Transcript::parse(&text)?;
+
+        let listener = std::net::TcpListener::bind(addr)
+            .map_err(|e| format!("Failed to listen on {}: {}", addr, e))?;
+        listener.set_nonblocking(true).map_err(|e| e.to_string())?;
+        let url = format!(
+            "http://{}",
+            listener.local_addr().map_err(|e| e.to_string())?
+        );
+
+        let state = Arc::new(Mutex::new(State {
+            config,
+            transcript,
+            requests: Vec::new(),
+            issued: Vec::new(),
+            refresh_tokens: Vec::new(),
+        }));
+        let (shutdown, stopped) = tokio::sync::oneshot::channel();
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .map_err(|e| e.to_string())?;
+        let serving = state.clone();
+        std::thread::spawn(move || {
+            runtime.block_on(async move {
+                let listener = match TcpListener::from_std(listener) {
+                    Ok(listener) => listener,
+                    Err(e) => {
+                        log::error!("Stub server failed to start: {}", e);
+                        return;
+                    }
+                };
+                tokio::select! {
+                    _ = serve(listener, serving) => {}
+                    _ = stopped => {}
+                }
+            })
+        });
+
+        Ok(Self {
+            url,
+            state,
+            shutdown: Some(shutdown),
+        })
+    }
+
+    /// The base URL, e.g. `http://127.0.0.1:54321`.
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    /// Everything received so far, oldest first.
+    pub fn requests(&self) -> Vec<StubRequest> {
+        self.state.lock().unwrap().requests.clone()
+    }
+
+    /// An access token for `email`, as if they had signed in.
+    pub fn issue_token(&self, email: &str) -> String {
+        self.state.lock().unwrap().issue_token(email)
+    }
+
+    /// Adds a canned failure, checked after those already configured.
+    pub fn fail(&self, failure: StubFailure) {
+        self.state.lock().unwrap().config.failures.push(failure);
+    }
+}
+
+impl Drop for StubServer {
+    fn drop(&mut self) {
+        if let Some(shutdown) = self.shutdown.take() {
+            let _ = shutdown.send(());
+        }
+    }
+}
+
+async fn serve(listener: TcpListener, state: Arc<Mutex<State>>) {
+    loop {
+        match listener.accept().await {
+            Ok((stream, _)) => {
+                let state = state.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = handle(stream, state).await {
+                        log::warn!("Stub server connection failed: {}", e);
+                    }
+                });
+            }
+            Err(e) => log::warn!("Stub server failed to accept a connection: {}", e),
+        }
+    }
+}
+
+/// Answers one HTTP/1.1 request, then closes the connection.
+async fn handle(mut stream: TcpStream, state: Arc<Mutex<State>>) -> std::io::Result<()> {
+    let mut buf = Vec::new();
+    let mut chunk = [0u8; 4096];
+    let head_end = loop {
+        let read = stream.read(&mut chunk).await?;
+        if read == 0 {
+            return Ok(());
+        }
+        buf.extend_from_slice(&chunk[..read]);
+        if let Some(pos) = buf.windows(4).position(|w| w == b"\r\n\r\n") {
+            break pos + 4;
+        }
+    };
+
+    let head = String::from_utf8_lossy(&buf[..head_end]).into_owned();
+    let mut lines = head.lines();
+    let mut request_line = lines.next().unwrap_or_default().split(' ');
+    let method = request_line.next().unwrap_or_default().to_string();
+    let path = request_line.next().unwrap_or_default().to_string();
+    let headers: Vec<(String, String)> = lines
+        .filter_map(|line| line.split_once(':'))
+        .map(|(name, value)| (name.trim().to_ascii_lowercase(), value.trim().to_string()))
+        .collect();
+    let length = headers
+        .iter()
+        .find(|(name, _)| name == "content-length")
+        .and_then(|(_, value)| value.parse().ok())
+        .unwrap_or(0);
+
+    while buf.len() < head_end + length {
+        let read = stream.read(&mut chunk).await?;
+        if read == 0 {
+            break;
+        }
+        buf.extend_from_slice(&chunk[..read]);
+    }
+    let body_end = buf.len().min(head_end + length);
+    let request = StubRequest {
+        method,
+        path,
+        headers,
+        body: String::from_utf8_lossy(&buf[head_end..body_end]).into_owned(),
+    };
+
+    let response = state.lock().unwrap().respond(request);
+    let StubResponse::Reply {
+        status,
+        content_type,
+        retry_after,
+        body,
+    } = response
+    else {
+        return Ok(());
+    };
+
+    let mut head = format!(
+        "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n",
+        status,
+        reason(status),
+        content_type,
+        body.len()
+    );
+    if let Some(retry_after) = retry_after {
+        head.push_str(&format!("Retry-After: {}\r\n", retry_after));
+    }
+    head.push_str("\r\n");
+    stream.write_all(head.as_bytes()).await?;
+    stream.write_all(body.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        429 => "Too Many Requests",
+        500 => "Internal Server Error",
+        502 => "Bad Gateway",
+        503 => "Service Unavailable",
+        _ => "Unknown",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::ApiError;
+    use crate::providers::Provider;
+    use crate::retry::RetryPolicy;
+    use crate::settings::Settings;
+    use crate::transport::ReqwestTransport;
+    use crate::{markers, refresh_session_native, request_otp_native, verify_otp_native};
+    use lazy_static::lazy_static;
+    use std::time::Duration;
+
+    lazy_static! {
+        /// Shared by every test, since the Supabase URL is only read once.
+        static ref SERVER: StubServer = {
+            let server = StubServer::start("127.0.0.1:0", StubConfig::default()).unwrap();
+            std::env::set_var("SUPABASE_URL", server.url());
+            server
+        };
+        /// Held by tests that sign in, since there's one signed-in student.
+        static ref SIGN_IN: tokio::sync::Mutex<()> = tokio::sync::Mutex::new(());
+    }
+
+    /// The shared stub, started before anything reads the Supabase URL.
+    fn server() -> &'static StubServer {
+        &SERVER
+    }
+
+    const ONE_QUICK_RETRY: RetryPolicy = RetryPolicy {
+        max_retries: 1,
+        base_delay: Duration::ZERO,
+        max_delay: Duration::ZERO,
+        max_retry_after: Duration::from_secs(60),
+    };
+
+    fn requests_from(email: &str) -> Vec<StubRequest> {
+        server()
+            .requests()
+            .into_iter()
+            .filter(|request| request.email().as_deref() == Some(email))
+            .collect()
+    }
+
+    fn settings() -> Settings {
+        Settings {
+            provider: Provider::Proxy {
+                url: format!("{}/", server().url()),
+            },
+            ..Settings::default()
+        }
+    }
+
+    /// Asks the tutor as the signed-in student, waiting for the reply.
+    async fn ask(content: &str) -> Result<crate::TutorReply, ApiError> {
+        let (reply_tx, reply_rx) = std::sync::mpsc::channel();
+        let message = crate::app::ChatMessage {
+            content: content.to_string(),
+            from_user: true,
+            analyzed_for_milestones: false,
+            found_milestones: Vec::new(),
+            unsent: false,
+            sent_at: None,
+            metadata: None,
+        };
+        crate::make_tutor_request(
+            &settings(),
+            message,
+            Vec::new(),
+            |_| {},
+            |_, _| {},
+            move |result| reply_tx.send(result).unwrap(),
+        )
+        .expect("signed in, so the request starts");
+        tokio::task::spawn_blocking(move || reply_rx.recv().unwrap())
+            .await
+            .unwrap()
+    }
+
+    /// A token the stub never issued, expiring at `exp`.
+    fn forged_token(email: &str, exp: chrono::DateTime<chrono::Utc>) -> String {
+        use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+        use base64::Engine;
+
+        let token = server().issue_token(email);
+        let mut parts: Vec<String> = token.split('.').map(str::to_string).collect();
+        let mut claims: serde_json::Value =
+            serde_json::from_slice(&URL_SAFE_NO_PAD.decode(&parts[1]).unwrap()).unwrap();
+        claims["exp"] = exp.timestamp().into();
+        claims["sub"] = "forged".into();
+        parts[1] = URL_SAFE_NO_PAD.encode(claims.to_string());
+        parts.join(".")
+    }
+
+    /// The bearer tokens sent with the chat requests a student made.
+    fn chat_tokens(email: &str) -> Vec<String> {
+        requests_from(email)
+            .iter()
+            .filter(|request| request.path == "/")
+            .filter_map(|request| request.header("authorization").map(str::to_string))
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn sign_in_then_chat_reaches_a_milestone() {
+        let email = "flow@uni.edu";
+        let _signed_in = SIGN_IN.lock().await;
+        server();
+        request_otp_native(email).await.unwrap();
+        assert_eq!(
+            verify_otp_native(email, "000000").await,
+            Err(ApiError::Auth {
+                code: "otp_expired".to_string(),
+                message: "Token has expired or is invalid".to_string(),
+            })
+        );
+        verify_otp_native(email, DEFAULT_OTP).await.unwrap();
+        let token = crate::AUTH_STATE.lock().unwrap().access_token.clone();
+        assert!(token.as_deref().and_then(crate::token_expiry).is_some());
+
+        let reply = ask("What if we split the list in half?").await.unwrap();
+
+        let scan = markers::scan(&reply.text, &["splitting_insight"]);
+        assert_eq!(scan.markers[0].milestone_id, "splitting_insight");
+
+        let requests = requests_from(email);
+        let paths: Vec<&str> = requests.iter().map(|r| r.path.as_str()).collect();
+        assert_eq!(
+            paths,
+            vec!["/auth/v1/otp", "/auth/v1/verify", "/auth/v1/verify", "/"]
+        );
+        assert_eq!(
+            requests[3].header("authorization"),
+            Some(format!("Bearer {}", token.unwrap()).as_str())
+        );
+    }
+
+    #[tokio::test]
+    async fn canned_failures_reach_the_client() {
+        let email = "failing@uni.edu";
+        server().fail(StubFailure {
+            path: "/auth/v1/otp".to_string(),
+            email: Some(email.to_string()),
+            status: 429,
+            body: r#"{"code":429,"error_code":"over_email_send_rate_limit","msg":"Rate limited"}"#
+                .to_string(),
+            ..StubFailure::default()
+        });
+        server().fail(StubFailure {
+            email: Some(email.to_string()),
+            status: 503,
+            times: Some(1),
+            ..StubFailure::default()
+        });
+
+        assert!(matches!(
+            request_otp_native(email).await,
+            Err(ApiError::Auth { code, .. }) if code == "over_email_send_rate_limit"
+        ));
+
+        // The chat request fails once, then succeeds on retry
+        let provider = settings().provider;
+        let (chat, _) = crate::build_request(
+            &settings(),
+            crate::app::ChatMessage {
+                content: "I'd compare every pair".to_string(),
+                from_user: true,
+                analyzed_for_milestones: false,
+                found_milestones: Vec::new(),
+                unsent: false,
+                sent_at: None,
+                metadata: None,
+            },
+            Vec::new(),
+            email.to_string(),
+            Some(server().issue_token(email)),
+        );
+        let retries = Mutex::new(Vec::new());
+        let reply = crate::request_reply(
+            &ReqwestTransport::default(),
+            &provider,
+            &ONE_QUICK_RETRY,
+            provider.http_request(&chat).unwrap(),
+            |_| {},
+            |retry, of| retries.lock().unwrap().push((retry, of)),
+        )
+        .await
+        .unwrap();
+
+        assert!(reply.text.starts_with("That works!"), "{}", reply.text);
+        assert_eq!(*retries.lock().unwrap(), vec![(1, 1)]);
+        assert_eq!(requests_from(email).len(), 3);
+    }
+
+    #[tokio::test]
+    async fn chat_without_a_valid_token_needs_sign_in() {
+        let email = "stranger@uni.edu";
+        let provider = settings().provider;
+        for token in [None, Some("not-a-token".to_string())] {
+            let (chat, _) = crate::build_request(
+                &settings(),
+                crate::app::ChatMessage {
+                    content: "Hello?".to_string(),
+                    from_user: true,
+                    analyzed_for_milestones: false,
+                    found_milestones: Vec::new(),
+                    unsent: false,
+                    sent_at: None,
+                    metadata: None,
+                },
+                Vec::new(),
+                email.to_string(),
+                token,
+            );
+            let error = crate::request_reply(
+                &ReqwestTransport::default(),
+                &provider,
+                &ONE_QUICK_RETRY,
+                provider.http_request(&chat).unwrap(),
+                |_| {},
+                |_, _| panic!("a rejected token won't be accepted later"),
+            )
+            .await
+            .unwrap_err();
+            assert_eq!(error, ApiError::SessionExpired);
+        }
+    }
+
+    #[tokio::test]
+    async fn sessions_are_refreshed_until_the_refresh_token_is_refused() {
+        let email = "refresh@uni.edu";
+        let _signed_in = SIGN_IN.lock().await;
+        server();
+        verify_otp_native(email, DEFAULT_OTP).await.unwrap();
+        let first = crate::AUTH_STATE.lock().unwrap().refresh_token.clone();
+
+        // A server error keeps the session to try again
+        server().fail(StubFailure {
+            path: "/auth/v1/token".to_string(),
+            email: Some(email.to_string()),
+            status: 503,
+            times: Some(1),
+            ..StubFailure::default()
+        });
+        assert!(refresh_session_native().await.unwrap_err().is_transient());
+        assert_eq!(crate::AUTH_STATE.lock().unwrap().refresh_token, first);
+
+        refresh_session_native().await.unwrap();
+        let (second, access_token) = {
+            let auth_state = crate::AUTH_STATE.lock().unwrap();
+            assert!(auth_state.signed_in);
+            assert!(auth_state.refresh_due() > Some(chrono::Utc::now()));
+            (
+                auth_state.refresh_token.clone(),
+                auth_state.access_token.clone().unwrap(),
+            )
+        };
+        assert_ne!(second, first);
+        let refresh = server()
+            .requests()
+            .into_iter()
+            .rev()
+            .find(|request| request.route() == "/auth/v1/token")
+            .unwrap();
+        assert_eq!(refresh.path, "/auth/v1/token?grant_type=refresh_token");
+        assert_eq!(json_field(&refresh.body, "refresh_token"), first.clone());
+        assert!(server().state.lock().unwrap().authorized(&StubRequest {
+            method: "POST".to_string(),
+            path: "/".to_string(),
+            headers: vec![(
+                "authorization".to_string(),
+                format!("Bearer {}", access_token)
+            )],
+            body: String::new(),
+        }));
+
+        // Refresh tokens are single use, so the first one is refused now
+        crate::AUTH_STATE.lock().unwrap().refresh_token = first;
+        assert_eq!(
+            refresh_session_native().await,
+            Err(ApiError::Auth {
+                code: "refresh_token_not_found".to_string(),
+                message: "Invalid Refresh Token: Refresh Token Not Found".to_string(),
+            })
+        );
+        let auth_state = crate::AUTH_STATE.lock().unwrap();
+        assert!(!auth_state.signed_in);
+        assert_eq!(auth_state.access_token, None);
+        assert_eq!(auth_state.email.as_deref(), Some(email));
+    }
+
+    #[tokio::test]
+    async fn an_expired_token_is_refreshed_before_asking() {
+        let email = "asleep@uni.edu";
+        let _signed_in = SIGN_IN.lock().await;
+        server();
+        verify_otp_native(email, DEFAULT_OTP).await.unwrap();
+        let expired = forged_token(email, chrono::Utc::now() - chrono::Duration::minutes(5));
+        crate::AUTH_STATE.lock().unwrap().access_token = Some(expired);
+
+        ask("Is it sorted yet?").await.unwrap();
+
+        let auth_state = crate::AUTH_STATE.lock().unwrap();
+        assert!(auth_state.signed_in);
+        let refreshed = auth_state.access_token.clone().unwrap();
+        assert_eq!(chat_tokens(email), vec![format!("Bearer {}", refreshed)]);
+    }
+
+    #[tokio::test]
+    async fn a_turned_away_token_is_refreshed_and_asked_again() {
+        let email = "revoked@uni.edu";
+        let _signed_in = SIGN_IN.lock().await;
+        server();
+        verify_otp_native(email, DEFAULT_OTP).await.unwrap();
+        let unknown = forged_token(email, chrono::Utc::now() + chrono::Duration::hours(1));
+        crate::AUTH_STATE.lock().unwrap().access_token = Some(unknown.clone());
+
+        ask("Where do I split?").await.unwrap();
+
+        let auth_state = crate::AUTH_STATE.lock().unwrap();
+        assert!(auth_state.signed_in);
+        let refreshed = auth_state.access_token.clone().unwrap();
+        assert_eq!(
+            chat_tokens(email),
+            vec![
+                format!("Bearer {}", unknown),
+                format!("Bearer {}", refreshed)
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn a_refused_refresh_returns_to_sign_in() {
+        let email = "gone@uni.edu";
+        let _signed_in = SIGN_IN.lock().await;
+        server();
+        verify_otp_native(email, DEFAULT_OTP).await.unwrap();
+        let unknown = forged_token(email, chrono::Utc::now() + chrono::Duration::hours(1));
+        {
+            let mut auth_state = crate::AUTH_STATE.lock().unwrap();
+            auth_state.access_token = Some(unknown);
+            auth_state.refresh_token = Some("not-a-refresh-token".to_string());
+        }
+
+        assert_eq!(ask("Hello?").await.unwrap_err(), ApiError::SessionExpired);
+        assert_eq!(chat_tokens(email).len(), 1);
+        assert!(!crate::AUTH_STATE.lock().unwrap().signed_in);
+    }
+
+    #[tokio::test]
+    async fn a_refresh_that_lost_the_race_keeps_the_session() {
+        let email = "racing@uni.edu";
+        let _signed_in = SIGN_IN.lock().await;
+        server();
+        verify_otp_native(email, DEFAULT_OTP).await.unwrap();
+        let first = crate::AUTH_STATE
+            .lock()
+            .unwrap()
+            .refresh_token
+            .clone()
+            .unwrap();
+        refresh_session_native().await.unwrap();
+
+        // A refresh that read the old token before the first one finished
+        let refused = Err(ApiError::Auth {
+            code: "refresh_token_not_found".to_string(),
+            message: "Invalid Refresh Token: Refresh Token Not Found".to_string(),
+        });
+        assert_eq!(crate::finish_refresh(&first, refused), Ok(()));
+        assert!(crate::AUTH_STATE.lock().unwrap().signed_in);
+    }
+}
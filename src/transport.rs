@@ -0,0 +1,389 @@
+#![warn(clippy::all)]
+
+use std::future::Future;
+use std::pin::Pin;
+use std::time::Duration;
+
+#[cfg(not(target_arch = "wasm32"))]
+pub(crate) type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+#[cfg(target_arch = "wasm32")]
+pub(crate) type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + 'a>>;
+
+/// `Send` on native, where requests run on the tokio runtime. The web only has
+/// the browser's main thread, so nothing is required there.
+#[cfg(not(target_arch = "wasm32"))]
+pub(crate) trait MaybeSend: Send {}
+#[cfg(not(target_arch = "wasm32"))]
+impl<T: Send> MaybeSend for T {}
+#[cfg(target_arch = "wasm32")]
+pub(crate) trait MaybeSend {}
+#[cfg(target_arch = "wasm32")]
+impl<T> MaybeSend for T {}
+
+/// Like `MaybeSend`, for things a running request borrows across awaits.
+#[cfg(not(target_arch = "wasm32"))]
+pub(crate) trait MaybeSync: Send + Sync {}
+#[cfg(not(target_arch = "wasm32"))]
+impl<T: Send + Sync> MaybeSync for T {}
+#[cfg(target_arch = "wasm32")]
+pub(crate) trait MaybeSync {}
+#[cfg(target_arch = "wasm32")]
+impl<T> MaybeSync for T {}
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct HttpRequest {
+    pub(crate) url: String,
+    pub(crate) headers: Vec<(String, String)>,
+    pub(crate) body: String,
+}
+
+pub(crate) struct HttpResponse {
+    pub(crate) status: u16,
+    pub(crate) content_type: Option<String>,
+    /// The raw `Retry-After` header.
+    pub(crate) retry_after: Option<String>,
+    pub(crate) body: Box<dyn ResponseBody>,
+}
+
+impl HttpResponse {
+    pub(crate) fn is_success(&self) -> bool {
+        (200..300).contains(&self.status)
+    }
+
+    /// Reads the rest of the body.
+    pub(crate) async fn bytes(mut self) -> Result<Vec<u8>, String> {
+        let mut bytes = Vec::new();
+        while let Some(chunk) = self.body.next_chunk().await? {
+            bytes.extend_from_slice(&chunk);
+        }
+        Ok(bytes)
+    }
+}
+
+pub(crate) trait ResponseBody: MaybeSend {
+    /// The next chunk of the body, or `None` once all of it has been read.
+    fn next_chunk(&mut self) -> BoxFuture<'_, Result<Option<Vec<u8>>, String>>;
+}
+
+/// Sends HTTP requests. The response is returned as soon as its headers have
+/// arrived, so the body can be read as it streams in.
+pub(crate) trait Transport {
+    fn post(&self, request: HttpRequest) -> BoxFuture<'_, Result<HttpResponse, String>>;
+
+    /// Whether the request was cancelled, so a failure shouldn't be retried.
+    fn cancelled(&self) -> bool {
+        false
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+mod native {
+    use super::*;
+
+    #[derive(Default)]
+    pub(crate) struct ReqwestTransport {
+        client: reqwest::Client,
+    }
+
+    impl Transport for ReqwestTransport {
+        fn post(&self, request: HttpRequest) -> BoxFuture<'_, Result<HttpResponse, String>> {
+            Box::pin(async move {
+                let mut builder = self.client.post(&request.url);
+                for (name, value) in &request.headers {
+                    builder = builder.header(name, value);
+                }
+                let response = builder
+                    .body(request.body)
+                    .send()
+                    .await
+                    .map_err(|e| e.to_string())?;
+
+                let header = |name| {
+                    response
+                        .headers()
+                        .get(name)
+                        .and_then(|value| value.to_str().ok())
+                        .map(str::to_string)
+                };
+                Ok(HttpResponse {
+                    status: response.status().as_u16(),
+                    content_type: header(reqwest::header::CONTENT_TYPE),
+                    retry_after: header(reqwest::header::RETRY_AFTER),
+                    body: Box::new(ReqwestBody(response)),
+                })
+            })
+        }
+    }
+
+    struct ReqwestBody(reqwest::Response);
+
+    impl ResponseBody for ReqwestBody {
+        fn next_chunk(&mut self) -> BoxFuture<'_, Result<Option<Vec<u8>>, String>> {
+            Box::pin(async move {
+                let chunk = self.0.chunk().await.map_err(|e| e.to_string())?;
+                Ok(chunk.map(|bytes| bytes.to_vec()))
+            })
+        }
+    }
+
+    /// Cancels an in-flight request.
+    pub(crate) struct RequestHandle {
+        task: tokio::task::AbortHandle,
+    }
+
+    impl RequestHandle {
+        pub(crate) fn abort(&self) {
+            self.task.abort();
+        }
+    }
+
+    /// Runs a request on the tokio runtime.
+    pub(crate) fn spawn_request<F, Fut>(run: F) -> RequestHandle
+    where
+        F: FnOnce(ReqwestTransport) -> Fut,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        let task = tokio::spawn(run(ReqwestTransport::default()));
+        RequestHandle {
+            task: task.abort_handle(),
+        }
+    }
+
+    pub(crate) async fn sleep(duration: Duration) {
+        tokio::time::sleep(duration).await;
+    }
+
+    /// A random number in `[0, 1)`, for jitter.
+    pub(crate) fn random_fraction() -> f64 {
+        use std::hash::{BuildHasher, Hasher};
+        // Every `RandomState` is seeded differently, which is all jitter needs
+        let bits = std::collections::hash_map::RandomState::new()
+            .build_hasher()
+            .finish();
+        (bits >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+#[cfg(target_arch = "wasm32")]
+mod web {
+    use super::*;
+    use web_sys::js_sys::{Reflect, Uint8Array};
+    use web_sys::wasm_bindgen::JsCast;
+
+    pub(crate) struct FetchTransport {
+        signal: Option<web_sys::AbortSignal>,
+    }
+
+    impl Transport for FetchTransport {
+        fn post(&self, request: HttpRequest) -> BoxFuture<'_, Result<HttpResponse, String>> {
+            Box::pin(async move {
+                let mut builder = reqwasm::http::Request::post(&request.url);
+                for (name, value) in &request.headers {
+                    builder = builder.header(name, value);
+                }
+                let response = builder
+                    .body(request.body)
+                    .abort_signal(self.signal.as_ref())
+                    .send()
+                    .await
+                    .map_err(|e| e.to_string())?;
+
+                let reader = match response.body() {
+                    Some(body) => Some(
+                        body.get_reader()
+                            .dyn_into::<web_sys::ReadableStreamDefaultReader>()
+                            .map_err(|_| "Failed to read response stream".to_string())?,
+                    ),
+                    None => None,
+                };
+
+                Ok(HttpResponse {
+                    status: response.status(),
+                    content_type: response.headers().get("content-type"),
+                    retry_after: response.headers().get("retry-after"),
+                    body: Box::new(FetchBody { reader }),
+                })
+            })
+        }
+
+        fn cancelled(&self) -> bool {
+            self.signal.as_ref().is_some_and(|signal| signal.aborted())
+        }
+    }
+
+    struct FetchBody {
+        reader: Option<web_sys::ReadableStreamDefaultReader>,
+    }
+
+    impl ResponseBody for FetchBody {
+        fn next_chunk(&mut self) -> BoxFuture<'_, Result<Option<Vec<u8>>, String>> {
+            Box::pin(async move {
+                let Some(reader) = &self.reader else {
+                    return Ok(None);
+                };
+                let result = wasm_bindgen_futures::JsFuture::from(reader.read())
+                    .await
+                    .map_err(|e| format!("{:?}", e))?;
+
+                let done = Reflect::get(&result, &"done".into()).map_err(|e| format!("{:?}", e))?;
+                if done.is_truthy() {
+                    return Ok(None);
+                }
+                let value =
+                    Reflect::get(&result, &"value".into()).map_err(|e| format!("{:?}", e))?;
+                Ok(Some(Uint8Array::new(&value).to_vec()))
+            })
+        }
+    }
+
+    /// Cancels an in-flight request.
+    pub(crate) struct RequestHandle {
+        controller: Option<web_sys::AbortController>,
+    }
+
+    impl RequestHandle {
+        pub(crate) fn abort(&self) {
+            if let Some(controller) = &self.controller {
+                controller.abort();
+            }
+        }
+    }
+
+    /// Runs a request on the browser's event loop, with a fetch that can be
+    /// aborted through the returned handle.
+    pub(crate) fn spawn_request<F, Fut>(run: F) -> RequestHandle
+    where
+        F: FnOnce(FetchTransport) -> Fut,
+        Fut: Future<Output = ()> + 'static,
+    {
+        let controller = web_sys::AbortController::new().ok();
+        let transport = FetchTransport {
+            signal: controller.as_ref().map(|c| c.signal()),
+        };
+        wasm_bindgen_futures::spawn_local(run(transport));
+        RequestHandle { controller }
+    }
+
+    pub(crate) async fn sleep(duration: Duration) {
+        let promise = web_sys::js_sys::Promise::new(&mut |resolve, _| {
+            if let Some(window) = web_sys::window() {
+                let _ = window.set_timeout_with_callback_and_timeout_and_arguments_0(
+                    &resolve,
+                    duration.as_millis() as i32,
+                );
+            }
+        });
+        let _ = wasm_bindgen_futures::JsFuture::from(promise).await;
+    }
+
+    /// A random number in `[0, 1)`, for jitter.
+    pub(crate) fn random_fraction() -> f64 {
+        web_sys::js_sys::Math::random()
+    }
+}
+
+#[cfg(all(not(target_arch = "wasm32"), test))]
+pub(crate) use native::ReqwestTransport;
+#[cfg(not(target_arch = "wasm32"))]
+pub(crate) use native::{random_fraction, sleep, spawn_request, RequestHandle};
+#[cfg(target_arch = "wasm32")]
+pub(crate) use web::{random_fraction, sleep, spawn_request, RequestHandle};
+
+/// Answers requests with canned responses, in order, repeating the last one,
+/// and remembers what was sent.
+#[cfg(test)]
+pub(crate) struct MemoryTransport {
+    responses: std::sync::Mutex<std::collections::VecDeque<Result<Canned, String>>>,
+    pub(crate) requests: std::sync::Mutex<Vec<HttpRequest>>,
+}
+
+#[cfg(test)]
+#[derive(Clone)]
+struct Canned {
+    status: u16,
+    content_type: &'static str,
+    retry_after: Option<&'static str>,
+    chunks: Vec<Vec<u8>>,
+}
+
+#[cfg(test)]
+impl Canned {
+    fn new(status: u16, content_type: &'static str, chunks: &[&str]) -> Self {
+        Self {
+            status,
+            content_type,
+            retry_after: None,
+            chunks: chunks
+                .iter()
+                .map(|chunk| chunk.as_bytes().to_vec())
+                .collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+impl MemoryTransport {
+    pub(crate) fn new(status: u16, content_type: &'static str, chunks: &[&str]) -> Self {
+        Self::starting_with(Ok(Canned::new(status, content_type, chunks)))
+    }
+
+    /// Fails the first request as if the network were down.
+    pub(crate) fn failing(error: &str) -> Self {
+        Self::starting_with(Err(error.to_string()))
+    }
+
+    fn starting_with(response: Result<Canned, String>) -> Self {
+        Self {
+            responses: std::sync::Mutex::new([response].into()),
+            requests: std::sync::Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Queues the response for the next request.
+    pub(crate) fn then(self, status: u16, content_type: &'static str, chunks: &[&str]) -> Self {
+        self.responses
+            .lock()
+            .unwrap()
+            .push_back(Ok(Canned::new(status, content_type, chunks)));
+        self
+    }
+
+    /// Sets `Retry-After` on the last queued response.
+    pub(crate) fn with_retry_after(self, value: &'static str) -> Self {
+        if let Some(Ok(canned)) = self.responses.lock().unwrap().back_mut() {
+            canned.retry_after = Some(value);
+        }
+        self
+    }
+}
+
+#[cfg(test)]
+impl Transport for MemoryTransport {
+    fn post(&self, request: HttpRequest) -> BoxFuture<'_, Result<HttpResponse, String>> {
+        self.requests.lock().unwrap().push(request);
+        let mut responses = self.responses.lock().unwrap();
+        let next = if responses.len() > 1 {
+            responses.pop_front().unwrap()
+        } else {
+            responses.front().cloned().unwrap()
+        };
+        let response = next.map(|canned| HttpResponse {
+            status: canned.status,
+            content_type: Some(canned.content_type.to_string()),
+            retry_after: canned.retry_after.map(str::to_string),
+            body: Box::new(MemoryBody(canned.chunks.into())),
+        });
+        Box::pin(async move { response })
+    }
+}
+
+#[cfg(test)]
+struct MemoryBody(std::collections::VecDeque<Vec<u8>>);
+
+#[cfg(test)]
+impl ResponseBody for MemoryBody {
+    fn next_chunk(&mut self) -> BoxFuture<'_, Result<Option<Vec<u8>>, String>> {
+        let chunk = self.0.pop_front();
+        Box::pin(async move { Ok(chunk) })
+    }
+}